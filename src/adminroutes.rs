@@ -0,0 +1,308 @@
+use std::net::IpAddr;
+use crate::structs::*;
+use crate::auth::{password_problems, AuthenticatedUser};
+use crate::permission::ALL_PERMISSIONS;
+use crate::role::RequireAdmin;
+use crate::error::ApiError;
+use crate::repository::UserUpdate;
+use crate::throttle;
+use rocket::{delete, get, post, serde::json::Json, State};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use bcrypt::{hash, DEFAULT_COST};
+
+#[get("/api/admin/permissions")]
+pub async fn list_permissions(
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Json<Vec<&'static str>> {
+    Json(ALL_PERMISSIONS.to_vec())
+}
+
+#[get("/api/admin/roles")]
+pub async fn list_roles(
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<Vec<RoleResponse>>, ApiError> {
+    Ok(Json(state.users.list_roles().await?))
+}
+
+/// Creates the role if needed and replaces its grants with exactly `permissions`.
+#[post("/api/admin/roles", format = "json", data = "<role_request>")]
+pub async fn set_role(
+    role_request: Json<RoleRequest>,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<RoleResponse>, ApiError> {
+    if role_request.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("Role name is required".to_string()));
+    }
+    if role_request.name == "admin" {
+        return Err(ApiError::Unprocessable("The admin role always has every permission".to_string()));
+    }
+    if let Some(unknown) = role_request.permissions.iter().find(|p| !ALL_PERMISSIONS.contains(&p.as_str())) {
+        return Err(ApiError::Unprocessable(format!("Unknown permission: {}", unknown)));
+    }
+
+    state.users.set_role_permissions(&role_request.name, &role_request.permissions).await?;
+
+    match state.users.find_role(&role_request.name).await? {
+        Some(role) => Ok(Json(role)),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+#[delete("/api/admin/roles/<name>")]
+pub async fn delete_role(
+    name: &str,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<&'static str>, ApiError> {
+    match state.users.find_role(name).await? {
+        None => return Err(ApiError::not_found()),
+        Some(role) if role.builtin => return Err(ApiError::conflict(format!("{} is a built-in role", name))),
+        Some(_) => {},
+    }
+
+    let users = state.users.delete_role(name).await?;
+    if users > 0 {
+        Err(ApiError::conflict(format!("{} is still assigned to {} user(s)", name, users)))
+    } else {
+        Ok(Json("Role deleted"))
+    }
+}
+
+/*
+    Users
+
+    Changes that should end a user's session (role change, disable, password reset,
+    delete) also revoke their refresh tokens. Access tokens already issued keep
+    working, but every request checks the user as stored now: a disabled or
+    deleted user is refused, and a new role applies at once. Websocket
+    connections are closed at their next ping.
+*/
+
+async fn find_user(state: &AppState, username: &str) -> Result<Option<UserDetails>, ApiError> {
+    Ok(state.users.find_user(username).await?.map(|user| user.details()))
+}
+
+/// Applies an update to one user, then signs them out everywhere and returns the new state.
+async fn update_user(state: &AppState, username: &str, update: UserUpdate) -> Result<Json<UserDetails>, ApiError> {
+    if find_user(state, username).await?.is_none() {
+        return Err(ApiError::not_found());
+    }
+
+    state.users.update_user(username, &update).await?;
+    if let Err(e) = state.users.revoke_user_tokens(username).await {
+        println!("Failed to revoke refresh tokens for {}: {}", username, e);
+    }
+
+    match find_user(state, username).await? {
+        Some(user) => Ok(Json(user)),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+#[get("/api/admin/users")]
+pub async fn list_users(
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<Vec<UserDetails>>, ApiError> {
+    let users = state.users.list_users().await?;
+    Ok(Json(users.iter().map(|user| user.details()).collect()))
+}
+
+#[get("/api/admin/users/<username>")]
+pub async fn get_user(
+    username: &str,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<UserDetails>, ApiError> {
+    match find_user(state, username).await? {
+        Some(user) => Ok(Json(user)),
+        None => Err(ApiError::not_found()),
+    }
+}
+
+#[post("/api/admin/users/<username>/role", format = "json", data = "<role_request>")]
+pub async fn update_user_role(
+    username: &str,
+    role_request: Json<UpdateRoleRequest>,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<UserDetails>, ApiError> {
+    if username == user.0.username {
+        return Err(ApiError::Forbidden("Admins cannot change their own role".to_string()));
+    }
+    if state.users.find_role(&role_request.role).await?.is_none() {
+        return Err(ApiError::Unprocessable(format!("Unknown role: {}", role_request.role)));
+    }
+
+    update_user(state, username, UserUpdate {
+        role: Some(role_request.role.clone()),
+        ..UserUpdate::default()
+    }).await
+}
+
+#[post("/api/admin/users/<username>/disable")]
+pub async fn disable_user(
+    username: &str,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<UserDetails>, ApiError> {
+    if username == user.0.username {
+        return Err(ApiError::Forbidden("Admins cannot disable their own account".to_string()));
+    }
+
+    update_user(state, username, UserUpdate {
+        disabled: Some(true),
+        ..UserUpdate::default()
+    }).await
+}
+
+#[post("/api/admin/users/<username>/enable")]
+pub async fn enable_user(
+    username: &str,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<UserDetails>, ApiError> {
+    update_user(state, username, UserUpdate {
+        disabled: Some(false),
+        ..UserUpdate::default()
+    }).await
+}
+
+/// Sets a temporary password the admin hands over. The user cannot log in with it
+/// until they pick a new one through /change_password.
+#[post("/api/admin/users/<username>/reset_password", format = "json", data = "<reset_request>")]
+pub async fn reset_user_password(
+    username: &str,
+    reset_request: Json<ResetPasswordRequest>,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<UserDetails>, ApiError> {
+    let problems = password_problems(username, &reset_request.temporary_password, state.config.password_min_length);
+    if !problems.is_empty() {
+        return Err(ApiError::Unprocessable(problems.join("; ")));
+    }
+
+    let hashed_password = hash(&reset_request.temporary_password, DEFAULT_COST)?;
+
+    update_user(state, username, UserUpdate {
+        password: Some(hashed_password),
+        must_reset_password: Some(true),
+        ..UserUpdate::default()
+    }).await
+}
+
+/// Clears the failed-login counter for a username, lifting any lockout on it.
+/// Failures are also counted per client IP, so a user locked out by their IP's
+/// counter needs that IP passed as ?ip= to be let back in.
+#[post("/api/admin/users/<username>/unlock?<ip>")]
+pub async fn unlock_user(
+    username: &str,
+    ip: Option<IpAddr>,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<&'static str>, ApiError> {
+    state.users.clear_login_failures(&throttle::user_key(username)).await?;
+    match ip {
+        Some(ip) => {
+            state.users.clear_login_failures(&throttle::ip_key(&ip)).await?;
+            Ok(Json("Account and IP unlocked"))
+        }
+        None => Ok(Json("Account unlocked. A lockout on the user's IP stays until it is unlocked with ?ip=")),
+    }
+}
+
+#[delete("/api/admin/users/<username>")]
+pub async fn delete_user(
+    username: &str,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<&'static str>, ApiError> {
+    if username == user.0.username {
+        return Err(ApiError::Forbidden("Admins cannot delete their own account".to_string()));
+    }
+    if find_user(state, username).await?.is_none() {
+        return Err(ApiError::not_found());
+    }
+
+    state.users.delete_user(username).await?;
+    Ok(Json("User deleted"))
+}
+
+/*
+    Invite codes
+
+    Single-use codes that let someone /register with the role the invite was made for.
+*/
+
+#[post("/api/admin/invites", format = "json", data = "<invite_request>")]
+pub async fn create_invite(
+    invite_request: Json<InviteRequest>,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<Invite>, ApiError> {
+    if state.users.find_role(&invite_request.role).await?.is_none() {
+        return Err(ApiError::Unprocessable(format!("Unknown role: {}", invite_request.role)));
+    }
+    let hours = invite_request.expires_in_hours.unwrap_or(72);
+    if !(1..=24 * 30).contains(&hours) {
+        return Err(ApiError::Unprocessable("expires_in_hours must be between 1 and 720".to_string()));
+    }
+
+    let now = Utc::now();
+    let invite = Invite {
+        code: Uuid::new_v4().simple().to_string(),
+        role: invite_request.role.clone(),
+        created_by: user.0.username.clone(),
+        expires: (now + Duration::hours(hours)).timestamp(),
+    };
+
+    state.users.create_invite(&invite, &now.to_rfc3339()).await?;
+    Ok(Json(invite))
+}
+
+/// Invites that have not been used and have not expired.
+#[get("/api/admin/invites")]
+pub async fn list_invites(
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<Vec<Invite>>, ApiError> {
+    Ok(Json(state.users.list_invites(Utc::now().timestamp()).await?))
+}
+
+#[delete("/api/admin/invites/<code>")]
+pub async fn delete_invite(
+    code: &str,
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<&'static str>, ApiError> {
+    state.users.delete_invite(code).await?;
+    Ok(Json("Invite deleted"))
+}
+
+/// Open websocket connections with their queue depths, for monitoring.
+#[get("/api/admin/websockets")]
+pub async fn websocket_stats(
+    state: &State<AppState>,
+    _user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Json<WebSocketStats> {
+    Json(crate::wsserver::stats(state).await)
+}
@@ -1,41 +1,163 @@
-use jsonwebtoken::{decode, DecodingKey, Validation, Algorithm};
+use std::collections::HashMap;
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, Validation, Algorithm};
+use jsonwebtoken::errors::{Error, ErrorKind};
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket::http::Status;
 use serde::{Deserialize, Serialize};
+use crate::config::AppConfig;
+use crate::repository::{RepoError, UserRepository};
+use crate::structs::AppState;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenType {
+    Access,
+    Refresh,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Claims {
     pub username: String,
     pub role: String,
     pub exp: usize,
+    pub token_type: TokenType,
+    pub jti: String,
+}
+
+/*
+    JWT key ring
+
+    Tokens are signed with the current key and carry its id in the `kid` header.
+    Previous keys are kept for verification only, so rotating the secret does not
+    invalidate tokens that are still in flight. Tokens without a `kid` were issued
+    before the key ring existed and are checked against the current key.
+*/
+
+#[derive(Clone)]
+pub struct KeyRing {
+    current_kid: String,
+    keys: HashMap<String, String>,
+}
+
+impl KeyRing {
+    pub fn new(current_kid: &str, current_secret: &str, previous: &HashMap<String, String>) -> Self {
+        let mut keys = previous.clone();
+        keys.insert(current_kid.to_string(), current_secret.to_string());
+
+        KeyRing {
+            current_kid: current_kid.to_string(),
+            keys,
+        }
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        KeyRing::new(&config.jwt_kid, &config.jwt_secret, &config.jwt_previous_secrets)
+    }
+
+    pub fn encode(&self, claims: &Claims) -> Result<String, Error> {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(self.current_kid.clone());
+        encode(&header, claims, &EncodingKey::from_secret(self.keys[&self.current_kid].as_ref()))
+    }
+
+    fn secret_for(&self, kid: Option<&str>) -> Option<&String> {
+        self.keys.get(kid.unwrap_or(&self.current_kid))
+    }
 }
 
-pub fn decode_token(token: &str, secret: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+pub fn decode_token(token: &str, keys: &KeyRing) -> Result<Claims, Error> {
+    let header = decode_header(token)?;
+    let secret = keys.secret_for(header.kid.as_deref()).ok_or_else(|| Error::from(ErrorKind::InvalidKeyFormat))?;
     let key = DecodingKey::from_secret(secret.as_ref());
     let validation = Validation::new(Algorithm::HS256);
     decode::<Claims>(token, &key, &validation).map(|data| data.claims)
 }
 
+/// Everything wrong with a proposed password, empty if it is acceptable.
+/// bcrypt only looks at the first 72 bytes, so longer passwords are refused.
+pub fn password_problems(username: &str, password: &str, min_length: usize) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if password.chars().count() < min_length {
+        problems.push(format!("Password must be at least {} characters", min_length));
+    }
+    if password.len() > 72 {
+        problems.push("Password must be at most 72 bytes".to_string());
+    }
+    if !password.chars().any(|c| c.is_alphabetic()) {
+        problems.push("Password must contain a letter".to_string());
+    }
+    if !password.chars().any(|c| c.is_ascii_digit()) {
+        problems.push("Password must contain a digit".to_string());
+    }
+    if !username.is_empty() && password.to_lowercase().contains(&username.to_lowercase()) {
+        problems.push("Password must not contain the username".to_string());
+    }
+
+    problems
+}
+
+/// The caller of a route. `role` is the user's role as stored now, not as it was
+/// when the token was issued: an access token outlives admin changes to its user.
 pub struct AuthenticatedUser(pub Claims);
 
+/// The claims with the user's current role, or None if the user has been
+/// disabled, deleted or made to reset their password since the token was issued.
+pub async fn current_claims(users: &dyn UserRepository, mut claims: Claims) -> Result<Option<Claims>, RepoError> {
+    match users.find_user(&claims.username).await? {
+        Some(user) if !user.disabled && !user.must_reset_password => {
+            claims.role = user.role;
+            Ok(Some(claims))
+        },
+        _ => Ok(None),
+    }
+}
+
+// Request-local, so a route with several auth guards looks the user up once.
+struct Verified(Result<Claims, Status>);
+
+async fn verify(request: &Request<'_>) -> Verified {
+    let state = match request.rocket().state::<AppState>() {
+        Some(state) => state,
+        None => return Verified(Err(Status::InternalServerError)),
+    };
+    let token = match request.headers().get_one("Authorization").and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(token) => token,
+        None => return Verified(Err(Status::Unauthorized)),
+    };
+    let claims = match decode_token(token, &state.jwt_keys) {
+        Ok(claims) if claims.token_type == TokenType::Access => claims,
+        Ok(_) => {
+            println!("Rejected token: refresh token used as an access token");
+            return Verified(Err(Status::Unauthorized));
+        },
+        Err(e) => {
+            println!("Rejected token: {:?}", e);
+            return Verified(Err(Status::Unauthorized));
+        },
+    };
+    let username = claims.username.clone();
+    match current_claims(state.users.as_ref(), claims).await {
+        Ok(Some(claims)) => Verified(Ok(claims)),
+        Ok(None) => {
+            println!("Rejected token: {} is disabled or deleted", username);
+            Verified(Err(Status::Unauthorized))
+        },
+        Err(e) => {
+            println!("Failed to look up {}: {}", username, e);
+            Verified(Err(Status::InternalServerError))
+        },
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AuthenticatedUser {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let secret = "tO7E8uCjD5rXpQl0FhKwV2yMz4bJnAi9sGeR3kTzXvNmPuLsDq8W"; // Replace with your secret key
-        if let Some(auth_header) = request.headers().get_one("Authorization") {
-            if let Some(token) = auth_header.strip_prefix("Bearer ") {
-                match decode_token(token, secret) {
-                    Ok(claims) => {
-                        return Outcome::Success(AuthenticatedUser(claims));
-                    },
-                    Err(e) => {
-                        return Outcome::Error((Status::Unauthorized, println!("{:?}", e)));
-                    },
-                }
-            }
+        match &request.local_cache_async(verify(request)).await.0 {
+            Ok(claims) => Outcome::Success(AuthenticatedUser(claims.clone())),
+            Err(status) => Outcome::Error((*status, ())),
         }
-        Outcome::Error((Status::Unauthorized, ()))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use rocket::figment::{Figment, Profile, providers::{Env, Format, Serialized, Toml}};
+use serde::{Deserialize, Serialize};
+
+/*
+    Application config
+
+    Read from the same sources as Rocket's own config, in increasing priority:
+    AppConfig::default(), Rocket.toml (or the file named by ROCKET_CONFIG) for the
+    profile selected by ROCKET_PROFILE, then ROCKET_<KEY> environment variables,
+    e.g. ROCKET_NEO4J_PASSWORD or ROCKET_JWT_SECRET.
+*/
+
+/// Where the app keeps its data.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Storage {
+    Neo4j,
+    /// Plain collections in the process, for tests and demos without a database.
+    Memory,
+}
+
+/// What happens to a websocket client whose outgoing queue is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuePolicy {
+    /// Skip the message and keep the client, which misses that event for good.
+    Drop,
+    /// Close the connection, so the client reconnects and resumes.
+    Disconnect,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    pub storage: Storage,
+    /// JSON file the memory store loads at startup and rewrites after every change.
+    pub memory_snapshot: Option<String>,
+    /// Apply pending Neo4j migrations at startup; otherwise only `rocket_http migrate` runs them.
+    pub run_migrations: bool,
+    pub neo4j_uri: String,
+    pub neo4j_user: String,
+    pub neo4j_password: String,
+    pub jwt_secret: String,
+    pub jwt_kid: String,
+    pub jwt_previous_secrets: HashMap<String, String>,
+    pub cors_allowed_origins: Vec<String>,
+    pub open_registration: bool,
+    pub password_min_length: usize,
+    pub login_max_attempts: u32,
+    pub login_ip_max_attempts: u32,
+    pub login_lockout_secs: u64,
+    pub login_lockout_max_secs: u64,
+    /// Events the websocket broadcaster may fall behind by before it loses some.
+    pub event_bus_capacity: usize,
+    /// Recent websocket broadcasts kept for clients resuming after a reconnect.
+    pub ws_replay_buffer: usize,
+    pub ws_ping_interval_secs: u64,
+    /// Websocket clients that send nothing, not even a pong, for this long are closed.
+    pub ws_idle_timeout_secs: u64,
+    /// Messages waiting to go out to one websocket client.
+    pub ws_queue_capacity: usize,
+    pub ws_queue_policy: QueuePolicy,
+    /// How long a new websocket client has to resume before live events flow.
+    pub ws_resume_window_ms: u64,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+            storage: Storage::Neo4j,
+            memory_snapshot: None,
+            run_migrations: true,
+            neo4j_uri: "bolt://localhost:7687".to_string(),
+            neo4j_user: "neo4j".to_string(),
+            // No usable defaults for secrets: they have to be configured.
+            neo4j_password: "".to_string(),
+            jwt_secret: "".to_string(),
+            jwt_kid: "default".to_string(),
+            jwt_previous_secrets: HashMap::new(),
+            cors_allowed_origins: vec!["*".to_string()],
+            open_registration: false,
+            password_min_length: 12,
+            login_max_attempts: 5,
+            login_ip_max_attempts: 50,
+            login_lockout_secs: 30,
+            login_lockout_max_secs: 3600,
+            event_bus_capacity: 256,
+            ws_replay_buffer: 1000,
+            ws_ping_interval_secs: 30,
+            ws_idle_timeout_secs: 90,
+            ws_queue_capacity: 256,
+            ws_queue_policy: QueuePolicy::Disconnect,
+            ws_resume_window_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Figment(Box<rocket::figment::Error>),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Figment(e) => write!(f, "failed to read configuration: {}", e),
+            ConfigError::Invalid(problems) => {
+                writeln!(f, "invalid configuration:")?;
+                for problem in problems {
+                    writeln!(f, "  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Rocket's standard figment with the app defaults layered underneath, so the same
+/// figment configures both `rocket::custom` and `AppConfig`.
+///
+/// Rocket takes the client IP from X-Real-IP by default, which any client can
+/// send, so a fresh one per request would dodge the per-IP login throttle. Here
+/// it comes from the socket unless `ip_header` names a header a trusted proxy sets.
+pub fn figment() -> Figment {
+    Figment::from(rocket::Config::default())
+        .merge(Serialized::default("ip_header", false))
+        .merge(Serialized::defaults(AppConfig::default()))
+        .merge(Toml::file(Env::var_or("ROCKET_CONFIG", "Rocket.toml")).nested())
+        .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).global())
+        .select(Profile::from_env_or("ROCKET_PROFILE", rocket::Config::DEFAULT_PROFILE))
+}
+
+impl AppConfig {
+    pub fn from_figment(figment: &Figment) -> Result<Self, ConfigError> {
+        let config: AppConfig = figment.extract().map_err(|e| ConfigError::Figment(Box::new(e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.storage == Storage::Neo4j {
+            let schemes = ["bolt://", "bolt+s://", "bolt+ssc://", "neo4j://", "neo4j+s://", "neo4j+ssc://"];
+            if !schemes.iter().any(|scheme| self.neo4j_uri.starts_with(scheme)) {
+                problems.push(format!("neo4j_uri '{}' must start with one of {}", self.neo4j_uri, schemes.join(", ")));
+            }
+            if self.neo4j_user.is_empty() {
+                problems.push("neo4j_user must be set (ROCKET_NEO4J_USER)".to_string());
+            }
+            if self.neo4j_password.is_empty() {
+                problems.push("neo4j_password must be set (ROCKET_NEO4J_PASSWORD)".to_string());
+            }
+        }
+        if self.memory_snapshot.is_some() && self.storage != Storage::Memory {
+            problems.push("memory_snapshot is only used with storage = \"memory\"".to_string());
+        }
+        if self.memory_snapshot.as_deref() == Some("") {
+            problems.push("memory_snapshot must be a file path".to_string());
+        }
+        if self.jwt_secret.len() < 32 {
+            problems.push("jwt_secret must be at least 32 characters (ROCKET_JWT_SECRET)".to_string());
+        }
+        if self.jwt_kid.is_empty() {
+            problems.push("jwt_kid must not be empty (ROCKET_JWT_KID)".to_string());
+        }
+        if self.jwt_previous_secrets.contains_key(&self.jwt_kid) {
+            problems.push(format!("jwt_previous_secrets must not reuse the current jwt_kid '{}'", self.jwt_kid));
+        }
+        for (kid, secret) in &self.jwt_previous_secrets {
+            if secret.len() < 32 {
+                problems.push(format!("jwt_previous_secrets.{} must be at least 32 characters", kid));
+            }
+        }
+        if !(8..=72).contains(&self.password_min_length) {
+            problems.push(format!("password_min_length must be between 8 and 72, got {}", self.password_min_length));
+        }
+        if self.login_max_attempts == 0 || self.login_ip_max_attempts == 0 {
+            problems.push("login_max_attempts and login_ip_max_attempts must be at least 1".to_string());
+        }
+        if self.login_lockout_secs == 0 || self.login_lockout_secs > self.login_lockout_max_secs {
+            problems.push("login_lockout_secs must be at least 1 and at most login_lockout_max_secs".to_string());
+        }
+        if self.event_bus_capacity == 0 || self.ws_replay_buffer == 0 || self.ws_queue_capacity == 0 {
+            problems.push("event_bus_capacity, ws_replay_buffer and ws_queue_capacity must be at least 1".to_string());
+        }
+        if self.ws_ping_interval_secs == 0 || self.ws_idle_timeout_secs <= self.ws_ping_interval_secs {
+            problems.push("ws_ping_interval_secs must be at least 1 and less than ws_idle_timeout_secs".to_string());
+        }
+        if self.cors_allowed_origins.is_empty() {
+            problems.push("cors_allowed_origins must list at least one origin, or \"*\"".to_string());
+        }
+        for origin in &self.cors_allowed_origins {
+            if origin == "*" {
+                continue;
+            }
+            match url::Url::parse(origin) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {},
+                _ => problems.push(format!("cors_allowed_origins entry '{}' is not an http(s) origin", origin)),
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.cors_allowed_origins.iter().any(|origin| origin == "*")
+    }
+}
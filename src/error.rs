@@ -0,0 +1,182 @@
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use crate::status::TransitionError;
+use crate::repository::RepoError;
+
+/*
+    API errors
+
+    Every failed request gets a status that matches the problem and a body of
+
+        {"code": "NOT_FOUND", "message": "...", "request_id": "..."}
+
+    The request id is also sent as X-Request-Id on every response, and is printed
+    with the underlying cause of internal errors so a report can be matched to the
+    log. Internal errors never send their cause to the client.
+*/
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    /// `details` are extra fields merged into the body, e.g. a shipment's current status.
+    Conflict { code: &'static str, message: String, details: Map<String, Value> },
+    Unprocessable(String),
+    TooManyRequests { message: String, retry_after: i64 },
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn conflict(message: impl Into<String>) -> Self {
+        ApiError::Conflict { code: "CONFLICT", message: message.into(), details: Map::new() }
+    }
+
+    pub fn not_found() -> Self {
+        ApiError::NotFound("No record found".to_string())
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest(_) => Status::BadRequest,
+            ApiError::Unauthorized(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict { .. } => Status::Conflict,
+            ApiError::Unprocessable(_) => Status::UnprocessableEntity,
+            ApiError::TooManyRequests { .. } => Status::TooManyRequests,
+            ApiError::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "BAD_REQUEST",
+            ApiError::Unauthorized(_) => "UNAUTHORIZED",
+            ApiError::Forbidden(_) => "FORBIDDEN",
+            ApiError::NotFound(_) => "NOT_FOUND",
+            ApiError::Conflict { code, .. } => code,
+            ApiError::Unprocessable(_) => "UNPROCESSABLE_ENTITY",
+            ApiError::TooManyRequests { .. } => "TOO_MANY_REQUESTS",
+            ApiError::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: String,
+    #[serde(flatten)]
+    details: Map<String, Value>,
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let request_id = RequestId::of(request).0.clone();
+        let status = self.status();
+        let code = self.code();
+
+        let (message, details, retry_after) = match self {
+            ApiError::Internal(cause) => {
+                println!("[{}] Internal error: {}", request_id, cause);
+                ("Internal Server Error".to_string(), Map::new(), None)
+            },
+            ApiError::Conflict { message, details, .. } => (message, details, None),
+            ApiError::TooManyRequests { message, retry_after } => (message, Map::new(), Some(retry_after)),
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Unprocessable(message) => (message, Map::new(), None),
+        };
+
+        let body = ErrorBody { code, message, request_id, details };
+        let mut response = Response::build_from(Json(body).respond_to(request)?)
+            .status(status)
+            .finalize();
+        if let Some(seconds) = retry_after {
+            response.set_header(Header::new("Retry-After", seconds.to_string()));
+        }
+        Ok(response)
+    }
+}
+
+impl From<TransitionError> for ApiError {
+    fn from(e: TransitionError) -> Self {
+        let mut details = match serde_json::to_value(&e) {
+            Ok(Value::Object(details)) => details,
+            _ => Map::new(),
+        };
+        details.remove("code");
+        details.remove("message");
+
+        ApiError::Conflict { code: e.code, message: e.message, details }
+    }
+}
+
+impl From<neo4rs::Error> for ApiError {
+    fn from(e: neo4rs::Error) -> Self {
+        ApiError::Internal(format!("Neo4j query failed: {:?}", e))
+    }
+}
+
+impl From<neo4rs::DeError> for ApiError {
+    fn from(e: neo4rs::DeError) -> Self {
+        ApiError::Internal(format!("Unexpected value in Neo4j result: {:?}", e))
+    }
+}
+
+impl From<RepoError> for ApiError {
+    fn from(e: RepoError) -> Self {
+        match e {
+            RepoError::Neo4j(e) => e.into(),
+            RepoError::Mapping(e) => e.into(),
+            RepoError::Duplicate => ApiError::conflict("Record already exists"),
+        }
+    }
+}
+
+impl From<bcrypt::BcryptError> for ApiError {
+    fn from(e: bcrypt::BcryptError) -> Self {
+        ApiError::Internal(format!("bcrypt failed: {:?}", e))
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for ApiError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        ApiError::Internal(format!("Failed to sign token: {:?}", e))
+    }
+}
+
+/*
+    Request ids
+*/
+
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// The caller's X-Request-Id if it looks sane, otherwise a new one. Fixed per request.
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r RequestId {
+        request.local_cache(|| {
+            let incoming = request.headers().get_one("X-Request-Id").filter(|id| {
+                !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+            RequestId(incoming.map(str::to_string).unwrap_or_else(|| Uuid::new_v4().to_string()))
+        })
+    }
+
+    pub fn fairing() -> AdHoc {
+        AdHoc::on_response("Request ID", |request, response| Box::pin(async move {
+            response.set_header(Header::new("X-Request-Id", RequestId::of(request).0.clone()));
+        }))
+    }
+}
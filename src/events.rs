@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use crate::structs::{DeleteShipmentRequest, Shipment, ShipmentLinesRequest, TrailerSchedule};
+
+/*
+    Server events
+
+    Setters publish one of these after a write succeeds, carrying the record as it
+    is now stored. The websocket layer forwards them to the dock screens, so those
+    stay current no matter which client made the change.
+
+    Serialized as {"type": "set_door", "data": {...}}. The type names are the ones
+    the front ends already send over the websocket, and the shipment ones match
+    the event names in the shipment history.
+*/
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    HotTrailer(TrailerSchedule),
+    ScheduleTrailer(TrailerSchedule),
+    SetDoor(TrailerSchedule),
+    TrailerArrived(TrailerSchedule),
+    ShipmentTrailerArrival(Shipment),
+    SetShipmentDoor(Shipment),
+    StartShipmentPick(Shipment),
+    FinishShipmentPick(Shipment),
+    NewShipment(Shipment),
+    ShipmentDepart(Shipment),
+    ShipmentStartLoading(Shipment),
+    DeleteShipment(DeleteShipmentRequest),
+    ShipmentHold(Shipment),
+    VerifiedBy(Shipment),
+    ShipmentLines(ShipmentLinesRequest),
+    ShipmentStatusOverride(Shipment),
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::HotTrailer(_) => "hot_trailer",
+            Event::ScheduleTrailer(_) => "schedule_trailer",
+            Event::SetDoor(_) => "set_door",
+            Event::TrailerArrived(_) => "trailer_arrived",
+            Event::ShipmentTrailerArrival(_) => "shipment_trailer_arrival",
+            Event::SetShipmentDoor(_) => "set_shipment_door",
+            Event::StartShipmentPick(_) => "start_shipment_pick",
+            Event::FinishShipmentPick(_) => "finish_shipment_pick",
+            Event::NewShipment(_) => "new_shipment",
+            Event::ShipmentDepart(_) => "shipment_depart",
+            Event::ShipmentStartLoading(_) => "shipment_start_loading",
+            Event::DeleteShipment(_) => "delete_shipment",
+            Event::ShipmentHold(_) => "shipment_hold",
+            Event::VerifiedBy(_) => "verified_by",
+            Event::ShipmentLines(_) => "shipment_lines",
+            Event::ShipmentStatusOverride(_) => "shipment_status_override",
+        }
+    }
+
+    /// Receiving (trailer) events, as opposed to shipping ones.
+    pub fn is_trailer_event(&self) -> bool {
+        self.trailer_schedule().is_some()
+    }
+
+    pub fn load_id(&self) -> Option<&str> {
+        match self {
+            Event::DeleteShipment(request) => Some(&request.LoadId),
+            Event::ShipmentLines(request) => Some(&request.LoadId),
+            _ => self.shipment().map(|shipment| shipment.LoadId.as_str()),
+        }
+    }
+
+    /// The door after the change. Deletes and line updates don't carry one.
+    pub fn door(&self) -> Option<&str> {
+        match self.trailer_schedule() {
+            Some(trailer) => Some(&trailer.Schedule.DoorNumber),
+            None => self.shipment().map(|shipment| shipment.Door.as_str()),
+        }
+    }
+
+    pub fn schedule_date(&self) -> Option<&str> {
+        match self.trailer_schedule() {
+            Some(trailer) => Some(&trailer.Schedule.ScheduleDate),
+            None => self.shipment().map(|shipment| shipment.ScheduleDate.as_str()),
+        }
+    }
+
+    fn trailer_schedule(&self) -> Option<&TrailerSchedule> {
+        match self {
+            Event::HotTrailer(trailer)
+            | Event::ScheduleTrailer(trailer)
+            | Event::SetDoor(trailer)
+            | Event::TrailerArrived(trailer) => Some(trailer),
+            _ => None,
+        }
+    }
+
+    fn shipment(&self) -> Option<&Shipment> {
+        match self {
+            Event::ShipmentTrailerArrival(shipment)
+            | Event::SetShipmentDoor(shipment)
+            | Event::StartShipmentPick(shipment)
+            | Event::FinishShipmentPick(shipment)
+            | Event::NewShipment(shipment)
+            | Event::ShipmentDepart(shipment)
+            | Event::ShipmentStartLoading(shipment)
+            | Event::ShipmentHold(shipment)
+            | Event::VerifiedBy(shipment)
+            | Event::ShipmentStatusOverride(shipment) => Some(shipment),
+            _ => None,
+        }
+    }
+}
+
+/// Fan-out of events to every subscriber. A subscriber that falls more than the
+/// capacity behind loses the oldest events and is told how many it missed.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    pub fn publish(&self, event: Event) {
+        // Only fails when nobody is subscribed, which is fine.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
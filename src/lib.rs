@@ -0,0 +1,224 @@
+pub mod auth;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod mapping;
+pub mod repository;
+pub mod role;
+pub mod permission;
+pub mod status;
+pub mod structs;
+pub mod getters;
+pub mod loginroutes;
+pub mod setters;
+pub mod throttle;
+pub mod adminroutes;
+pub mod wsserver;
+pub mod wsprotocol;
+
+use std::fmt;
+use std::sync::Arc;
+use rocket::{catch, catchers, routes, Build, Rocket};
+use rocket::fairing::AdHoc;
+use rocket::figment::providers::Serialized;
+use rocket_cors::{AllowedHeaders, AllowedOrigins, CorsOptions};
+use config::{AppConfig, Storage};
+use error::{ApiError, RequestId};
+use repository::{MemoryStore, Neo4jStore};
+use structs::AppState;
+use getters::*;
+use loginroutes::*;
+use setters::*;
+use adminroutes::*;
+use wsserver::*;
+
+
+/*
+    CORS Config
+*/
+
+fn custom_cors(config: &AppConfig) -> rocket_cors::Cors {
+    let allowed_origins = if config.allows_any_origin() {
+        AllowedOrigins::all()
+    } else {
+        AllowedOrigins::some_exact(&config.cors_allowed_origins)
+    };
+
+    CorsOptions::default()
+        .allowed_origins(allowed_origins)
+        .allowed_headers(AllowedHeaders::some(&["Authorization", "Accept", "Content-Type"]))
+        .allow_credentials(true)
+        .to_cors()
+        .expect("error creating CORS fairing")
+}
+
+/*
+    Catchers
+
+    Guards and Rocket itself fail with a bare status, these give those failures
+    the same JSON body as ApiError.
+*/
+
+#[catch(400)]
+fn bad_request() -> ApiError {
+    ApiError::BadRequest("Bad Request".to_string())
+}
+
+#[catch(401)]
+fn unauthorized() -> ApiError {
+    ApiError::Unauthorized("Unauthorized".to_string())
+}
+
+#[catch(403)]
+fn forbidden() -> ApiError {
+    ApiError::Forbidden("Forbidden".to_string())
+}
+
+#[catch(404)]
+fn not_found() -> ApiError {
+    ApiError::NotFound("Not Found".to_string())
+}
+
+#[catch(422)]
+fn unprocessable() -> ApiError {
+    ApiError::Unprocessable("Request body is missing fields or has the wrong types".to_string())
+}
+
+#[catch(500)]
+fn internal_error() -> ApiError {
+    ApiError::Internal("Unhandled server error".to_string())
+}
+
+/*
+    App
+
+    build_rocket opens the configured store and mounts everything, main only adds
+    launch. Tests call it with storage = "memory" and drive it through a local Client.
+*/
+
+#[derive(Debug)]
+pub struct StartupError(pub String);
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StartupError {}
+
+pub async fn connect_neo4j(config: &AppConfig) -> Result<Neo4jStore, StartupError> {
+    Neo4jStore::connect(&config.neo4j_uri, &config.neo4j_user, &config.neo4j_password).await
+        .map_err(|e| StartupError(format!("Failed to connect to Neo4j: {}", e)))
+}
+
+async fn open_state(config: AppConfig) -> Result<AppState, StartupError> {
+    let state = match config.storage {
+        Storage::Neo4j => {
+            let store = connect_neo4j(&config).await?;
+            if config.run_migrations {
+                for migration in store.migrate().await.map_err(|e| StartupError(e.to_string()))? {
+                    println!("Applied migration {} ({})", migration.version, migration.name);
+                }
+            } else {
+                let pending = store.pending_migrations().await
+                    .map_err(|e| StartupError(format!("Failed to read applied migrations: {}", e)))?;
+                if let Some(migration) = pending.iter().find(|m| m.creates_constraints()) {
+                    return Err(StartupError(format!(
+                        "Migration {} ({}) adds constraints the app relies on, run `rocket_http migrate` before starting",
+                        migration.version, migration.name,
+                    )));
+                }
+                if !pending.is_empty() {
+                    println!("{} migration(s) pending, run `rocket_http migrate` to apply them", pending.len());
+                }
+            }
+            AppState::new(config, Arc::new(store))
+        }
+        Storage::Memory => {
+            let store = match &config.memory_snapshot {
+                Some(path) => MemoryStore::open(path)
+                    .map_err(|e| StartupError(format!("Failed to load snapshot {}: {}", path, e)))?,
+                None => MemoryStore::default(),
+            };
+            AppState::new(config, Arc::new(store))
+        }
+    };
+
+    permission::bootstrap(state.users.as_ref()).await
+        .map_err(|e| StartupError(format!("Failed to set up roles and permissions: {}", e)))?;
+    Ok(state)
+}
+
+/// The whole app for an already validated config, ready to launch or to hand to a local Client.
+pub async fn build_rocket(config: AppConfig) -> Result<Rocket<Build>, StartupError> {
+    // Rocket's own settings (limits, log level, ...) still come from Rocket.toml and
+    // the environment; the config passed in wins for the keys it has.
+    let figment = config::figment().merge(Serialized::globals(&config));
+    let cors = custom_cors(&config);
+    let state = open_state(config).await?;
+
+    Ok(rocket::custom(figment)
+        .attach(cors)
+        .attach(RequestId::fairing())
+        .attach(token_fairing())
+        .attach(AdHoc::on_liftoff("Websocket broadcaster", |rocket| Box::pin(async move {
+            if let Some(state) = rocket.state::<AppState>() {
+                tokio::spawn(broadcast_events(state.events.clone(), state.ws_list.clone(), state.ws_replay.clone()));
+            }
+        })))
+        .register("/", catchers![bad_request, unauthorized, forbidden, not_found, unprocessable, internal_error])
+        .mount("/", routes![
+            get_shipment_details,
+            shipment_lines,
+            get_todays_shipments,
+            shipment_hold,
+            delete_shipment,
+            shipment_verification,
+            get_shipments,
+            shipment_history,
+            shipment_pick_finish,
+            shipment_begin_loading,
+            shipment_door,
+            new_shipment,
+            set_shipment_trailer,
+            set_shipment_departure_time,
+            set_shipment_pick_start,
+            shipment_status_override,
+            get_counts,
+            todays_trucks,
+            get_load_count,
+            date_range_trucks,
+            set_arrival_time,
+            set_door,
+            hot_trailer,
+            set_schedule,
+            get_load_info,
+            schedule_changes,
+            trailers,
+            ws_handler,
+            list_permissions,
+            list_roles,
+            set_role,
+            delete_role,
+            list_users,
+            get_user,
+            update_user_role,
+            disable_user,
+            enable_user,
+            reset_user_password,
+            unlock_user,
+            delete_user,
+            create_invite,
+            list_invites,
+            delete_invite,
+            websocket_stats,
+            refresh_token,
+            logout,
+            change_password,
+            login,
+            schedule_trailer,
+            register
+            ])
+        .manage(state))
+}
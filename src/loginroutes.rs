@@ -1,138 +1,312 @@
 use crate::structs::*;
-use crate::auth::{decode_token, Claims};
+use crate::auth::{decode_token, password_problems, Claims, TokenType};
+use crate::role::RequireAdmin;
+use crate::repository::{RefreshTokenRecord, RepoError, UserRecord, UserUpdate};
+use crate::throttle;
+use crate::error::ApiError;
 use rocket::{post, serde::json::Json, State};
-use neo4rs::{query, Node};
+use std::net::IpAddr;
+use std::sync::OnceLock;
 use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::{Utc, Duration};
-use jsonwebtoken::{encode, Header, EncodingKey};
+use uuid::Uuid;
 
+/*
+    Refresh token store
 
+    Every refresh token is stored with its jti and family. A login starts a new
+    family, and each /refresh revokes the presented token and issues the next one in
+    the same family. A revoked token coming back means it was copied, so the whole
+    family is revoked and the user has to log in again.
+*/
+
+async fn issue_tokens(state: &AppState, username: &str, role: &str, family: &str) -> Result<(String, String), ApiError> {
+    let now = Utc::now();
+    let access_expiration = (now + Duration::seconds(3600)).timestamp();
+    let refresh_expiration = (now + Duration::days(30)).timestamp();
+    let refresh_jti = Uuid::new_v4().to_string();
+
+    let access_token = state.jwt_keys.encode(&Claims {
+        username: username.to_string(),
+        role: role.to_string(),
+        exp: access_expiration as usize,
+        token_type: TokenType::Access,
+        jti: Uuid::new_v4().to_string(),
+    })?;
+
+    let refresh_token = state.jwt_keys.encode(&Claims {
+        username: username.to_string(),
+        role: role.to_string(),
+        exp: refresh_expiration as usize,
+        token_type: TokenType::Refresh,
+        jti: refresh_jti.clone(),
+    })?;
+
+    state.users.store_refresh_token(&RefreshTokenRecord {
+        jti: refresh_jti,
+        family: family.to_string(),
+        username: username.to_string(),
+        expires: refresh_expiration,
+        created_at: now.to_rfc3339(),
+        revoked: false,
+    }).await?;
+
+    Ok((access_token, refresh_token))
+}
+
+fn login_failed() -> ApiError {
+    ApiError::Unauthorized("Invalid username or password".to_string())
+}
+
+fn invalid_refresh_token() -> ApiError {
+    ApiError::Unauthorized("Invalid refresh token".to_string())
+}
+
+// Checked against when the username does not exist, so both failures take as long.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash(Uuid::new_v4().to_string(), DEFAULT_COST).unwrap_or_default())
+}
+
+async fn record_login_failure(state: &AppState, keys: &[String], now: i64) {
+    for key in keys {
+        let policy = throttle::policy(key, &state.config);
+        if let Err(e) = state.users.record_login_failure(key, &policy, now).await {
+            println!("Failed to record login failure for {}: {}", key, e);
+        }
+    }
+}
 
 #[post("/login", format = "json", data = "<login_request>")]
-pub async fn login(login_request: Json<LoginRequest>, state: &State<AppState>) -> Result<Json<LoginResponse>, Json<String>> {
-    let graph = &state.graph;
+pub async fn login(
+    login_request: Json<LoginRequest>,
+    state: &State<AppState>,
+    client_ip: Option<IpAddr>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    let now = Utc::now().timestamp();
 
-    let query = query("
-        MATCH (u:User {name: $username}) RETURN u
-    ").param("username", login_request.username.clone());
+    let mut keys = vec![throttle::user_key(&login_request.username)];
+    if let Some(ip) = &client_ip {
+        keys.push(throttle::ip_key(ip));
+    }
 
-    let mut result = match graph.execute(query).await {
-        Ok(r) => r,
-        Err(e) => return Err(Json(e.to_string())),
-    };
+    if let Some(until) = state.users.locked_until(&keys, now).await? {
+        return Err(ApiError::TooManyRequests {
+            message: "Too many failed login attempts, try again later".to_string(),
+            retry_after: (until - now).max(1),
+        });
+    }
 
-    if let Some(record) = result.next().await.unwrap() {
-        let user_node: Node = record.get("u").unwrap();
-
-        let stored_password: String = user_node.get::<String>("password").unwrap().to_string();
-        let username: String = user_node.get::<String>("name").unwrap().to_string();
-        let role: String = user_node.get::<String>("role").unwrap().to_string();
-
-        let is_password_valid = match verify(&login_request.password, &stored_password) {
-            Ok(valid) => valid,
-            Err(e) => return Err(Json(e.to_string())),
-        };
-
-        if is_password_valid {
-            let access_expiration = Utc::now()
-                .checked_add_signed(Duration::seconds(3600))
-                .expect("valid timestamp")
-                .timestamp() as usize;
-
-            let refresh_expiration = Utc::now()
-                .checked_add_signed(Duration::days(30))
-                .expect("valid timestamp")
-                .timestamp() as usize;
-
-            let access_token = match encode(
-                &Header::default(),
-                &Claims { username: username.clone(), role: role.clone(), exp: access_expiration },
-                &EncodingKey::from_secret(state.jwt_secret.as_ref()),
-            ) {
-                Ok(t) => t,
-                Err(e) => return Err(Json(e.to_string())),
-            };
+    if let Some(user) = state.users.find_user(&login_request.username).await? {
+        let UserRecord { username, password: stored_password, role, disabled, must_reset_password } = user;
 
-            let refresh_token = match encode(
-                &Header::default(),
-                &Claims { username: username.clone(), role: role.clone(), exp: refresh_expiration },
-                &EncodingKey::from_secret(state.jwt_secret.as_ref()),
-            ) {
-                Ok(t) => t,
-                Err(e) => return Err(Json(e.to_string())),
-            };
+        let is_password_valid = verify(&login_request.password, &stored_password).unwrap_or(false);
+
+        if !is_password_valid {
+            record_login_failure(state, &keys, now).await;
+            return Err(login_failed());
+        }
+
+        if let Err(e) = state.users.clear_login_failures(&keys[0]).await {
+            println!("Failed to clear login failures for {}: {}", username, e);
+        }
+
+        if disabled {
+            Err(ApiError::Forbidden("Account disabled".to_string()))
+        } else if must_reset_password {
+            Err(ApiError::Forbidden("Password reset required".to_string()))
+        } else {
+            let (access_token, refresh_token) = issue_tokens(state, &username, &role, &Uuid::new_v4().to_string()).await?;
 
             let response = LoginResponse {
                 token: access_token,
-                refresh_token: Some(refresh_token),  // Add this field in the response
+                refresh_token: Some(refresh_token),
                 user: UserResponse {
                     username,
                     role,
                 },
             };
-            return Ok(Json(response));
-        } else {
-            return Err(Json("Invalid password".to_string()));
+            Ok(Json(response))
         }
     } else {
-        return Err(Json("User not found".to_string()));
+        let _ = verify(&login_request.password, dummy_hash());
+        record_login_failure(state, &keys, now).await;
+        Err(login_failed())
     }
 }
 
-#[post("/register", format = "json", data = "<user>")]
-pub async fn register(user: Json<LoginRequest>, state: &State<AppState>) -> Result<Json<&'static str>, String> {
-    let graph = &state.graph;
+/*
+    Registration
 
-    let hashed_password = match hash(&user.password, DEFAULT_COST) {
-        Ok(p) => p,
-        Err(e) => return Err(e.to_string()),
-    };
+    An admin can register anyone with any existing role. Everyone else needs an
+    unused invite code, which carries the role, unless open_registration is on,
+    in which case they get a read account.
+*/
+
+#[post("/register", format = "json", data = "<registration>")]
+pub async fn register(
+    registration: Json<RegisterRequest>,
+    state: &State<AppState>,
+    admin: Option<RequireAdmin>,
+) -> Result<Json<&'static str>, ApiError> {
+    let username = registration.username.trim().to_string();
+
+    if username.is_empty() {
+        return Err(ApiError::BadRequest("Username is required".to_string()));
+    }
+    let problems = password_problems(&username, &registration.password, state.config.password_min_length);
+    if !problems.is_empty() {
+        return Err(ApiError::Unprocessable(problems.join("; ")));
+    }
+    if registration.role.is_some() && admin.is_none() {
+        return Err(ApiError::Forbidden("Only admins can choose a role".to_string()));
+    }
+
+    if state.users.find_user(&username).await?.is_some() {
+        return Err(ApiError::conflict("Username already taken"));
+    }
 
-    println!("{} {}", user.username.clone(), hashed_password);
+    let hashed_password = hash(&registration.password, DEFAULT_COST)?;
+    let now = Utc::now();
 
-    let query = query("CREATE (u:User {name: $username, password: $password, role: 'read'})")
-        .param("username", user.username.clone())
-        .param("password", hashed_password);
+    let new_user = |role: String| UserRecord {
+        username: username.clone(),
+        password: hashed_password.clone(),
+        role,
+        disabled: false,
+        must_reset_password: false,
+    };
 
-    match graph.run(query).await {
-        Ok(_) => Ok(Json("User registered")),
-        Err(e) => Err(format!("Failed to register user: {:?}", e)),
+    let created = if admin.is_some() {
+        let role = registration.role.clone().unwrap_or("read".to_string());
+        if state.users.find_role(&role).await?.is_none() {
+            return Err(ApiError::Unprocessable(format!("Unknown role: {}", role)));
+        }
+        state.users.create_user(&new_user(role), &now.to_rfc3339()).await.map(|_| true)
+    } else if let Some(code) = &registration.invite_code {
+        state.users.redeem_invite(code, &username, &hashed_password, now).await
+    } else if state.config.open_registration {
+        state.users.create_user(&new_user("read".to_string()), &now.to_rfc3339()).await.map(|_| true)
+    } else {
+        return Err(ApiError::Forbidden("Registration requires an invite code".to_string()));
+    };
+
+    match created {
+        Ok(true) => Ok(Json("User registered")),
+        Ok(false) => Err(ApiError::Forbidden("Invalid or expired invite code".to_string())),
+        Err(RepoError::Duplicate) => Err(ApiError::conflict("Username already taken")),
+        Err(e) => Err(e.into()),
     }
 }
 
 #[post("/refresh", format = "json", data = "<refresh_request>")]
-pub async fn refresh_token(refresh_request: Json<RefreshRequest>, state: &State<AppState>) -> Result<Json<LoginResponse>, Json<String>> {
-    let claims = match decode_token(&refresh_request.refresh_token, &state.jwt_secret) {
-        Ok(claims) => claims,
-        Err(_) => return Err(Json("Invalid refresh token".to_string())),
+pub async fn refresh_token(refresh_request: Json<RefreshRequest>, state: &State<AppState>) -> Result<Json<LoginResponse>, ApiError> {
+    let claims = match decode_token(&refresh_request.refresh_token, &state.jwt_keys) {
+        Ok(claims) if claims.token_type == TokenType::Refresh => claims,
+        _ => return Err(invalid_refresh_token()),
     };
 
-    let new_expiration = Utc::now()
-        .checked_add_signed(Duration::seconds(3600))
-        .expect("valid timestamp")
-        .timestamp() as usize;
-
-    let new_token = match encode(
-        &Header::default(),
-        &Claims {
-            username: claims.username.clone(),
-            role: claims.role.clone(),
-            exp: new_expiration,
-        },
-        &EncodingKey::from_secret(state.jwt_secret.as_ref()),
-    ) {
-        Ok(t) => t,
-        Err(e) => return Err(Json(e.to_string())),
+    // Consume the presented token. Revoking it and reading the previous flag in one
+    // statement means two concurrent refreshes cannot both succeed. The role is
+    // re-read from the user so admin changes apply from the next refresh.
+    let consumed = match state.users.consume_refresh_token(&claims.jti, &Utc::now().to_rfc3339()).await? {
+        Some(consumed) => consumed,
+        None => return Err(invalid_refresh_token()),
+    };
+    let (family, was_revoked, role, blocked) = (consumed.family, consumed.was_revoked, consumed.role, consumed.blocked);
+
+    if was_revoked {
+        println!("Refresh token reuse detected for {}, revoking token family {}", claims.username, family);
+        state.users.revoke_token_family(&family).await?;
+        return Err(invalid_refresh_token());
+    }
+
+    let role = match role {
+        Some(role) if !blocked => role,
+        _ => return Err(invalid_refresh_token()),
     };
 
+    let (access_token, refresh_token) = issue_tokens(state, &claims.username, &role, &family).await?;
+
     let response = LoginResponse {
-        token: new_token,
-        refresh_token: None,  // Do not issue a new refresh token
+        token: access_token,
+        refresh_token: Some(refresh_token),
         user: UserResponse {
             username: claims.username,
-            role: claims.role,
+            role,
         },
     };
 
     Ok(Json(response))
-}
\ No newline at end of file
+}
+
+#[post("/logout", format = "json", data = "<logout_request>")]
+pub async fn logout(logout_request: Json<RefreshRequest>, state: &State<AppState>) -> Result<Json<&'static str>, ApiError> {
+    let claims = match decode_token(&logout_request.refresh_token, &state.jwt_keys) {
+        Ok(claims) if claims.token_type == TokenType::Refresh => claims,
+        _ => return Err(invalid_refresh_token()),
+    };
+
+    if let Some(family) = state.users.refresh_token_family(&claims.jti).await? {
+        state.users.revoke_token_family(&family).await?;
+    }
+
+    Ok(Json("Logged out"))
+}
+
+/// Self-service password change. The current password is checked again, so this also
+/// works for accounts an admin has flagged for a reset, which cannot log in until then.
+#[post("/change_password", format = "json", data = "<change_request>")]
+pub async fn change_password(
+    change_request: Json<ChangePasswordRequest>,
+    state: &State<AppState>,
+    client_ip: Option<IpAddr>,
+) -> Result<Json<&'static str>, ApiError> {
+    let now = Utc::now().timestamp();
+
+    // Same lockout as /login, otherwise this would be a way around it.
+    let mut keys = vec![throttle::user_key(&change_request.username)];
+    if let Some(ip) = &client_ip {
+        keys.push(throttle::ip_key(ip));
+    }
+    if let Some(until) = state.users.locked_until(&keys, now).await? {
+        return Err(ApiError::TooManyRequests {
+            message: "Too many failed login attempts, try again later".to_string(),
+            retry_after: (until - now).max(1),
+        });
+    }
+
+    let (stored_password, disabled) = match state.users.find_user(&change_request.username).await? {
+        Some(user) => (user.password, user.disabled),
+        None => (dummy_hash().to_string(), false),
+    };
+
+    if !verify(&change_request.current_password, &stored_password).unwrap_or(false) {
+        record_login_failure(state, &keys, now).await;
+        return Err(login_failed());
+    }
+    if disabled {
+        return Err(ApiError::Forbidden("Account disabled".to_string()));
+    }
+
+    if change_request.new_password == change_request.current_password {
+        return Err(ApiError::Unprocessable("New password must be different from the current one".to_string()));
+    }
+    let problems = password_problems(&change_request.username, &change_request.new_password, state.config.password_min_length);
+    if !problems.is_empty() {
+        return Err(ApiError::Unprocessable(problems.join("; ")));
+    }
+
+    let hashed_password = hash(&change_request.new_password, DEFAULT_COST)?;
+
+    state.users.update_user(&change_request.username, &UserUpdate {
+        password: Some(hashed_password),
+        must_reset_password: Some(false),
+        password_changed_at: Some(Utc::now().to_rfc3339()),
+        ..UserUpdate::default()
+    }).await?;
+    state.users.revoke_user_tokens(&change_request.username).await?;
+    state.users.clear_login_failures(&keys[0]).await?;
+
+    Ok(Json("Password changed"))
+}
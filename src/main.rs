@@ -1,99 +1,82 @@
 extern crate rocket;
 
-mod auth;
-mod role;
-mod structs;
-mod getters;
-mod loginroutes;
-mod setters;
-mod wsserver;
+use rocket_http::{build_rocket, config, connect_neo4j};
+use rocket_http::config::{AppConfig, Storage};
+use rocket_http::repository::MIGRATIONS;
 
-use rocket::routes;
-use neo4rs::Graph;
-use structs::AppState;
-use tokio::sync::Mutex;
-use std::{collections::HashMap, sync::Arc};
-use rocket_cors::{CorsOptions, AllowedHeaders};
-use getters::*;
-use loginroutes::*;
-use setters::*;
-use wsserver::*;
 
+const USAGE: &str = "usage: rocket_http [migrate [status]]";
 
-/*
-    CORS Config
-*/
-
-fn custom_cors() -> rocket_cors::Cors {
-    CorsOptions::default()
-        .allowed_origins(rocket_cors::AllOrSome::All)
-        .allowed_headers(AllowedHeaders::some(&["Authorization", "Accept", "Content-Type"]))
-        .allow_credentials(true)
-        .to_cors()
-        .expect("error creating CORS fairing")
-}
-
-impl AppState {
-    pub async fn new() -> Self {
-        let graph = Graph::new("bolt://localhost:7687", "neo4j", "Asdf123$").await.unwrap();
+/// `migrate` applies pending migrations and exits, `migrate status` only lists them.
+async fn migrate(config: &AppConfig, status_only: bool) -> Result<(), String> {
+    if config.storage != Storage::Neo4j {
+        return Err("Migrations only apply to storage = \"neo4j\"".to_string());
+    }
+    let store = connect_neo4j(config).await.map_err(|e| e.to_string())?;
 
-        AppState {
-            ws_list: Arc::new(Mutex::new(HashMap::new())),
-            graph: Arc::new(graph),
-            jwt_secret: "tO7E8uCjD5rXpQl0FhKwV2yMz4bJnAi9sGeR3kTzXvNmPuLsDq8W".to_string(),
+    if status_only {
+        let applied = store.applied_migrations().await.map_err(|e| e.to_string())?;
+        for migration in MIGRATIONS {
+            match applied.iter().find(|a| a.version == migration.version) {
+                Some(a) => println!("{:>4}  {:<24} applied {}", migration.version, migration.name, a.applied_at),
+                None => println!("{:>4}  {:<24} pending", migration.version, migration.name),
+            }
         }
+        return Ok(());
+    }
+
+    let applied = store.migrate().await.map_err(|e| e.to_string())?;
+    if applied.is_empty() {
+        println!("Nothing to migrate");
+    }
+    for migration in applied {
+        println!("Applied migration {} ({})", migration.version, migration.name);
     }
+    Ok(())
 }
 
 #[rocket::main]
 async fn main() {
-    let state = AppState::new().await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
 
+    let figment = config::figment();
 
-    // Configure CORS
-    let cors = custom_cors();
+    // Fail fast on bad config rather than at the first request.
+    let app_config = match AppConfig::from_figment(&figment) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    rocket::custom(
-        rocket::Config {
-            address: "0.0.0.0".parse().expect("Invalid IP address"),
-            port: 8000,
-            ..rocket::Config::default()
+    let status_only = match args.as_slice() {
+        [] => None,
+        ["migrate"] => Some(false),
+        ["migrate", "status"] => Some(true),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Some(status_only) = status_only {
+        if let Err(e) = migrate(&app_config, status_only).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-    )
-        .attach(cors)
-        .mount("/", routes![
-            get_shipment_details,
-            shipment_lines,
-            get_todays_shipments,
-            shipment_hold,
-            delete_shipment,
-            shipment_verification,
-            get_shipments,
-            shipment_pick_finish,
-            shipment_begin_loading,
-            shipment_door,
-            new_shipment,
-            set_shipment_trailer,
-            set_shipment_departure_time,
-            set_shipment_pick_start,
-            get_counts,
-            todays_trucks,
-            get_load_count,
-            date_range_trucks,
-            set_arrival_time,
-            set_door,
-            hot_trailer,
-            set_schedule,
-            get_load_info,
-            trailers,
-            ws_handler,
-            refresh_token,
-            login,
-            schedule_trailer,
-            register
-            ])
-        .manage(state)
-        .launch()
+        return;
+    }
+
+    let rocket = match build_rocket(app_config).await {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    rocket.launch()
         .await
         .unwrap();
-}
\ No newline at end of file
+}
@@ -0,0 +1,200 @@
+use neo4rs::{DeError, Node, Row};
+use serde::Deserialize;
+use crate::structs::*;
+
+/*
+    Neo4j result mapping
+
+    FromNode builds a struct from a node's properties, FromRow from the columns of
+    a result row. The rules are the same everywhere:
+
+    - a property that is missing or null gets the field's default ("" / false / 0),
+      since older nodes were written before some properties existed
+    - a property with the wrong type is an error, as is a missing row column,
+      because both mean the query and the struct disagree
+
+    Errors turn into a 500 through ApiError, never a panic.
+*/
+
+pub trait FromNode: Sized {
+    fn from_node(node: &Node) -> Result<Self, DeError>;
+}
+
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, DeError>;
+}
+
+/// Anything with named, typed values: node properties or row columns.
+trait Properties {
+    fn value<'a, T: Deserialize<'a>>(&'a self, key: &str) -> Result<T, DeError>;
+}
+
+impl Properties for Node {
+    fn value<'a, T: Deserialize<'a>>(&'a self, key: &str) -> Result<T, DeError> {
+        self.get(key)
+    }
+}
+
+impl Properties for Row {
+    fn value<'a, T: Deserialize<'a>>(&'a self, key: &str) -> Result<T, DeError> {
+        self.get(key)
+    }
+}
+
+/// A property that may be missing or null, in which case it is `T::default()`.
+fn optional<'a, T, P>(source: &'a P, key: &str) -> Result<T, DeError>
+where
+    T: Deserialize<'a> + Default,
+    P: Properties,
+{
+    match source.value::<Option<T>>(key) {
+        Ok(value) => Ok(value.unwrap_or_default()),
+        Err(DeError::NoSuchProperty) => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The node in `column`, mapped with its FromNode impl.
+pub fn node_column<T: FromNode>(row: &Row, column: &str) -> Result<T, DeError> {
+    T::from_node(&row.get::<Node>(column)?)
+}
+
+impl FromNode for Schedule {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        Ok(Schedule {
+            ScheduleDate: optional(node, "ScheduleDate")?,
+            ScheduleTime: optional(node, "ScheduleTime")?,
+            ArrivalTime: optional(node, "ArrivalTime")?,
+            CarrierCode: optional(node, "CarrierCode")?,
+            ContactEmail: optional(node, "ContactEmail")?,
+            DoorNumber: optional(node, "DoorNumber")?,
+            IsHot: optional(node, "IsHot")?,
+            LastFreeDate: optional(node, "LastFreeDate")?,
+            LoadStatus: optional(node, "LoadStatus")?,
+            RequestDate: optional(node, "RequestDate")?,
+            Seal: optional(node, "Seal")?,
+            IsMulti: optional(node, "IsMulti")?,
+            IsStat6: optional(node, "IsStat6")?,
+            ClaimComments: optional(node, "ClaimComments")?,
+            HasClaim: optional(node, "HasClaim")?,
+        })
+    }
+}
+
+impl FromNode for Shipment {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        Ok(Shipment {
+            ScheduleDate: optional(node, "ScheduleDate")?,
+            ScheduleTime: optional(node, "ScheduleTime")?,
+            ArrivalTime: optional(node, "ArrivalTime")?,
+            DepartTime: optional(node, "DepartTime")?,
+            Dock: optional(node, "Dock")?,
+            Door: optional(node, "Door")?,
+            LoadId: optional(node, "LoadId")?,
+            LoadNum: optional(node, "LoadNum")?,
+            Status: optional(node, "Status")?,
+            Picker: optional(node, "Picker")?,
+            PickStartTime: optional(node, "PickStartTime")?,
+            PickFinishTime: optional(node, "PickFinishTime")?,
+            VerifiedBy: optional(node, "VerifiedBy")?,
+            TrailerNum: optional(node, "TrailerNum")?,
+            IsHold: optional(node, "IsHold")?,
+            Seal: optional(node, "Seal")?,
+        })
+    }
+}
+
+impl FromNode for ShipmentLine {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        Ok(ShipmentLine {
+            item: optional(node, "PartNumber")?,
+            quantity: optional(node, "Quantity")?,
+            ip: optional(node, "Ip")?,
+        })
+    }
+}
+
+impl FromNode for Count {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        Ok(Count {
+            location: optional(node, "Location")?,
+            item: optional(node, "Item")?,
+            actual: optional(node, "Actual")?,
+            expected: optional(node, "Expected")?,
+            actual_lp_count: optional(node, "ActualLP")?,
+            expected_lp_count: optional(node, "ExpectedLP")?,
+            date: optional(node, "Date")?,
+            comment: optional(node, "Comment")?,
+        })
+    }
+}
+
+impl FromNode for Sid {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        Ok(Sid {
+            CiscoID: optional(node, "ciscoID")?,
+            id: optional(node, "id")?,
+        })
+    }
+}
+
+impl FromNode for ShipmentEvent {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        let payload: String = optional(node, "payload")?;
+
+        Ok(ShipmentEvent {
+            LoadId: optional(node, "LoadId")?,
+            event: optional(node, "event")?,
+            from: optional(node, "from")?,
+            to: optional(node, "to")?,
+            actor: optional(node, "actor")?,
+            at: optional(node, "at")?,
+            payload: serde_json::from_str(&payload).unwrap_or(serde_json::Value::Null),
+        })
+    }
+}
+
+impl FromNode for ScheduleChange {
+    fn from_node(node: &Node) -> Result<Self, DeError> {
+        Ok(ScheduleChange {
+            TrailerID: optional(node, "TrailerID")?,
+            event: optional(node, "event")?,
+            field: optional(node, "field")?,
+            old: optional(node, "old")?,
+            new: optional(node, "new")?,
+            user: optional(node, "user")?,
+            at: optional(node, "at")?,
+        })
+    }
+}
+
+/// Rows of `RETURN trailer.id AS TrailerID, s`.
+impl FromRow for TrailerSchedule {
+    fn from_row(row: &Row) -> Result<Self, DeError> {
+        Ok(TrailerSchedule {
+            TrailerID: row.get("TrailerID")?,
+            Schedule: node_column(row, "s")?,
+        })
+    }
+}
+
+/// Rows of `RETURN trailer.id AS TrailerID, s, COLLECT(cisco.id) AS CiscoIDs`.
+impl FromRow for Trailer {
+    fn from_row(row: &Row) -> Result<Self, DeError> {
+        Ok(Trailer {
+            TrailerID: row.get("TrailerID")?,
+            Schedule: node_column(row, "s")?,
+            CiscoIDs: optional(row, "CiscoIDs")?,
+        })
+    }
+}
+
+/// Rows of `RETURN sid, COLLECT({partNumber, quantity}) AS parts`.
+impl FromRow for SidParts {
+    fn from_row(row: &Row) -> Result<Self, DeError> {
+        Ok(SidParts {
+            Sid: node_column(row, "sid")?,
+            Parts: optional(row, "parts")?,
+        })
+    }
+}
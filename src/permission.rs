@@ -0,0 +1,102 @@
+use std::marker::PhantomData;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::http::Status;
+use crate::auth::AuthenticatedUser;
+use crate::role::Role;
+use crate::structs::AppState;
+use crate::repository::{RepoError, UserRepository};
+
+/*
+    Permissions
+
+    A user's `role` names a (:Role) node, and the role can do whatever its
+    (:Role)-[:GRANTS]->(:Permission) edges allow. `admin` is always allowed
+    everything so it can never lock itself out.
+
+    Routes declare what they need with a `Can<P>` guard, e.g. `_perm: Can<DoorsWrite>`.
+*/
+
+pub trait Permission: Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($ty:ident => $name:literal),* $(,)?) => {
+        $(
+            pub struct $ty;
+
+            impl Permission for $ty {
+                const NAME: &'static str = $name;
+            }
+        )*
+
+        pub const ALL_PERMISSIONS: &[&str] = &[$($name),*];
+    };
+}
+
+permissions! {
+    TrailersRead => "trailers:read",
+    CountsRead => "counts:read",
+    ShipmentsRead => "shipments:read",
+    ScheduleWrite => "schedule:write",
+    DoorsWrite => "doors:write",
+    ShipmentsWrite => "shipments:write",
+    ShipmentsPick => "shipments:pick",
+    ShipmentsDelete => "shipments:delete",
+}
+
+/// Grants given to the built-in roles the first time they are created.
+pub const BUILTIN_ROLES: &[(&str, &[&str])] = &[
+    ("read", &["trailers:read", "counts:read", "shipments:read"]),
+    ("write", &[
+        "trailers:read", "counts:read", "shipments:read",
+        "schedule:write", "doors:write", "shipments:write", "shipments:pick",
+    ]),
+    ("admin", &[]),
+];
+
+/// Creates every known permission, and the built-in roles with their default grants
+/// if they do not exist yet. Existing roles are left alone so admin edits survive restarts.
+pub async fn bootstrap(users: &dyn UserRepository) -> Result<(), RepoError> {
+    users.bootstrap_roles(ALL_PERMISSIONS, BUILTIN_ROLES).await
+}
+
+pub struct Can<P: Permission>(PhantomData<P>);
+
+// Request-local cache of the caller's grants.
+struct Granted(Result<Vec<String>, ()>);
+
+#[rocket::async_trait]
+impl<'r, P: Permission> FromRequest<'r> for Can<P> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(user) => user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+
+        if user.0.role.parse::<Role>() == Ok(Role::Admin) {
+            return Outcome::Success(Can(PhantomData));
+        }
+
+        let state = match request.rocket().state::<AppState>() {
+            Some(state) => state,
+            None => return Outcome::Error((Status::InternalServerError, ())),
+        };
+
+        // Looked up once per request, however many Can<_> guards the route has.
+        let granted = request.local_cache_async(async {
+            Granted(state.users.role_permissions(&user.0.role).await.map_err(|e| {
+                println!("Failed to load permissions for role {}: {}", user.0.role, e);
+            }))
+        }).await;
+
+        match &granted.0 {
+            Ok(permissions) if permissions.iter().any(|p| p == P::NAME) => Outcome::Success(Can(PhantomData)),
+            Ok(_) => Outcome::Error((Status::Forbidden, ())),
+            Err(_) => Outcome::Error((Status::InternalServerError, ())),
+        }
+    }
+}
@@ -0,0 +1,747 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use super::*;
+
+/*
+    In-memory store
+
+    Everything lives in one MemoryData behind a mutex. Each call holds the lock for
+    its whole read-modify-write, which gives the same all-or-nothing behaviour as
+    the single Cypher statements in the Neo4j store.
+
+    With a snapshot path the whole of MemoryData is written out as JSON after
+    each write. The JSON is made under the lock, but written to disk on a blocking
+    task once the lock is released, so the file may trail a write by a moment; the
+    store saves whatever is left when it is dropped. That is plenty for tests and
+    demos, not meant for real volumes.
+*/
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SidRecord {
+    pub id: String,
+    pub CiscoID: String,
+    pub Parts: Vec<Part>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TrailerRecord {
+    pub TrailerID: String,
+    pub Schedule: Schedule,
+    #[serde(default)]
+    pub CiscoIDs: Vec<String>,
+    #[serde(default)]
+    pub Sids: Vec<SidRecord>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShipmentRecord {
+    pub Shipment: Shipment,
+    #[serde(default)]
+    pub Lines: Vec<ShipmentLine>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoleRecord {
+    pub builtin: bool,
+    pub permissions: BTreeSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteRecord {
+    pub code: String,
+    pub role: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires: i64,
+    #[serde(default)]
+    pub used_by: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ThrottleRecord {
+    pub failures: u32,
+    pub last_failure_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MemoryData {
+    pub trailers: Vec<TrailerRecord>,
+    pub schedule_changes: Vec<ScheduleChange>,
+    pub shipments: Vec<ShipmentRecord>,
+    pub shipment_events: Vec<ShipmentEvent>,
+    pub counts: Vec<Count>,
+    pub users: Vec<UserRecord>,
+    pub permissions: BTreeSet<String>,
+    pub roles: BTreeMap<String, RoleRecord>,
+    pub refresh_tokens: Vec<RefreshTokenRecord>,
+    pub invites: Vec<InviteRecord>,
+    pub login_throttles: BTreeMap<String, ThrottleRecord>,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+    snapshot: Option<Arc<Snapshot>>,
+}
+
+struct Snapshot {
+    path: PathBuf,
+    // The newest JSON not yet on disk. Saves take the file lock first, so an
+    // older one can never be written over a newer one.
+    pending: Mutex<Option<Vec<u8>>>,
+    file: Mutex<()>,
+}
+
+impl MemoryStore {
+    pub fn new(data: MemoryData) -> Self {
+        MemoryStore { data: Mutex::new(data), snapshot: None }
+    }
+
+    /// Starts from the JSON snapshot at `path`, or empty if there is none yet, and
+    /// rewrites the snapshot after every write so the data survives a restart.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let data = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => MemoryData::default(),
+            Err(e) => return Err(e),
+        };
+        let snapshot = Snapshot { path, pending: Mutex::new(None), file: Mutex::new(()) };
+        Ok(MemoryStore { data: Mutex::new(data), snapshot: Some(Arc::new(snapshot)) })
+    }
+
+    fn data(&self) -> MutexGuard<'_, MemoryData> {
+        lock(&self.data)
+    }
+
+    fn data_mut(&self) -> WriteGuard<'_> {
+        WriteGuard { data: self.data(), snapshot: self.snapshot.as_ref() }
+    }
+}
+
+impl Drop for MemoryStore {
+    fn drop(&mut self) {
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.save();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panic while holding the lock leaves the data as it was, still usable.
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The lock for a write; saves the snapshot, if there is one, when released.
+struct WriteGuard<'a> {
+    data: MutexGuard<'a, MemoryData>,
+    snapshot: Option<&'a Arc<Snapshot>>,
+}
+
+impl Deref for WriteGuard<'_> {
+    type Target = MemoryData;
+
+    fn deref(&self) -> &MemoryData {
+        &self.data
+    }
+}
+
+impl DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut MemoryData {
+        &mut self.data
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        let Some(snapshot) = self.snapshot else { return };
+        // Serialized here, while the lock still orders it against other writes.
+        match serde_json::to_vec_pretty(&*self.data) {
+            Ok(json) => {
+                *lock(&snapshot.pending) = Some(json);
+                let snapshot = Arc::clone(snapshot);
+                match tokio::runtime::Handle::try_current() {
+                    Ok(runtime) => drop(runtime.spawn_blocking(move || snapshot.save())),
+                    Err(_) => snapshot.save(),
+                }
+            },
+            Err(e) => println!("Failed to serialize snapshot {}: {}", snapshot.path.display(), e),
+        }
+    }
+}
+
+impl Snapshot {
+    fn save(&self) {
+        let _file = lock(&self.file);
+        let Some(json) = lock(&self.pending).take() else { return };
+        // The write itself already happened, so a failed save is only reported.
+        if let Err(e) = write_replacing(&self.path, &json) {
+            println!("Failed to save snapshot {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn write_replacing(path: &Path, json: &[u8]) -> io::Result<()> {
+    // Write beside the snapshot and rename over it, so a crash never leaves half a file.
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+impl MemoryData {
+    fn trailer_mut(&mut self, trailer_id: &str) -> Option<&mut TrailerRecord> {
+        self.trailers.iter_mut().find(|t| t.TrailerID == trailer_id)
+    }
+
+    fn shipment(&self, load_id: &str) -> Option<&ShipmentRecord> {
+        self.shipments.iter().find(|s| s.Shipment.LoadId == load_id)
+    }
+
+    fn shipment_mut(&mut self, load_id: &str) -> Option<&mut ShipmentRecord> {
+        self.shipments.iter_mut().find(|s| s.Shipment.LoadId == load_id)
+    }
+
+    fn user_mut(&mut self, username: &str) -> Option<&mut UserRecord> {
+        self.users.iter_mut().find(|u| u.username == username)
+    }
+
+    fn role_response(&self, name: &str) -> Option<RoleResponse> {
+        self.roles.get(name).map(|role| RoleResponse {
+            name: name.to_string(),
+            builtin: role.builtin,
+            permissions: role.permissions.iter().cloned().collect(),
+        })
+    }
+}
+
+fn trailer_schedules(trailer: Option<&mut TrailerRecord>, update: impl FnOnce(&mut Schedule)) -> Vec<TrailerSchedule> {
+    match trailer {
+        Some(trailer) => {
+            update(&mut trailer.Schedule);
+            vec![TrailerSchedule {
+                TrailerID: trailer.TrailerID.clone(),
+                Schedule: trailer.Schedule.clone(),
+            }]
+        },
+        None => Vec::new(),
+    }
+}
+
+fn scheduled_trailers<'a>(data: &'a MemoryData, on: impl Fn(&Schedule) -> bool + 'a) -> impl Iterator<Item = Trailer> + 'a {
+    data.trailers.iter()
+        .filter(move |t| !t.CiscoIDs.is_empty() && on(&t.Schedule))
+        .map(|t| Trailer {
+            TrailerID: t.TrailerID.clone(),
+            Schedule: t.Schedule.clone(),
+            CiscoIDs: t.CiscoIDs.clone(),
+        })
+}
+
+fn by_schedule_date_desc(shipments: &mut [Shipment]) {
+    shipments.sort_by(|a, b| b.ScheduleDate.cmp(&a.ScheduleDate));
+}
+
+/*
+    Trailers
+*/
+
+#[rocket::async_trait]
+impl TrailerRepository for MemoryStore {
+    async fn all(&self) -> Result<Vec<Trailer>, RepoError> {
+        Ok(scheduled_trailers(&self.data(), |_| true).collect())
+    }
+
+    async fn in_date_range(&self, date1: &str, date2: &str) -> Result<Vec<Trailer>, RepoError> {
+        let data = self.data();
+        Ok(scheduled_trailers(&data, |s| s.ScheduleDate.as_str() >= date1 && s.ScheduleDate.as_str() <= date2).collect())
+    }
+
+    async fn sid_parts(&self, trailer_id: &str) -> Result<Vec<SidParts>, RepoError> {
+        let data = self.data();
+        Ok(data.trailers.iter()
+            .filter(|t| t.TrailerID == trailer_id)
+            .flat_map(|t| t.Sids.iter())
+            .filter(|sid| !sid.Parts.is_empty())
+            .map(|sid| SidParts {
+                Sid: Sid { CiscoID: sid.CiscoID.clone(), id: sid.id.clone() },
+                Parts: sid.Parts.clone(),
+            })
+            .collect())
+    }
+
+    async fn sids_for_date(&self, date: &str) -> Result<Vec<Sids>, RepoError> {
+        let data = self.data();
+        Ok(data.trailers.iter()
+            .filter(|t| t.Schedule.ScheduleDate == date)
+            .map(|t| Sids {
+                TrailerID: t.TrailerID.clone(),
+                Sids: t.Sids.iter().flat_map(|sid| sid.Parts.iter().map(|part| SidAndParts {
+                    Sid: sid.id.clone(),
+                    Cisco: sid.CiscoID.clone(),
+                    Part: part.partNumber.clone(),
+                    Quantity: part.quantity,
+                })).collect(),
+            })
+            .filter(|t| !t.Sids.is_empty())
+            .collect())
+    }
+
+    async fn schedule(&self, trailer_id: &str) -> Result<Option<Schedule>, RepoError> {
+        Ok(self.data().trailer_mut(trailer_id).map(|t| t.Schedule.clone()))
+    }
+
+    async fn set_schedule(&self, request: &SetScheduleRequest) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let mut data = self.data_mut();
+        Ok(trailer_schedules(data.trailer_mut(&request.TrailerID), |s| {
+            s.ScheduleDate = request.ScheduleDate.clone();
+            s.RequestDate = request.RequestDate.clone();
+            s.CarrierCode = request.CarrierCode.clone();
+            s.ScheduleTime = request.ScheduleTime.clone();
+            s.LastFreeDate = request.LastFreeDate.clone();
+            s.ContactEmail = request.ContactEmail.clone();
+            s.DoorNumber = request.Door.clone();
+            s.Seal = request.Seal.clone();
+        }))
+    }
+
+    async fn set_door(&self, trailer_id: &str, door: &str) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let mut data = self.data_mut();
+        Ok(trailer_schedules(data.trailer_mut(trailer_id), |s| s.DoorNumber = door.to_string()))
+    }
+
+    async fn toggle_hot(&self, trailer_id: &str) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let mut data = self.data_mut();
+        Ok(trailer_schedules(data.trailer_mut(trailer_id), |s| s.IsHot = !s.IsHot))
+    }
+
+    async fn set_arrival_time(&self, trailer_id: &str, arrival_time: &str, load_status: &str) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let mut data = self.data_mut();
+        Ok(trailer_schedules(data.trailer_mut(trailer_id), |s| {
+            s.ArrivalTime = arrival_time.to_string();
+            s.LoadStatus = load_status.to_string();
+        }))
+    }
+
+    async fn record_schedule_changes(&self, changes: &[ScheduleChange]) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        for change in changes {
+            if data.trailers.iter().any(|t| t.TrailerID == change.TrailerID) {
+                data.schedule_changes.push(change.clone());
+            }
+        }
+        Ok(())
+    }
+
+    async fn schedule_changes(&self, filter: &ScheduleChangeRequest) -> Result<Vec<ScheduleChange>, RepoError> {
+        let data = self.data();
+        let day = |c: &ScheduleChange| c.at.chars().take(10).collect::<String>();
+
+        let mut changes: Vec<ScheduleChange> = data.schedule_changes.iter()
+            .filter(|c| filter.TrailerID.as_ref().is_none_or(|id| &c.TrailerID == id))
+            .filter(|c| filter.user.as_ref().is_none_or(|user| &c.user == user))
+            .filter(|c| filter.date1.as_ref().is_none_or(|date1| &day(c) >= date1))
+            .filter(|c| filter.date2.as_ref().is_none_or(|date2| &day(c) <= date2))
+            .cloned()
+            .collect();
+        changes.sort_by(|a, b| b.at.cmp(&a.at));
+        Ok(changes)
+    }
+}
+
+/*
+    Shipments
+*/
+
+#[rocket::async_trait]
+impl ShipmentRepository for MemoryStore {
+    async fn count_with_prefix(&self, prefix: &str) -> Result<u32, RepoError> {
+        let data = self.data();
+        Ok(data.shipments.iter().filter(|s| s.Shipment.LoadId.contains(prefix)).count() as u32)
+    }
+
+    async fn for_day(&self, date: &str) -> Result<Vec<Shipment>, RepoError> {
+        let data = self.data();
+        let mut shipments: Vec<Shipment> = data.shipments.iter()
+            .map(|s| &s.Shipment)
+            .filter(|s| s.ScheduleDate == date || (s.ScheduleDate.as_str() < date && s.Status != "COMPLETE"))
+            .cloned()
+            .collect();
+        by_schedule_date_desc(&mut shipments);
+        Ok(shipments)
+    }
+
+    async fn recent(&self, limit: usize) -> Result<Vec<Shipment>, RepoError> {
+        let data = self.data();
+        let mut shipments: Vec<Shipment> = data.shipments.iter().map(|s| s.Shipment.clone()).collect();
+        by_schedule_date_desc(&mut shipments);
+        shipments.truncate(limit);
+        Ok(shipments)
+    }
+
+    async fn state(&self, load_id: &str) -> Result<Option<(String, bool)>, RepoError> {
+        Ok(self.data().shipment(load_id).map(|s| (s.Shipment.Status.clone(), s.Shipment.IsHold)))
+    }
+
+    async fn create(&self, shipment: &Shipment) -> Result<Option<Shipment>, RepoError> {
+        let mut data = self.data_mut();
+        if data.shipment(&shipment.LoadId).is_none() {
+            data.shipments.push(ShipmentRecord {
+                Shipment: Shipment { LoadId: shipment.LoadId.clone(), ..Shipment::default() },
+                Lines: Vec::new(),
+            });
+        }
+
+        let record = match data.shipment_mut(&shipment.LoadId) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let stored = &mut record.Shipment;
+        if !stored.Status.is_empty() && stored.Status != "NOT STARTED" {
+            return Ok(None);
+        }
+
+        stored.ScheduleDate = shipment.ScheduleDate.clone();
+        stored.ScheduleTime = shipment.ScheduleTime.clone();
+        stored.ArrivalTime = String::new();
+        stored.DepartTime = String::new();
+        stored.Dock = shipment.Dock.clone();
+        stored.Door = shipment.Door.clone();
+        stored.Status = "NOT STARTED".to_string();
+        stored.Picker = String::new();
+        stored.PickStartTime = String::new();
+        stored.VerifiedBy = String::new();
+        stored.LoadNum = shipment.LoadNum.clone();
+        stored.TrailerNum = String::new();
+        Ok(Some(stored.clone()))
+    }
+
+    async fn update(&self, load_id: &str, from: Option<&str>, update: &ShipmentUpdate) -> Result<Option<Shipment>, RepoError> {
+        let mut data = self.data_mut();
+        let shipment = match data.shipment_mut(load_id) {
+            Some(record) => &mut record.Shipment,
+            None => return Ok(None),
+        };
+        if let Some(from) = from {
+            if shipment.Status != from || shipment.IsHold {
+                return Ok(None);
+            }
+        }
+
+        update.apply(shipment);
+        Ok(Some(shipment.clone()))
+    }
+
+    async fn set_door(&self, load_id: &str, door: &str) -> Result<Option<Shipment>, RepoError> {
+        let mut data = self.data_mut();
+        if data.shipment(load_id).is_none() {
+            data.shipments.push(ShipmentRecord {
+                Shipment: Shipment { LoadId: load_id.to_string(), ..Shipment::default() },
+                Lines: Vec::new(),
+            });
+        }
+
+        Ok(data.shipment_mut(load_id).map(|record| {
+            record.Shipment.Door = door.to_string();
+            record.Shipment.clone()
+        }))
+    }
+
+    async fn toggle_hold(&self, load_id: &str) -> Result<Option<Shipment>, RepoError> {
+        Ok(self.data_mut().shipment_mut(load_id).map(|record| {
+            record.Shipment.IsHold = !record.Shipment.IsHold;
+            record.Shipment.clone()
+        }))
+    }
+
+    async fn delete(&self, load_id: &str, event: &ShipmentEvent) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        if data.shipment(load_id).is_some() {
+            data.shipment_events.push(event.clone());
+            data.shipments.retain(|s| s.Shipment.LoadId != load_id);
+        }
+        Ok(())
+    }
+
+    async fn lines(&self, load_id: &str) -> Result<Vec<ShipmentLine>, RepoError> {
+        Ok(self.data().shipment(load_id).map(|s| s.Lines.clone()).unwrap_or_default())
+    }
+
+    async fn replace_lines(&self, load_id: &str, lines: &[ShipmentLine]) -> Result<Vec<ShipmentLine>, RepoError> {
+        Ok(self.data_mut().shipment_mut(load_id).map(|record| {
+            record.Lines = lines.to_vec();
+            record.Lines.clone()
+        }).unwrap_or_default())
+    }
+
+    async fn record_event(&self, event: &ShipmentEvent) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        if data.shipment(&event.LoadId).is_some() {
+            data.shipment_events.push(event.clone());
+        }
+        Ok(())
+    }
+
+    async fn events(&self, load_id: &str) -> Result<Vec<ShipmentEvent>, RepoError> {
+        let data = self.data();
+        let mut events: Vec<ShipmentEvent> = data.shipment_events.iter()
+            .filter(|e| e.LoadId == load_id)
+            .cloned()
+            .collect();
+        events.sort_by(|a, b| a.at.cmp(&b.at));
+        Ok(events)
+    }
+}
+
+/*
+    Counts
+*/
+
+#[rocket::async_trait]
+impl CountRepository for MemoryStore {
+    async fn in_range(&self, date1: &str, date2: &str) -> Result<Vec<Count>, RepoError> {
+        let data = self.data();
+        Ok(data.counts.iter()
+            .filter(|c| c.date.as_str() >= date1 && c.date.as_str() <= date2)
+            .cloned()
+            .collect())
+    }
+}
+
+/*
+    Users, roles, refresh tokens, invites and login throttling
+*/
+
+#[rocket::async_trait]
+impl UserRepository for MemoryStore {
+    async fn find_user(&self, username: &str) -> Result<Option<UserRecord>, RepoError> {
+        Ok(self.data().users.iter().find(|u| u.username == username).cloned())
+    }
+
+    async fn list_users(&self) -> Result<Vec<UserRecord>, RepoError> {
+        let mut users = self.data().users.clone();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    async fn create_user(&self, user: &UserRecord, _now: &str) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        if data.users.iter().any(|u| u.username == user.username) {
+            return Err(RepoError::Duplicate);
+        }
+        data.users.push(user.clone());
+        Ok(())
+    }
+
+    async fn redeem_invite(&self, code: &str, username: &str, password: &str, now: DateTime<Utc>) -> Result<bool, RepoError> {
+        let mut data = self.data_mut();
+        if data.users.iter().any(|u| u.username == username) {
+            return Err(RepoError::Duplicate);
+        }
+
+        let timestamp = now.timestamp();
+        let invite = match data.invites.iter_mut().find(|i| i.code == code && i.used_by.is_none() && i.expires > timestamp) {
+            Some(invite) => invite,
+            None => return Ok(false),
+        };
+        invite.used_by = Some(username.to_string());
+        let role = invite.role.clone();
+
+        data.users.push(UserRecord {
+            username: username.to_string(),
+            password: password.to_string(),
+            role,
+            disabled: false,
+            must_reset_password: false,
+        });
+        Ok(true)
+    }
+
+    async fn update_user(&self, username: &str, update: &UserUpdate) -> Result<(), RepoError> {
+        if let Some(user) = self.data_mut().user_mut(username) {
+            if let Some(role) = &update.role {
+                user.role = role.clone();
+            }
+            if let Some(disabled) = update.disabled {
+                user.disabled = disabled;
+            }
+            if let Some(password) = &update.password {
+                user.password = password.clone();
+            }
+            if let Some(must_reset_password) = update.must_reset_password {
+                user.must_reset_password = must_reset_password;
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        data.users.retain(|u| u.username != username);
+        data.refresh_tokens.retain(|t| t.username != username);
+        Ok(())
+    }
+
+    async fn bootstrap_roles(&self, permissions: &[&str], roles: &[(&str, &[&str])]) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        data.permissions.extend(permissions.iter().map(|p| p.to_string()));
+
+        for (role, grants) in roles {
+            if !data.roles.contains_key(*role) {
+                let record = RoleRecord {
+                    builtin: true,
+                    permissions: grants.iter().map(|p| p.to_string()).collect(),
+                };
+                data.roles.insert(role.to_string(), record);
+            }
+        }
+        Ok(())
+    }
+
+    async fn role_permissions(&self, role: &str) -> Result<Vec<String>, RepoError> {
+        Ok(self.data().role_response(role).map(|r| r.permissions).unwrap_or_default())
+    }
+
+    async fn find_role(&self, name: &str) -> Result<Option<RoleResponse>, RepoError> {
+        Ok(self.data().role_response(name))
+    }
+
+    async fn list_roles(&self) -> Result<Vec<RoleResponse>, RepoError> {
+        let data = self.data();
+        Ok(data.roles.keys().filter_map(|name| data.role_response(name)).collect())
+    }
+
+    async fn set_role_permissions(&self, name: &str, permissions: &[String]) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        let known: BTreeSet<String> = permissions.iter().filter(|p| data.permissions.contains(*p)).cloned().collect();
+        data.roles.entry(name.to_string()).or_default().permissions = known;
+        Ok(())
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<i64, RepoError> {
+        let mut data = self.data_mut();
+        let users = data.users.iter().filter(|u| u.role == name).count() as i64;
+        if users == 0 {
+            data.roles.remove(name);
+        }
+        Ok(users)
+    }
+
+    async fn store_refresh_token(&self, token: &RefreshTokenRecord) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        if data.users.iter().any(|u| u.username == token.username) {
+            data.refresh_tokens.push(token.clone());
+        }
+        Ok(())
+    }
+
+    async fn consume_refresh_token(&self, jti: &str, _now: &str) -> Result<Option<ConsumedToken>, RepoError> {
+        let mut data = self.data_mut();
+        let token = match data.refresh_tokens.iter_mut().find(|t| t.jti == jti) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let was_revoked = token.revoked;
+        token.revoked = true;
+        let (family, username) = (token.family.clone(), token.username.clone());
+
+        let user = data.users.iter().find(|u| u.username == username);
+        Ok(Some(ConsumedToken {
+            family,
+            was_revoked,
+            role: user.map(|u| u.role.clone()),
+            blocked: user.is_some_and(|u| u.disabled || u.must_reset_password),
+        }))
+    }
+
+    async fn refresh_token_family(&self, jti: &str) -> Result<Option<String>, RepoError> {
+        Ok(self.data().refresh_tokens.iter().find(|t| t.jti == jti).map(|t| t.family.clone()))
+    }
+
+    async fn revoke_token_family(&self, family: &str) -> Result<(), RepoError> {
+        for token in self.data_mut().refresh_tokens.iter_mut().filter(|t| t.family == family) {
+            token.revoked = true;
+        }
+        Ok(())
+    }
+
+    async fn revoke_user_tokens(&self, username: &str) -> Result<(), RepoError> {
+        for token in self.data_mut().refresh_tokens.iter_mut().filter(|t| t.username == username) {
+            token.revoked = true;
+        }
+        Ok(())
+    }
+
+    async fn create_invite(&self, invite: &Invite, now: &str) -> Result<(), RepoError> {
+        self.data_mut().invites.push(InviteRecord {
+            code: invite.code.clone(),
+            role: invite.role.clone(),
+            created_by: invite.created_by.clone(),
+            created_at: now.to_string(),
+            expires: invite.expires,
+            used_by: None,
+        });
+        Ok(())
+    }
+
+    async fn list_invites(&self, now: i64) -> Result<Vec<Invite>, RepoError> {
+        let data = self.data();
+        let mut invites: Vec<Invite> = data.invites.iter()
+            .filter(|i| i.used_by.is_none() && i.expires > now)
+            .map(|i| Invite {
+                code: i.code.clone(),
+                role: i.role.clone(),
+                created_by: i.created_by.clone(),
+                expires: i.expires,
+            })
+            .collect();
+        invites.sort_by_key(|i| i.expires);
+        Ok(invites)
+    }
+
+    async fn delete_invite(&self, code: &str) -> Result<(), RepoError> {
+        self.data_mut().invites.retain(|i| i.code != code || i.used_by.is_some());
+        Ok(())
+    }
+
+    async fn locked_until(&self, keys: &[String], now: i64) -> Result<Option<i64>, RepoError> {
+        let data = self.data();
+        Ok(keys.iter()
+            .filter_map(|key| data.login_throttles.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .max())
+    }
+
+    async fn record_login_failure(&self, key: &str, policy: &ThrottlePolicy, now: i64) -> Result<(), RepoError> {
+        let mut data = self.data_mut();
+        let throttle = data.login_throttles.entry(key.to_string()).or_default();
+
+        let last_activity = throttle.locked_until.unwrap_or(0).max(throttle.last_failure_at);
+        throttle.failures = if last_activity < now - policy.lockout_max_secs { 1 } else { throttle.failures + 1 };
+        throttle.last_failure_at = now;
+
+        let over = throttle.failures as i64 - policy.max_attempts as i64;
+        throttle.locked_until = if over < 0 {
+            None
+        } else {
+            let lockout = policy.lockout_secs as f64 * 2f64.powi(over.min(62) as i32);
+            Some(now + if lockout > policy.lockout_max_secs as f64 { policy.lockout_max_secs } else { lockout as i64 })
+        };
+        Ok(())
+    }
+
+    async fn clear_login_failures(&self, key: &str) -> Result<(), RepoError> {
+        self.data_mut().login_throttles.remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::structs::*;
+
+mod memory;
+mod neo4j;
+
+pub use memory::{InviteRecord, MemoryData, MemoryStore, RoleRecord, ShipmentRecord, SidRecord, ThrottleRecord, TrailerRecord};
+pub use neo4j::Neo4jStore;
+pub use neo4j::migrations::{AppliedMigration, Check, Migration, MigrationError, MIGRATIONS};
+
+/*
+    Repositories
+
+    Handlers reach storage only through these traits, held as trait objects in
+    AppState. Neo4jStore runs the Cypher against the graph, MemoryStore keeps the
+    same data in plain collections so the API can run without a database.
+
+    Both implement every trait, and both must behave the same way, including the
+    guarded writes: a write that matches nothing returns None or an empty Vec
+    rather than an error, and the handler decides what that means.
+*/
+
+#[derive(Debug)]
+pub enum RepoError {
+    Neo4j(neo4rs::Error),
+    Mapping(neo4rs::DeError),
+    /// A unique key, e.g. a username, is already taken.
+    Duplicate,
+}
+
+impl From<neo4rs::Error> for RepoError {
+    fn from(e: neo4rs::Error) -> Self {
+        if e.to_string().contains("ConstraintValidationFailed") {
+            RepoError::Duplicate
+        } else {
+            RepoError::Neo4j(e)
+        }
+    }
+}
+
+impl From<neo4rs::DeError> for RepoError {
+    fn from(e: neo4rs::DeError) -> Self {
+        RepoError::Mapping(e)
+    }
+}
+
+impl std::fmt::Display for RepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoError::Neo4j(e) => write!(f, "{}", e),
+            RepoError::Mapping(e) => write!(f, "unexpected value in Neo4j result: {:?}", e),
+            RepoError::Duplicate => f.write_str("duplicate key"),
+        }
+    }
+}
+
+/*
+    Records
+*/
+
+/// A stored user. `password` is the bcrypt hash.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserRecord {
+    pub username: String,
+    pub password: String,
+    pub role: String,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default)]
+    pub must_reset_password: bool,
+}
+
+impl UserRecord {
+    pub fn details(&self) -> UserDetails {
+        UserDetails {
+            username: self.username.clone(),
+            role: self.role.clone(),
+            disabled: self.disabled,
+            must_reset_password: self.must_reset_password,
+        }
+    }
+}
+
+/// Fields to change on a user, `None` leaves the field as it is.
+#[derive(Default, Debug)]
+pub struct UserUpdate {
+    pub role: Option<String>,
+    pub disabled: Option<bool>,
+    pub password: Option<String>,
+    pub must_reset_password: Option<bool>,
+    pub password_changed_at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RefreshTokenRecord {
+    pub jti: String,
+    pub family: String,
+    pub username: String,
+    pub expires: i64,
+    pub created_at: String,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+/// What consuming a refresh token found.
+pub struct ConsumedToken {
+    pub family: String,
+    /// The token had already been used or revoked before this call.
+    pub was_revoked: bool,
+    /// The owner's current role, `None` if the user no longer exists.
+    pub role: Option<String>,
+    /// The owner is disabled or has to reset their password.
+    pub blocked: bool,
+}
+
+/// Lockout settings for one throttle key, see the throttle module.
+pub struct ThrottlePolicy {
+    pub max_attempts: u32,
+    pub lockout_secs: i64,
+    pub lockout_max_secs: i64,
+}
+
+/// Fields to change on a shipment, `None` leaves the field as it is.
+#[allow(non_snake_case)]
+#[derive(Default, Debug)]
+pub struct ShipmentUpdate {
+    pub Status: Option<String>,
+    pub ArrivalTime: Option<String>,
+    pub DepartTime: Option<String>,
+    pub TrailerNum: Option<String>,
+    pub Seal: Option<String>,
+    pub Picker: Option<String>,
+    pub PickStartTime: Option<String>,
+    pub PickFinishTime: Option<String>,
+    pub VerifiedBy: Option<String>,
+}
+
+impl ShipmentUpdate {
+    /// The fields being set, by property name.
+    pub fn changes(&self) -> HashMap<String, String> {
+        [
+            ("Status", &self.Status),
+            ("ArrivalTime", &self.ArrivalTime),
+            ("DepartTime", &self.DepartTime),
+            ("TrailerNum", &self.TrailerNum),
+            ("Seal", &self.Seal),
+            ("Picker", &self.Picker),
+            ("PickStartTime", &self.PickStartTime),
+            ("PickFinishTime", &self.PickFinishTime),
+            ("VerifiedBy", &self.VerifiedBy),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| value.as_ref().map(|v| (field.to_string(), v.clone())))
+        .collect()
+    }
+
+    pub fn apply(&self, shipment: &mut Shipment) {
+        let fields = [
+            (&self.Status, &mut shipment.Status),
+            (&self.ArrivalTime, &mut shipment.ArrivalTime),
+            (&self.DepartTime, &mut shipment.DepartTime),
+            (&self.TrailerNum, &mut shipment.TrailerNum),
+            (&self.Seal, &mut shipment.Seal),
+            (&self.Picker, &mut shipment.Picker),
+            (&self.PickStartTime, &mut shipment.PickStartTime),
+            (&self.PickFinishTime, &mut shipment.PickFinishTime),
+            (&self.VerifiedBy, &mut shipment.VerifiedBy),
+        ];
+        for (value, field) in fields {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+    }
+}
+
+/*
+    Traits
+*/
+
+#[rocket::async_trait]
+pub trait TrailerRepository: Send + Sync {
+    /// Every scheduled trailer that has at least one Cisco id.
+    async fn all(&self) -> Result<Vec<Trailer>, RepoError>;
+    /// Like `all`, for schedule dates between `date1` and `date2` inclusive.
+    async fn in_date_range(&self, date1: &str, date2: &str) -> Result<Vec<Trailer>, RepoError>;
+    async fn sid_parts(&self, trailer_id: &str) -> Result<Vec<SidParts>, RepoError>;
+    /// The SIDs and parts of every trailer scheduled on `date`.
+    async fn sids_for_date(&self, date: &str) -> Result<Vec<Sids>, RepoError>;
+    async fn schedule(&self, trailer_id: &str) -> Result<Option<Schedule>, RepoError>;
+
+    async fn set_schedule(&self, request: &SetScheduleRequest) -> Result<Vec<TrailerSchedule>, RepoError>;
+    async fn set_door(&self, trailer_id: &str, door: &str) -> Result<Vec<TrailerSchedule>, RepoError>;
+    async fn toggle_hot(&self, trailer_id: &str) -> Result<Vec<TrailerSchedule>, RepoError>;
+    async fn set_arrival_time(&self, trailer_id: &str, arrival_time: &str, load_status: &str) -> Result<Vec<TrailerSchedule>, RepoError>;
+
+    async fn record_schedule_changes(&self, changes: &[ScheduleChange]) -> Result<(), RepoError>;
+    /// Newest first. Every filter is optional, the dates bound the day of the change.
+    async fn schedule_changes(&self, filter: &ScheduleChangeRequest) -> Result<Vec<ScheduleChange>, RepoError>;
+}
+
+#[rocket::async_trait]
+pub trait ShipmentRepository: Send + Sync {
+    /// Number of shipments whose LoadId contains `prefix`.
+    async fn count_with_prefix(&self, prefix: &str) -> Result<u32, RepoError>;
+    /// Shipments scheduled on `date`, plus earlier ones that are not complete.
+    async fn for_day(&self, date: &str) -> Result<Vec<Shipment>, RepoError>;
+    /// The `limit` latest shipments by schedule date.
+    async fn recent(&self, limit: usize) -> Result<Vec<Shipment>, RepoError>;
+    /// Current status and hold flag.
+    async fn state(&self, load_id: &str) -> Result<Option<(String, bool)>, RepoError>;
+
+    /// Creates the shipment, or resets it if it has not started yet.
+    /// `None` if it exists and has moved past NOT STARTED.
+    async fn create(&self, shipment: &Shipment) -> Result<Option<Shipment>, RepoError>;
+    /// Applies `update`. With `from`, only if the status is still `from` and the
+    /// shipment is not on hold, so a concurrent change makes this return `None`.
+    async fn update(&self, load_id: &str, from: Option<&str>, update: &ShipmentUpdate) -> Result<Option<Shipment>, RepoError>;
+    /// Sets the door, creating the shipment if it does not exist.
+    async fn set_door(&self, load_id: &str, door: &str) -> Result<Option<Shipment>, RepoError>;
+    async fn toggle_hold(&self, load_id: &str) -> Result<Option<Shipment>, RepoError>;
+    /// Deletes the shipment and appends `event` to its history in the same write,
+    /// so the history never shows a delete that didn't happen. Does nothing if the
+    /// shipment does not exist.
+    async fn delete(&self, load_id: &str, event: &ShipmentEvent) -> Result<(), RepoError>;
+
+    async fn lines(&self, load_id: &str) -> Result<Vec<ShipmentLine>, RepoError>;
+    /// Replaces every line of the shipment, returning the ones created.
+    async fn replace_lines(&self, load_id: &str, lines: &[ShipmentLine]) -> Result<Vec<ShipmentLine>, RepoError>;
+
+    /// Appends to the shipment's history. Does nothing if the shipment does not exist.
+    async fn record_event(&self, event: &ShipmentEvent) -> Result<(), RepoError>;
+    /// Oldest first, including the history of deleted shipments.
+    async fn events(&self, load_id: &str) -> Result<Vec<ShipmentEvent>, RepoError>;
+}
+
+#[rocket::async_trait]
+pub trait CountRepository: Send + Sync {
+    /// Counts dated between `date1` and `date2` inclusive.
+    async fn in_range(&self, date1: &str, date2: &str) -> Result<Vec<Count>, RepoError>;
+}
+
+#[rocket::async_trait]
+pub trait UserRepository: Send + Sync {
+    /* Users */
+
+    async fn find_user(&self, username: &str) -> Result<Option<UserRecord>, RepoError>;
+    /// Sorted by username.
+    async fn list_users(&self) -> Result<Vec<UserRecord>, RepoError>;
+    /// `RepoError::Duplicate` if the username is taken.
+    async fn create_user(&self, user: &UserRecord, now: &str) -> Result<(), RepoError>;
+    /// Creates the user with the invite's role and marks the invite used, in one step.
+    /// `false` if the code is unknown, used or expired.
+    async fn redeem_invite(&self, code: &str, username: &str, password: &str, now: DateTime<Utc>) -> Result<bool, RepoError>;
+    async fn update_user(&self, username: &str, update: &UserUpdate) -> Result<(), RepoError>;
+    /// Deletes the user and their refresh tokens.
+    async fn delete_user(&self, username: &str) -> Result<(), RepoError>;
+
+    /* Roles */
+
+    /// Creates the permissions, and each role with its grants unless it already exists.
+    async fn bootstrap_roles(&self, permissions: &[&str], roles: &[(&str, &[&str])]) -> Result<(), RepoError>;
+    async fn role_permissions(&self, role: &str) -> Result<Vec<String>, RepoError>;
+    async fn find_role(&self, name: &str) -> Result<Option<RoleResponse>, RepoError>;
+    /// Sorted by name.
+    async fn list_roles(&self) -> Result<Vec<RoleResponse>, RepoError>;
+    /// Creates the role if needed and replaces its grants with exactly `permissions`.
+    async fn set_role_permissions(&self, name: &str, permissions: &[String]) -> Result<(), RepoError>;
+    /// Deletes the role unless users still have it. Returns how many do.
+    async fn delete_role(&self, name: &str) -> Result<i64, RepoError>;
+
+    /* Refresh tokens */
+
+    async fn store_refresh_token(&self, token: &RefreshTokenRecord) -> Result<(), RepoError>;
+    /// Marks the token used and reports its previous state, in one step so two
+    /// concurrent refreshes cannot both see it unused.
+    async fn consume_refresh_token(&self, jti: &str, now: &str) -> Result<Option<ConsumedToken>, RepoError>;
+    async fn refresh_token_family(&self, jti: &str) -> Result<Option<String>, RepoError>;
+    async fn revoke_token_family(&self, family: &str) -> Result<(), RepoError>;
+    async fn revoke_user_tokens(&self, username: &str) -> Result<(), RepoError>;
+
+    /* Invites */
+
+    async fn create_invite(&self, invite: &Invite, now: &str) -> Result<(), RepoError>;
+    /// Unused invites that expire after `now`, soonest first.
+    async fn list_invites(&self, now: i64) -> Result<Vec<Invite>, RepoError>;
+    /// Deletes the invite if it has not been used.
+    async fn delete_invite(&self, code: &str) -> Result<(), RepoError>;
+
+    /* Login throttling */
+
+    /// Unix timestamp the latest of `keys` is locked until, if any of them is locked at `now`.
+    async fn locked_until(&self, keys: &[String], now: i64) -> Result<Option<i64>, RepoError>;
+    async fn record_login_failure(&self, key: &str, policy: &ThrottlePolicy, now: i64) -> Result<(), RepoError>;
+    async fn clear_login_failures(&self, key: &str) -> Result<(), RepoError>;
+}
+
+/// A backend that provides every repository.
+pub trait Store: TrailerRepository + ShipmentRepository + CountRepository + UserRepository {}
+
+impl<T: TrailerRepository + ShipmentRepository + CountRepository + UserRepository> Store for T {}
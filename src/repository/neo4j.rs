@@ -0,0 +1,833 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use neo4rs::{query, BoltType, Graph, Query, Row};
+use crate::mapping::{node_column, FromRow};
+use super::*;
+
+pub mod migrations;
+
+pub struct Neo4jStore {
+    graph: Graph,
+}
+
+impl Neo4jStore {
+    pub async fn connect(uri: &str, user: &str, password: &str) -> Result<Self, neo4rs::Error> {
+        Ok(Neo4jStore { graph: Graph::new(uri, user, password).await? })
+    }
+
+    async fn rows<T, F>(&self, query: Query, map: F) -> Result<Vec<T>, RepoError>
+    where
+        F: Fn(&Row) -> Result<T, neo4rs::DeError>,
+    {
+        let mut result = self.graph.execute(query).await?;
+        let mut data = Vec::new();
+        while let Some(record) = result.next().await? {
+            data.push(map(&record)?);
+        }
+        Ok(data)
+    }
+
+    async fn first<T, F>(&self, query: Query, map: F) -> Result<Option<T>, RepoError>
+    where
+        F: Fn(&Row) -> Result<T, neo4rs::DeError>,
+    {
+        match self.graph.execute(query).await?.next().await? {
+            Some(record) => Ok(Some(map(&record)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/*
+    Trailers
+*/
+
+#[rocket::async_trait]
+impl TrailerRepository for Neo4jStore {
+    async fn all(&self) -> Result<Vec<Trailer>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WITH trailer, s
+            MATCH (trailer)-[:HAS_CISCO]->(cisco:Cisco)
+            RETURN trailer.id AS TrailerID, s, COLLECT(cisco.id) AS CiscoIDs
+        ");
+
+        self.rows(query, Trailer::from_row).await
+    }
+
+    async fn in_date_range(&self, date1: &str, date2: &str) -> Result<Vec<Trailer>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WHERE s.ScheduleDate >= $date1 and s.ScheduleDate <= $date2
+            WITH trailer, s
+            MATCH (trailer)-[:HAS_CISCO]->(cisco:Cisco)
+            RETURN trailer.id AS TrailerID, s, COLLECT(cisco.id) AS CiscoIDs
+        ")
+        .param("date1", date1.to_string())
+        .param("date2", date2.to_string());
+
+        self.rows(query, Trailer::from_row).await
+    }
+
+    async fn sid_parts(&self, trailer_id: &str) -> Result<Vec<SidParts>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer {id: $param})-[:HAS_SID]->(sid:SID)-[:HAS_PART]->(part:Part)
+            RETURN sid, COLLECT({partNumber: part.number, quantity: part.quantity}) AS parts
+        ").param("param", trailer_id.to_string());
+
+        self.rows(query, SidParts::from_row).await
+    }
+
+    async fn sids_for_date(&self, date: &str) -> Result<Vec<Sids>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule {ScheduleDate: $date})
+            MATCH (trailer)-[:HAS_SID]->(sid:SID)-[:HAS_PART]->(part:Part)
+            RETURN trailer.id AS TrailerID, sid.id AS sid, sid.ciscoID AS cisco, part.number AS partNumber, part.quantity AS quantity
+        ").param("date", date.to_string());
+
+        let rows = self.rows(query, |record| {
+            let trailer_id: String = record.get("TrailerID")?;
+            let part = SidAndParts {
+                Sid: record.get("sid")?,
+                Cisco: record.get("cisco")?,
+                Part: record.get("partNumber")?,
+                Quantity: record.get("quantity")?,
+            };
+            Ok((trailer_id, part))
+        }).await?;
+
+        let mut trailers_map: HashMap<String, Vec<SidAndParts>> = HashMap::new();
+        for (trailer_id, part) in rows {
+            trailers_map.entry(trailer_id).or_default().push(part);
+        }
+
+        Ok(trailers_map.into_iter().map(|(trailer_id, parts)| Sids {
+            TrailerID: trailer_id,
+            Sids: parts,
+        }).collect())
+    }
+
+    async fn schedule(&self, trailer_id: &str) -> Result<Option<Schedule>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WHERE trailer.id = $TrailerID
+            RETURN s
+        ").param("TrailerID", trailer_id.to_string());
+
+        self.first(query, |record| node_column(record, "s")).await
+    }
+
+    async fn set_schedule(&self, request: &SetScheduleRequest) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WHERE trailer.id = $TrailerID
+            SET s.ScheduleDate = $ScheduleDate,
+                s.RequestDate = $RequestDate,
+                s.CarrierCode = $CarrierCode,
+                s.ScheduleTime = $ScheduleTime,
+                s.LastFreeDate = $LastFreeDate,
+                s.ContactEmail = $ContactEmail,
+                s.DoorNumber = $Door,
+                s.Seal = $Seal
+            RETURN trailer.id as TrailerID, s
+        ")
+        .param("TrailerID", request.TrailerID.clone())
+        .param("ScheduleDate", request.ScheduleDate.clone())
+        .param("RequestDate", request.RequestDate.clone())
+        .param("CarrierCode", request.CarrierCode.clone())
+        .param("ScheduleTime", request.ScheduleTime.clone())
+        .param("LastFreeDate", request.LastFreeDate.clone())
+        .param("ContactEmail", request.ContactEmail.clone())
+        .param("Seal", request.Seal.clone())
+        .param("Door", request.Door.clone());
+
+        self.rows(query, TrailerSchedule::from_row).await
+    }
+
+    async fn set_door(&self, trailer_id: &str, door: &str) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WHERE trailer.id = $TrailerID
+            SET s.DoorNumber = $Door
+            RETURN trailer.id as TrailerID, s
+        ")
+        .param("TrailerID", trailer_id.to_string())
+        .param("Door", door.to_string());
+
+        self.rows(query, TrailerSchedule::from_row).await
+    }
+
+    async fn toggle_hot(&self, trailer_id: &str) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WHERE trailer.id = $TrailerID
+            SET s.IsHot = NOT s.IsHot
+            RETURN trailer.id as TrailerID, s
+        ").param("TrailerID", trailer_id.to_string());
+
+        self.rows(query, TrailerSchedule::from_row).await
+    }
+
+    async fn set_arrival_time(&self, trailer_id: &str, arrival_time: &str, load_status: &str) -> Result<Vec<TrailerSchedule>, RepoError> {
+        let query = query("
+            MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
+            WHERE trailer.id = $TrailerID
+            SET s.ArrivalTime = $ArrivalTime
+            SET s.LoadStatus = $load_status
+            REMOVE s.LoadStatue
+            RETURN trailer.id as TrailerID, s
+        ")
+        .param("TrailerID", trailer_id.to_string())
+        .param("ArrivalTime", arrival_time.to_string())
+        .param("load_status", load_status.to_string());
+
+        self.rows(query, TrailerSchedule::from_row).await
+    }
+
+    async fn record_schedule_changes(&self, changes: &[ScheduleChange]) -> Result<(), RepoError> {
+        let changes: Vec<HashMap<String, String>> = changes.iter().map(|change| HashMap::from([
+            ("TrailerID".to_string(), change.TrailerID.clone()),
+            ("event".to_string(), change.event.clone()),
+            ("field".to_string(), change.field.clone()),
+            ("old".to_string(), change.old.clone()),
+            ("new".to_string(), change.new.clone()),
+            ("user".to_string(), change.user.clone()),
+            ("at".to_string(), change.at.clone()),
+        ])).collect();
+
+        let query = query("
+            UNWIND $changes AS change
+            MATCH (trailer:Trailer {id: change.TrailerID})
+            CREATE (trailer)-[:HAS_SCHEDULE_CHANGE]->(:ScheduleChange {
+                TrailerID: change.TrailerID,
+                event: change.event,
+                field: change.field,
+                old: change.old,
+                new: change.new,
+                user: change.user,
+                at: change.at
+            })
+        ").param("changes", changes);
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn schedule_changes(&self, filter: &ScheduleChangeRequest) -> Result<Vec<ScheduleChange>, RepoError> {
+        let query = query("
+            MATCH (c:ScheduleChange)
+            WHERE ($TrailerID IS NULL OR c.TrailerID = $TrailerID)
+              AND ($user IS NULL OR c.user = $user)
+              AND ($date1 IS NULL OR substring(c.at, 0, 10) >= $date1)
+              AND ($date2 IS NULL OR substring(c.at, 0, 10) <= $date2)
+            RETURN c
+            ORDER BY c.at DESC
+        ")
+        .param("TrailerID", filter.TrailerID.clone())
+        .param("user", filter.user.clone())
+        .param("date1", filter.date1.clone())
+        .param("date2", filter.date2.clone());
+
+        self.rows(query, |record| node_column(record, "c")).await
+    }
+}
+
+/*
+    Shipments
+*/
+
+#[rocket::async_trait]
+impl ShipmentRepository for Neo4jStore {
+    async fn count_with_prefix(&self, prefix: &str) -> Result<u32, RepoError> {
+        let query = query("
+            MATCH (s:Shipment)
+            WHERE s.LoadId CONTAINS $prefix
+            return COUNT(s.LoadId) as LoadCount
+        ").param("prefix", prefix.to_string());
+
+        Ok(self.first(query, |record| record.get("LoadCount")).await?.unwrap_or(0))
+    }
+
+    async fn for_day(&self, date: &str) -> Result<Vec<Shipment>, RepoError> {
+        let query = query("
+            MATCH (s:Shipment)
+            WHERE s.ScheduleDate = $date OR (s.ScheduleDate < $date AND s.Status <> 'COMPLETE')
+            RETURN s
+            ORDER BY s.ScheduleDate DESC
+        ").param("date", date.to_string());
+
+        self.rows(query, |record| node_column(record, "s")).await
+    }
+
+    async fn recent(&self, limit: usize) -> Result<Vec<Shipment>, RepoError> {
+        let query = query("
+            MATCH (s:Shipment)
+            RETURN s
+            ORDER BY s.ScheduleDate DESC
+            LIMIT $limit
+        ").param("limit", limit as i64);
+
+        self.rows(query, |record| node_column(record, "s")).await
+    }
+
+    async fn state(&self, load_id: &str) -> Result<Option<(String, bool)>, RepoError> {
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})
+            RETURN coalesce(s.Status, '') AS Status, coalesce(s.IsHold, false) AS IsHold
+        ").param("LoadId", load_id.to_string());
+
+        self.first(query, |record| Ok((
+            record.get("Status").unwrap_or("".to_string()),
+            record.get("IsHold").unwrap_or(false),
+        ))).await
+    }
+
+    async fn create(&self, shipment: &Shipment) -> Result<Option<Shipment>, RepoError> {
+        let query = query("
+            MERGE (s:Shipment {LoadId: $LoadId})
+            WITH s
+            // '' is how shipments from before Status was always set read back.
+            WHERE s.Status IS NULL OR s.Status IN ['', 'NOT STARTED']
+            SET s.ScheduleDate = $ScheduleDate,
+                s.ScheduleTime = $ScheduleTime,
+                s.ArrivalTime = '',
+                s.DepartTime = '',
+                s.Dock = $Dock,
+                s.Door = $Door,
+                s.Status = 'NOT STARTED',
+                s.LoadId = $LoadId,
+                s.Picker = '',
+                s.PickStartTime = '',
+                s.VerifiedBy = '',
+                s.LoadNum = $LoadNum,
+                s.TrailerNum = ''
+            RETURN s
+        ")
+        .param("ScheduleDate", shipment.ScheduleDate.clone())
+        .param("ScheduleTime", shipment.ScheduleTime.clone())
+        .param("Dock", shipment.Dock.clone())
+        .param("LoadId", shipment.LoadId.clone())
+        .param("LoadNum", shipment.LoadNum.clone())
+        .param("Door", shipment.Door.clone());
+
+        self.first(query, |record| node_column(record, "s")).await
+    }
+
+    async fn update(&self, load_id: &str, from: Option<&str>, update: &ShipmentUpdate) -> Result<Option<Shipment>, RepoError> {
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})
+            WHERE $from IS NULL OR (coalesce(s.Status, '') = $from AND NOT coalesce(s.IsHold, false))
+            SET s += $changes
+            RETURN s
+        ")
+        .param("LoadId", load_id.to_string())
+        .param("from", from.map(str::to_string))
+        .param("changes", update.changes());
+
+        self.first(query, |record| node_column(record, "s")).await
+    }
+
+    async fn set_door(&self, load_id: &str, door: &str) -> Result<Option<Shipment>, RepoError> {
+        let query = query("
+            MERGE (s:Shipment {LoadId: $LoadId})
+            SET s.Door = $Door
+            RETURN s
+        ")
+        .param("LoadId", load_id.to_string())
+        .param("Door", door.to_string());
+
+        self.first(query, |record| node_column(record, "s")).await
+    }
+
+    async fn toggle_hold(&self, load_id: &str) -> Result<Option<Shipment>, RepoError> {
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})
+            SET s.IsHold = NOT s.IsHold
+            RETURN s
+        ").param("LoadId", load_id.to_string());
+
+        self.first(query, |record| node_column(record, "s")).await
+    }
+
+    async fn delete(&self, load_id: &str, event: &ShipmentEvent) -> Result<(), RepoError> {
+        // The event is left unattached: HAS_EVENT would go with the shipment.
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})
+            CREATE (:ShipmentEvent {
+                LoadId: $LoadId,
+                event: $event,
+                from: $from,
+                to: $to,
+                actor: $actor,
+                at: $at,
+                payload: $payload
+            })
+            DETACH DELETE s
+        ")
+        .param("LoadId", load_id.to_string())
+        .param("event", event.event.clone())
+        .param("from", event.from.clone())
+        .param("to", event.to.clone())
+        .param("actor", event.actor.clone())
+        .param("at", event.at.clone())
+        .param("payload", event.payload.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn lines(&self, load_id: &str) -> Result<Vec<ShipmentLine>, RepoError> {
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})-[:HAS_LINE]->(sl:ShipmentLine)
+            RETURN sl
+        ").param("LoadId", load_id.to_string());
+
+        self.rows(query, |record| node_column(record, "sl")).await
+    }
+
+    async fn replace_lines(&self, load_id: &str, lines: &[ShipmentLine]) -> Result<Vec<ShipmentLine>, RepoError> {
+        let lines: Vec<HashMap<String, BoltType>> = lines.iter().map(|line| HashMap::from([
+            ("item".to_string(), BoltType::from(line.item.clone())),
+            ("quantity".to_string(), BoltType::from(line.quantity as i64)),
+            ("ip".to_string(), BoltType::from(line.ip.clone())),
+        ])).collect();
+
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})
+            OPTIONAL MATCH (s)-[:HAS_LINE]->(old:ShipmentLine)
+            DETACH DELETE old
+            WITH DISTINCT s
+            UNWIND $lines AS line
+            CREATE (s)-[:HAS_LINE]->(sl:ShipmentLine {
+                PartNumber: line.item,
+                Quantity: line.quantity,
+                Ip: line.ip
+            })
+            RETURN sl
+        ")
+        .param("LoadId", load_id.to_string())
+        .param("lines", lines);
+
+        self.rows(query, |record| node_column(record, "sl")).await
+    }
+
+    async fn record_event(&self, event: &ShipmentEvent) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (s:Shipment {LoadId: $LoadId})
+            CREATE (s)-[:HAS_EVENT]->(:ShipmentEvent {
+                LoadId: $LoadId,
+                event: $event,
+                from: $from,
+                to: $to,
+                actor: $actor,
+                at: $at,
+                payload: $payload
+            })
+        ")
+        .param("LoadId", event.LoadId.clone())
+        .param("event", event.event.clone())
+        .param("from", event.from.clone())
+        .param("to", event.to.clone())
+        .param("actor", event.actor.clone())
+        .param("at", event.at.clone())
+        .param("payload", event.payload.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn events(&self, load_id: &str) -> Result<Vec<ShipmentEvent>, RepoError> {
+        // Matched on LoadId rather than HAS_EVENT so deleted shipments keep their history.
+        let query = query("
+            MATCH (e:ShipmentEvent {LoadId: $LoadId})
+            RETURN e
+            ORDER BY e.at ASC
+        ").param("LoadId", load_id.to_string());
+
+        self.rows(query, |record| node_column(record, "e")).await
+    }
+}
+
+/*
+    Counts
+*/
+
+#[rocket::async_trait]
+impl CountRepository for Neo4jStore {
+    async fn in_range(&self, date1: &str, date2: &str) -> Result<Vec<Count>, RepoError> {
+        let query = query("
+            MATCH (c:Count)
+            WHERE c.Date >= $date1 and c.Date <= $date2
+            RETURN c
+        ")
+        .param("date1", date1.to_string())
+        .param("date2", date2.to_string());
+
+        self.rows(query, |record| node_column(record, "c")).await
+    }
+}
+
+/*
+    Users, roles, refresh tokens, invites and login throttling
+*/
+
+const USER_FIELDS: &str = "
+    u.name AS username,
+    coalesce(u.password, '') AS password,
+    u.role AS role,
+    coalesce(u.disabled, false) AS disabled,
+    coalesce(u.must_reset_password, false) AS must_reset_password
+";
+
+fn user_record(record: &Row) -> Result<UserRecord, neo4rs::DeError> {
+    Ok(UserRecord {
+        username: record.get("username")?,
+        password: record.get("password")?,
+        role: record.get("role").unwrap_or("".to_string()),
+        disabled: record.get("disabled")?,
+        must_reset_password: record.get("must_reset_password")?,
+    })
+}
+
+fn role_response(record: &Row) -> Result<RoleResponse, neo4rs::DeError> {
+    Ok(RoleResponse {
+        name: record.get("name")?,
+        builtin: record.get("builtin")?,
+        permissions: record.get("permissions")?,
+    })
+}
+
+const ROLE_FIELDS: &str = "
+    OPTIONAL MATCH (r)-[:GRANTS]->(p:Permission)
+    WITH r, p ORDER BY p.name
+    RETURN r.name AS name, coalesce(r.builtin, false) AS builtin, collect(p.name) AS permissions
+";
+
+#[rocket::async_trait]
+impl UserRepository for Neo4jStore {
+    async fn find_user(&self, username: &str) -> Result<Option<UserRecord>, RepoError> {
+        let query = query(&format!("MATCH (u:User {{name: $username}}) RETURN {}", USER_FIELDS))
+            .param("username", username.to_string());
+
+        self.first(query, user_record).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<UserRecord>, RepoError> {
+        let query = query(&format!("MATCH (u:User) RETURN {} ORDER BY username", USER_FIELDS));
+
+        self.rows(query, user_record).await
+    }
+
+    async fn create_user(&self, user: &UserRecord, now: &str) -> Result<(), RepoError> {
+        let query = query("
+            CREATE (u:User {
+                name: $username,
+                password: $password,
+                role: $role,
+                disabled: $disabled,
+                must_reset_password: $must_reset_password,
+                created_at: $now
+            })
+        ")
+        .param("username", user.username.clone())
+        .param("password", user.password.clone())
+        .param("role", user.role.clone())
+        .param("disabled", user.disabled)
+        .param("must_reset_password", user.must_reset_password)
+        .param("now", now.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn redeem_invite(&self, code: &str, username: &str, password: &str, now: DateTime<Utc>) -> Result<bool, RepoError> {
+        // Writing to the invite first takes its lock, so the used_by check below sees
+        // any registration that claimed the same code concurrently.
+        let query = query("
+            MATCH (i:InviteCode {code: $code})
+            SET i.last_attempt_at = $now
+            WITH i
+            WHERE i.used_by IS NULL AND i.expires > $timestamp
+            SET i.used_by = $username, i.used_at = $now
+            CREATE (u:User {name: $username, password: $password, role: i.role, created_at: $now})
+            RETURN u.name AS name
+        ")
+        .param("code", code.to_string())
+        .param("username", username.to_string())
+        .param("password", password.to_string())
+        .param("timestamp", now.timestamp())
+        .param("now", now.to_rfc3339());
+
+        Ok(self.first(query, |_| Ok(())).await?.is_some())
+    }
+
+    async fn update_user(&self, username: &str, update: &UserUpdate) -> Result<(), RepoError> {
+        let mut changes: HashMap<String, BoltType> = HashMap::new();
+        if let Some(role) = &update.role {
+            changes.insert("role".to_string(), BoltType::from(role.clone()));
+        }
+        if let Some(disabled) = update.disabled {
+            changes.insert("disabled".to_string(), BoltType::from(disabled));
+        }
+        if let Some(password) = &update.password {
+            changes.insert("password".to_string(), BoltType::from(password.clone()));
+        }
+        if let Some(must_reset_password) = update.must_reset_password {
+            changes.insert("must_reset_password".to_string(), BoltType::from(must_reset_password));
+        }
+        if let Some(at) = &update.password_changed_at {
+            changes.insert("password_changed_at".to_string(), BoltType::from(at.clone()));
+        }
+
+        let query = query("
+            MATCH (u:User {name: $username})
+            SET u += $changes
+        ")
+        .param("username", username.to_string())
+        .param("changes", changes);
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (u:User {name: $username})
+            OPTIONAL MATCH (u)-[:HAS_REFRESH_TOKEN]->(t:RefreshToken)
+            DETACH DELETE t, u
+        ").param("username", username.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn bootstrap_roles(&self, permissions: &[&str], roles: &[(&str, &[&str])]) -> Result<(), RepoError> {
+        let permissions: Vec<String> = permissions.iter().map(|p| p.to_string()).collect();
+        self.graph.run(query("
+            UNWIND $permissions AS name
+            MERGE (:Permission {name: name})
+        ").param("permissions", permissions)).await?;
+
+        // Existing roles are left alone so admin edits survive restarts.
+        for (role, grants) in roles {
+            let grants: Vec<String> = grants.iter().map(|p| p.to_string()).collect();
+            self.graph.run(query("
+                OPTIONAL MATCH (existing:Role {name: $role})
+                WITH existing WHERE existing IS NULL
+                CREATE (r:Role {name: $role, builtin: true})
+                WITH r
+                UNWIND $grants AS name
+                MATCH (p:Permission {name: name})
+                CREATE (r)-[:GRANTS]->(p)
+            ")
+            .param("role", role.to_string())
+            .param("grants", grants)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn role_permissions(&self, role: &str) -> Result<Vec<String>, RepoError> {
+        let query = query("
+            MATCH (:Role {name: $role})-[:GRANTS]->(p:Permission)
+            RETURN p.name AS permission
+            ORDER BY permission
+        ").param("role", role.to_string());
+
+        self.rows(query, |record| record.get("permission")).await
+    }
+
+    async fn find_role(&self, name: &str) -> Result<Option<RoleResponse>, RepoError> {
+        let query = query(&format!("MATCH (r:Role {{name: $name}}) {}", ROLE_FIELDS))
+            .param("name", name.to_string());
+
+        self.first(query, role_response).await
+    }
+
+    async fn list_roles(&self) -> Result<Vec<RoleResponse>, RepoError> {
+        let query = query(&format!("MATCH (r:Role) {} ORDER BY name", ROLE_FIELDS));
+
+        self.rows(query, role_response).await
+    }
+
+    async fn set_role_permissions(&self, name: &str, permissions: &[String]) -> Result<(), RepoError> {
+        let query = query("
+            MERGE (r:Role {name: $name})
+            ON CREATE SET r.builtin = false
+            WITH r
+            OPTIONAL MATCH (r)-[g:GRANTS]->(:Permission)
+            DELETE g
+            WITH DISTINCT r
+            UNWIND $permissions AS name
+            MATCH (p:Permission {name: name})
+            CREATE (r)-[:GRANTS]->(p)
+        ")
+        .param("name", name.to_string())
+        .param("permissions", permissions.to_vec());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<i64, RepoError> {
+        let query = query("
+            MATCH (r:Role {name: $name})
+            OPTIONAL MATCH (u:User {role: $name})
+            WITH r, count(u) AS users
+            FOREACH (_ IN CASE WHEN users = 0 THEN [1] ELSE [] END | DETACH DELETE r)
+            RETURN users
+        ").param("name", name.to_string());
+
+        Ok(self.first(query, |record| record.get("users")).await?.unwrap_or(0))
+    }
+
+    async fn store_refresh_token(&self, token: &RefreshTokenRecord) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (u:User {name: $username})
+            CREATE (u)-[:HAS_REFRESH_TOKEN]->(:RefreshToken {
+                jti: $jti,
+                family: $family,
+                username: $username,
+                expires: $expires,
+                created_at: $created_at,
+                revoked: $revoked
+            })
+        ")
+        .param("username", token.username.clone())
+        .param("jti", token.jti.clone())
+        .param("family", token.family.clone())
+        .param("expires", token.expires)
+        .param("created_at", token.created_at.clone())
+        .param("revoked", token.revoked);
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn consume_refresh_token(&self, jti: &str, now: &str) -> Result<Option<ConsumedToken>, RepoError> {
+        // The role is re-read from the user so admin changes apply from the next refresh.
+        let query = query("
+            MATCH (t:RefreshToken {jti: $jti})
+            WITH t, t.revoked AS was_revoked
+            SET t.revoked = true, t.used_at = $now
+            WITH t, was_revoked
+            OPTIONAL MATCH (u:User {name: t.username})
+            RETURN t.family AS family, was_revoked, u.role AS role,
+                coalesce(u.disabled, false) OR coalesce(u.must_reset_password, false) AS blocked
+        ")
+        .param("jti", jti.to_string())
+        .param("now", now.to_string());
+
+        self.first(query, |record| Ok(ConsumedToken {
+            family: record.get("family").unwrap_or("".to_string()),
+            was_revoked: record.get("was_revoked").unwrap_or(true),
+            role: record.get::<Option<String>>("role").unwrap_or(None),
+            blocked: record.get("blocked").unwrap_or(true),
+        })).await
+    }
+
+    async fn refresh_token_family(&self, jti: &str) -> Result<Option<String>, RepoError> {
+        let query = query("
+            MATCH (t:RefreshToken {jti: $jti})
+            RETURN t.family AS family
+        ").param("jti", jti.to_string());
+
+        self.first(query, |record| record.get("family")).await
+    }
+
+    async fn revoke_token_family(&self, family: &str) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (t:RefreshToken {family: $family})
+            SET t.revoked = true
+        ").param("family", family.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn revoke_user_tokens(&self, username: &str) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (t:RefreshToken {username: $username})
+            SET t.revoked = true
+        ").param("username", username.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn create_invite(&self, invite: &Invite, now: &str) -> Result<(), RepoError> {
+        let query = query("
+            CREATE (:InviteCode {code: $code, role: $role, created_by: $created_by, created_at: $now, expires: $expires})
+        ")
+        .param("code", invite.code.clone())
+        .param("role", invite.role.clone())
+        .param("created_by", invite.created_by.clone())
+        .param("now", now.to_string())
+        .param("expires", invite.expires);
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn list_invites(&self, now: i64) -> Result<Vec<Invite>, RepoError> {
+        let query = query("
+            MATCH (i:InviteCode)
+            WHERE i.used_by IS NULL AND i.expires > $now
+            RETURN i.code AS code, i.role AS role, i.created_by AS created_by, i.expires AS expires
+            ORDER BY expires
+        ").param("now", now);
+
+        self.rows(query, |record| Ok(Invite {
+            code: record.get("code")?,
+            role: record.get("role")?,
+            created_by: record.get("created_by").unwrap_or("".to_string()),
+            expires: record.get("expires")?,
+        })).await
+    }
+
+    async fn delete_invite(&self, code: &str) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (i:InviteCode {code: $code})
+            WHERE i.used_by IS NULL
+            DELETE i
+        ").param("code", code.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn locked_until(&self, keys: &[String], now: i64) -> Result<Option<i64>, RepoError> {
+        let query = query("
+            MATCH (t:LoginThrottle)
+            WHERE t.key IN $keys AND t.locked_until > $now
+            RETURN max(t.locked_until) AS until
+        ")
+        .param("keys", keys.to_vec())
+        .param("now", now);
+
+        Ok(self.first(query, |record| record.get::<Option<i64>>("until")).await?.flatten())
+    }
+
+    async fn record_login_failure(&self, key: &str, policy: &ThrottlePolicy, now: i64) -> Result<(), RepoError> {
+        let query = query("
+            MERGE (t:LoginThrottle {key: $key})
+            ON CREATE SET t.failures = 0
+            WITH t, CASE
+                WHEN coalesce(t.locked_until, 0) > coalesce(t.last_failure_at, 0) THEN t.locked_until
+                ELSE coalesce(t.last_failure_at, 0)
+            END AS last_activity
+            SET t.failures = CASE WHEN last_activity < $now - $max_secs THEN 1 ELSE t.failures + 1 END,
+                t.last_failure_at = $now
+            WITH t, t.failures - $max_attempts AS over
+            SET t.locked_until = CASE
+                WHEN over < 0 THEN null
+                WHEN $base_secs * 2 ^ over > $max_secs THEN $now + $max_secs
+                ELSE $now + toInteger($base_secs * 2 ^ over)
+            END
+        ")
+        .param("key", key.to_string())
+        .param("now", now)
+        .param("max_attempts", policy.max_attempts as i64)
+        .param("base_secs", policy.lockout_secs)
+        .param("max_secs", policy.lockout_max_secs);
+
+        Ok(self.graph.run(query).await?)
+    }
+
+    async fn clear_login_failures(&self, key: &str) -> Result<(), RepoError> {
+        let query = query("
+            MATCH (t:LoginThrottle {key: $key})
+            DELETE t
+        ").param("key", key.to_string());
+
+        Ok(self.graph.run(query).await?)
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use chrono::Utc;
+use neo4rs::query;
+use super::*;
+
+/*
+    Schema migrations
+
+    Each migration is a list of Cypher statements run in order, then recorded as
+    (:SchemaMigration {version, name, applied_at}). Only versions the graph has no
+    record of are run, lowest first.
+
+    Schema statements cannot share a transaction with anything else, so a migration
+    is not atomic. Every statement must be safe to run twice (IF NOT EXISTS, or a
+    data fix that matches nothing the second time) so a migration that failed part
+    way can simply be run again.
+
+    A unique constraint cannot be created over duplicates, so a migration lists
+    checks that return the values in the way. They run before its statements, and
+    any rows stop the migration with a message saying what to clean up.
+
+    Never edit a migration once it has shipped, add a new one.
+*/
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub checks: &'static [Check],
+    pub statements: &'static [&'static str],
+}
+
+/// A query returning `value` for each row a migration cannot go ahead with.
+pub struct Check {
+    pub query: &'static str,
+    pub problem: &'static str,
+}
+
+impl Migration {
+    /// The app relies on these: without user_name_unique, two /register calls
+    /// at once can create the same user.
+    pub fn creates_constraints(&self) -> bool {
+        self.statements.iter().any(|statement| statement.starts_with("CREATE CONSTRAINT"))
+    }
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "unique_keys",
+        checks: &[
+            Check {
+                query: "MATCH (u:User) WITH u.name AS value, count(*) AS n WHERE n > 1 RETURN toString(value) AS value",
+                problem: "usernames held by more than one User; rename or delete the extras",
+            },
+            Check {
+                query: "MATCH (t:Trailer) WITH t.id AS value, count(*) AS n WHERE n > 1 RETURN toString(value) AS value",
+                problem: "trailer ids held by more than one Trailer; merge them",
+            },
+            Check {
+                query: "MATCH (s:Shipment) WITH s.LoadId AS value, count(*) AS n WHERE n > 1 RETURN toString(value) AS value",
+                problem: "LoadIds held by more than one Shipment; merge them",
+            },
+        ],
+        statements: &[
+            "CREATE CONSTRAINT schema_migration_version_unique IF NOT EXISTS FOR (m:SchemaMigration) REQUIRE m.version IS UNIQUE",
+            "CREATE CONSTRAINT user_name_unique IF NOT EXISTS FOR (u:User) REQUIRE u.name IS UNIQUE",
+            "CREATE CONSTRAINT trailer_id_unique IF NOT EXISTS FOR (t:Trailer) REQUIRE t.id IS UNIQUE",
+            "CREATE CONSTRAINT shipment_load_id_unique IF NOT EXISTS FOR (s:Shipment) REQUIRE s.LoadId IS UNIQUE",
+            "CREATE CONSTRAINT role_name_unique IF NOT EXISTS FOR (r:Role) REQUIRE r.name IS UNIQUE",
+            "CREATE CONSTRAINT permission_name_unique IF NOT EXISTS FOR (p:Permission) REQUIRE p.name IS UNIQUE",
+            "CREATE CONSTRAINT invite_code_unique IF NOT EXISTS FOR (i:InviteCode) REQUIRE i.code IS UNIQUE",
+            "CREATE CONSTRAINT refresh_token_jti_unique IF NOT EXISTS FOR (t:RefreshToken) REQUIRE t.jti IS UNIQUE",
+            "CREATE CONSTRAINT login_throttle_key_unique IF NOT EXISTS FOR (t:LoginThrottle) REQUIRE t.key IS UNIQUE",
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "lookup_indexes",
+        checks: &[],
+        statements: &[
+            "CREATE INDEX schedule_date IF NOT EXISTS FOR (s:Schedule) ON (s.ScheduleDate)",
+            "CREATE INDEX shipment_schedule_date IF NOT EXISTS FOR (s:Shipment) ON (s.ScheduleDate)",
+            "CREATE INDEX count_date IF NOT EXISTS FOR (c:Count) ON (c.Date)",
+            "CREATE INDEX shipment_event_load_id IF NOT EXISTS FOR (e:ShipmentEvent) ON (e.LoadId)",
+            "CREATE INDEX schedule_change_at IF NOT EXISTS FOR (c:ScheduleChange) ON (c.at)",
+            "CREATE INDEX refresh_token_family IF NOT EXISTS FOR (t:RefreshToken) ON (t.family)",
+            "CREATE INDEX refresh_token_username IF NOT EXISTS FOR (t:RefreshToken) ON (t.username)",
+            "CREATE INDEX user_role IF NOT EXISTS FOR (u:User) ON (u.role)",
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "fold_load_statue",
+        checks: &[],
+        statements: &[
+            // set_arrival_time used to write its status to LoadStatue. The app can
+            // run before this does, and what it writes to LoadStatus is newer, so
+            // LoadStatue only fills in a missing LoadStatus.
+            "MATCH (s:Schedule) WHERE s.LoadStatue IS NOT NULL AND s.LoadStatus IS NULL
+             SET s.LoadStatus = s.LoadStatue",
+            "MATCH (s:Schedule) WHERE s.LoadStatue IS NOT NULL
+             REMOVE s.LoadStatue",
+        ],
+    },
+];
+
+#[derive(Debug)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: String,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The applied migrations could not be read, nothing was run.
+    Status(RepoError),
+    /// A check of this migration found data in the way, nothing of it was run.
+    Blocked { version: i64, name: &'static str, problem: &'static str, values: Vec<String> },
+    /// A statement of this migration failed; earlier migrations stay applied.
+    Failed { version: i64, name: &'static str, error: RepoError },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Status(e) => write!(f, "could not read applied migrations: {}", e),
+            MigrationError::Blocked { version, name, problem, values } => write!(
+                f,
+                "migration {} ({}) cannot run: {}: {}. Fix the data, then run `rocket_http migrate` again",
+                version, name, problem, values.join(", "),
+            ),
+            MigrationError::Failed { version, name, error } => write!(f, "migration {} ({}) failed: {}", version, name, error),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl Neo4jStore {
+    pub async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, RepoError> {
+        let query = query("
+            MATCH (m:SchemaMigration)
+            RETURN m.version AS version, m.name AS name, m.applied_at AS applied_at
+            ORDER BY m.version
+        ");
+        self.rows(query, |row| Ok(AppliedMigration {
+            version: row.get("version")?,
+            name: row.get("name")?,
+            applied_at: row.get("applied_at")?,
+        })).await
+    }
+
+    /// Migrations the graph has not recorded yet, in the order they would run.
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static Migration>, RepoError> {
+        let applied: Vec<i64> = self.applied_migrations().await?.iter().map(|m| m.version).collect();
+        Ok(MIGRATIONS.iter().filter(|m| !applied.contains(&m.version)).collect())
+    }
+
+    /// Runs every pending migration and returns the ones it applied.
+    pub async fn migrate(&self) -> Result<Vec<&'static Migration>, MigrationError> {
+        let pending = self.pending_migrations().await.map_err(MigrationError::Status)?;
+
+        for migration in &pending {
+            let failed = |error| MigrationError::Failed { version: migration.version, name: migration.name, error };
+            for check in migration.checks {
+                let values = self.rows(query(check.query), |row| row.get("value")).await.map_err(failed)?;
+                if !values.is_empty() {
+                    return Err(MigrationError::Blocked {
+                        version: migration.version,
+                        name: migration.name,
+                        problem: check.problem,
+                        values,
+                    });
+                }
+            }
+            self.apply(migration).await.map_err(failed)?;
+        }
+        Ok(pending)
+    }
+
+    async fn apply(&self, migration: &Migration) -> Result<(), RepoError> {
+        for statement in migration.statements {
+            self.graph.run(query(statement)).await?;
+        }
+
+        // MERGE so an instance starting at the same time cannot record it twice.
+        self.graph.run(query("
+            MERGE (m:SchemaMigration {version: $version})
+            ON CREATE SET m.name = $name, m.applied_at = $applied_at
+        ")
+        .param("version", migration.version)
+        .param("name", migration.name)
+        .param("applied_at", Utc::now().to_rfc3339())).await?;
+        Ok(())
+    }
+}
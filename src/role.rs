@@ -1,18 +1,89 @@
+use std::fmt;
+use std::str::FromStr;
 use rocket::request::{FromRequest, Outcome, Request};
 use rocket::http::Status;
+use serde::{Deserialize, Serialize};
 use crate::auth::AuthenticatedUser;
 
-pub struct Role(pub String);
+/*
+    Roles are ordered, each one includes everything the ones below it can do:
+
+    read < write < admin
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Write => "write",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn allows(&self, required: Role) -> bool {
+        *self >= required
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Role::Read),
+            "write" => Ok(Role::Write),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for Role {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        if let Outcome::Success(auth_user) = request.guard::<AuthenticatedUser>().await {
-            let role = auth_user.0.role.clone();
-            return Outcome::Success(Role(role));
+        match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(auth_user) => match auth_user.0.role.parse::<Role>() {
+                Ok(role) => Outcome::Success(role),
+                Err(_) => Outcome::Error((Status::Forbidden, ())),
+            },
+            Outcome::Error(e) => Outcome::Error(e),
+            Outcome::Forward(f) => Outcome::Forward(f),
         }
-        Outcome::Error((Status::Forbidden, ()))
     }
-}
\ No newline at end of file
+}
+
+async fn require(request: &Request<'_>, required: Role) -> Outcome<Role, ()> {
+    match request.guard::<Role>().await {
+        Outcome::Success(role) if role.allows(required) => Outcome::Success(role),
+        Outcome::Success(_) => Outcome::Error((Status::Forbidden, ())),
+        other => other,
+    }
+}
+
+/// Request guard for routes that need `admin`.
+pub struct RequireAdmin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequireAdmin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        require(request, Role::Admin).await.map(|_| RequireAdmin)
+    }
+}
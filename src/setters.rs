@@ -1,213 +1,215 @@
 use crate::structs::*;
 use crate::auth::AuthenticatedUser;
-use crate::role::Role;
-use futures_util::TryFutureExt;
+use crate::permission::{Can, DoorsWrite, ScheduleWrite, ShipmentsDelete, ShipmentsPick, ShipmentsWrite};
+use crate::role::RequireAdmin;
+use crate::error::ApiError;
+use crate::events::Event;
+use crate::repository::ShipmentUpdate;
+use crate::status::{check_transition, ShipmentStatus, TransitionError};
 use rocket::{post, serde::json::Json, State};
-use neo4rs::{query, Node};
+use serde::Serialize;
+use chrono::Utc;
+
+/*
+    Shipment status helpers
+*/
+
+// Returns the stored status so the write can be guarded against it.
+async fn check_shipment_transition(state: &AppState, load_id: &str, next: ShipmentStatus) -> Result<String, ApiError> {
+    match state.shipments.state(load_id).await? {
+        Some((current, is_hold)) => {
+            check_transition(load_id, &current, is_hold, next)?;
+            Ok(current)
+        },
+        None => Err(ApiError::not_found()),
+    }
+}
 
-#[post("/api/set_schedule", format = "json", data = "<schedule_request>")]
-pub async fn set_schedule(
-    schedule_request: Json<SetScheduleRequest>,
-    state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Vec<TrailerSchedule>>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
+fn shipment_event<P: Serialize>(load_id: &str, event: &str, from: &str, to: &str, actor: &str, payload: &P) -> ShipmentEvent {
+    ShipmentEvent {
+        LoadId: load_id.to_string(),
+        event: event.to_string(),
+        from: from.to_string(),
+        to: to.to_string(),
+        actor: actor.to_string(),
+        at: Utc::now().to_rfc3339(),
+        payload: serde_json::to_value(payload).unwrap_or_default(),
     }
+}
 
-    let graph = &state.graph;
+// Appends to the shipment's history. Failures are logged rather than returned
+// because the shipment write has already gone through.
+async fn record_shipment_event<P: Serialize>(state: &AppState, load_id: &str, event: &str, from: &str, to: &str, actor: &str, payload: &P) {
+    let event = shipment_event(load_id, event, from, to, actor, payload);
+    if let Err(e) = state.shipments.record_event(&event).await {
+        println!("Failed to record shipment event for {}: {}", load_id, e);
+    }
+}
 
-    let query = query("
-        MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
-        WHERE trailer.id = $TrailerID
-        SET s.ScheduleDate = $ScheduleDate,
-            s.RequestDate = $RequestDate,
-            s.CarrierCode = $CarrierCode,
-            s.ScheduleTime = $ScheduleTime,
-            s.LastFreeDate = $LastFreeDate,
-            s.ContactEmail = $ContactEmail,
-            s.DoorNumber = $Door,
-            s.Seal = $Seal
-        RETURN trailer.id as TrailerID, s
-    ")
-    .param("TrailerID", schedule_request.TrailerID.clone())
-    .param("ClaimComments", schedule_request.ClaimComments.clone())
-    .param("ScheduleDate", schedule_request.ScheduleDate.clone())
-    .param("RequestDate", schedule_request.RequestDate.clone())
-    .param("CarrierCode", schedule_request.CarrierCode.clone())
-    .param("ScheduleTime", schedule_request.ScheduleTime.clone())
-    .param("LastFreeDate", schedule_request.LastFreeDate.clone())
-    .param("ContactEmail", schedule_request.ContactEmail.clone())
-    .param("Seal", schedule_request.Seal.clone())
-    .param("Door", schedule_request.Door.clone());
+// The guarded write matched nothing: the status or hold changed after it was checked.
+fn stale_transition(load_id: &str, from: &str, next: ShipmentStatus) -> ApiError {
+    ApiError::from(TransitionError {
+        code: "STALE_STATUS",
+        message: format!("Shipment {} changed while moving to {}, reload and retry", load_id, next),
+        LoadId: load_id.to_string(),
+        from: from.to_string(),
+        to: next.to_string(),
+        IsHold: false,
+    })
+}
 
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut data: Vec<TrailerSchedule> = Vec::new();
-            while let Ok(Some(record)) = result.next().await {
+// Checks the move against the transition table, applies `update` with the new
+// status guarded against the status it was checked at, then records and publishes
+// the event.
+async fn transition<P: Serialize>(
+    state: &AppState,
+    load_id: &str,
+    next: ShipmentStatus,
+    mut update: ShipmentUpdate,
+    event: fn(Shipment) -> Event,
+    user: &AuthenticatedUser,
+    payload: &P,
+) -> Result<Json<Shipment>, ApiError> {
+    let from = check_shipment_transition(state, load_id, next).await?;
+    update.Status = Some(next.as_str().to_string());
+
+    match state.shipments.update(load_id, Some(&from), &update).await? {
+        Some(shipment) => {
+            let event = event(shipment.clone());
+            record_shipment_event(state, load_id, event.name(), &from, &shipment.Status, &user.0.username, payload).await;
+            state.events.publish(event);
+            Ok(Json(shipment))
+        },
+        None => Err(stale_transition(load_id, &from, next)),
+    }
+}
 
-                let trailer_id: String = record.get("TrailerID").unwrap();
-                let schedule_node: Node = record.get("s").unwrap();
-                let schedule_date: String = schedule_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = schedule_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = schedule_node.get("ArrivalTime").unwrap_or("".to_string());
-                let carrier_code: String = schedule_node.get("CarrierCode").unwrap_or("".to_string());
-                let contact_email: String = schedule_node.get("ContactEmail").unwrap_or("".to_string());
-                let door_number: String = schedule_node.get("DoorNumber").unwrap_or("".to_string());
-                let is_hot: bool = schedule_node.get("IsHot").unwrap_or(false);
-                let last_free_date: String = schedule_node.get("LastFreeDate").unwrap_or("".to_string());
-                let load_status: String = schedule_node.get("LoadStatus").unwrap_or("".to_string());
-                let request_date: String = schedule_node.get("RequestDate").unwrap_or("".to_string());
-                let carrier_claim: String = schedule_node.get("ClaimComments").unwrap();
-                let has_claim: bool = schedule_node.get("HasClaim").unwrap();
-                let is_stat6: bool = schedule_node.get("IsStat6").unwrap();
-                let seal: String = schedule_node.get("Seal").unwrap();
-                let is_multi: bool = schedule_node.get("IsMulti").unwrap_or(false);
-                let schedule_data = TrailerSchedule {
-                    TrailerID: trailer_id,
-                    Schedule: Schedule {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        CarrierCode: carrier_code,
-                        ContactEmail: contact_email,	
-                        DoorNumber: door_number,
-                        IsHot: is_hot,
-                        LastFreeDate: last_free_date,
-                        LoadStatus: load_status,
-                        RequestDate: request_date,
-                        ClaimComments: carrier_claim,
-                        IsStat6: is_stat6,
-                        IsMulti: is_multi,
-                        Seal: seal,
-                        HasClaim: has_claim,
-                    },
-                };
-                data.push(schedule_data);
-            }
+/*
+    Schedule change helpers
+*/
 
-            Ok(Json(data))
-        },
+// Current schedule for a trailer, used as the "old" side of the change log.
+async fn schedule_snapshot(state: &AppState, trailer_id: &str) -> Option<Schedule> {
+    match state.trailers.schedule(trailer_id).await {
+        Ok(schedule) => schedule,
         Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
+            println!("Failed to run query: {}", e);
+            None
         }
     }
 }
 
-#[post("/api/delete_shipment", format = "json", data = "<delete_shipment>")]
-pub async fn delete_shipment(
-    delete_shipment: Json<DeleteShipmentRequest>,
-    state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<(), Json<&'static str>> {
-    if role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
+// Records one change per field that differs between the two schedules. Failures
+// are logged, the schedule write already happened.
+async fn record_schedule_changes(state: &AppState, updated: &TrailerSchedule, previous: &Schedule, event: &str, user: &str) {
+    let (old, new) = match (serde_json::to_value(previous), serde_json::to_value(&updated.Schedule)) {
+        (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) => (old, new),
+        _ => return,
+    };
 
-    let graph = &state.graph;
+    let as_string = |v: Option<&serde_json::Value>| match v {
+        Some(serde_json::Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+        None => "".to_string(),
+    };
 
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        DETACH DELETE s
-    ").param("LoadId", delete_shipment.LoadId.clone());
+    let at = Utc::now().to_rfc3339();
+    let changes: Vec<ScheduleChange> = new.keys()
+        .filter(|field| old.get(*field) != new.get(*field))
+        .map(|field| ScheduleChange {
+            TrailerID: updated.TrailerID.clone(),
+            event: event.to_string(),
+            field: field.clone(),
+            old: as_string(old.get(field)),
+            new: as_string(new.get(field)),
+            user: user.to_string(),
+            at: at.clone(),
+        })
+        .collect();
+
+    if changes.is_empty() {
+        return;
+    }
 
-    match graph.execute(query).await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
+    if let Err(e) = state.trailers.record_schedule_changes(&changes).await {
+        println!("Failed to record schedule changes for {}: {}", updated.TrailerID, e);
+    }
+}
+
+// Logs each updated schedule against its previous state, then publishes it.
+async fn record_schedules(
+    state: &AppState,
+    updated: &[TrailerSchedule],
+    previous: &Option<Schedule>,
+    event: &str,
+    publish: fn(TrailerSchedule) -> Event,
+    user: &AuthenticatedUser,
+) {
+    for schedule in updated {
+        if let Some(previous) = previous {
+            record_schedule_changes(state, schedule, previous, event, &user.0.username).await;
         }
+        state.events.publish(publish(schedule.clone()));
     }
 }
 
+#[post("/api/set_schedule", format = "json", data = "<schedule_request>")]
+pub async fn set_schedule(
+    schedule_request: Json<SetScheduleRequest>,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _perm: Can<ScheduleWrite>,
+) -> Result<Json<Vec<TrailerSchedule>>, ApiError> {
+    let previous = schedule_snapshot(state, &schedule_request.TrailerID).await;
+
+    let data = state.trailers.set_schedule(&schedule_request).await?;
+    record_schedules(state, &data, &previous, "set_schedule", Event::ScheduleTrailer, &user).await;
+    Ok(Json(data))
+}
+
+#[post("/api/delete_shipment", format = "json", data = "<delete_shipment>")]
+pub async fn delete_shipment(
+    delete_shipment: Json<DeleteShipmentRequest>,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsDelete>,
+) -> Result<(), ApiError> {
+    // The history is kept by LoadId, so it outlives the shipment.
+    let current = state.shipments.state(&delete_shipment.LoadId).await?.map(|(status, _)| status).unwrap_or_default();
+    let event = shipment_event(&delete_shipment.LoadId, "delete_shipment", &current, "DELETED", &user.0.username, &*delete_shipment);
+    state.shipments.delete(&delete_shipment.LoadId, &event).await?;
+    state.events.publish(Event::DeleteShipment(delete_shipment.into_inner()));
+    Ok(())
+}
+
 #[post("/api/new_shipment", format = "json", data = "<new_shipment>")]
 pub async fn new_shipment(
     new_shipment: Json<Shipment>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsWrite>,
+) -> Result<Json<Shipment>, ApiError> {
+    let mut from = String::new();
+    if let Some((current, is_hold)) = state.shipments.state(&new_shipment.LoadId).await? {
+        check_transition(&new_shipment.LoadId, &current, is_hold, ShipmentStatus::NotStarted)?;
+        from = current;
     }
 
-    let graph = &state.graph;
-
-    let query = query("
-        MERGE (s:Shipment {LoadId: $LoadId})
-        SET s.ScheduleDate = $ScheduleDate,
-            s.ScheduleTime = $ScheduleTime,
-            s.ArrivalTime = '',
-            s.DepartTime = '',
-            s.Dock = $Dock,
-            s.Door = $Door,
-            s.Status = 'NOT STARTED',
-            s.LoadId = $LoadId,
-            s.Picker = '',
-            s.PickStartTime = '',
-            s.VerifiedBy = '',
-            s.LoadNum = $LoadNum,
-            s.TrailerNum = ''
-        RETURN s
-    ")
-    .param("ScheduleDate", new_shipment.ScheduleDate.clone())
-    .param("ScheduleTime", new_shipment.ScheduleTime.clone())
-    .param("Dock", new_shipment.Dock.clone())
-    .param("LoadId", new_shipment.LoadId.clone())
-    .param("LoadNum", new_shipment.LoadNum.clone())
-    .param("Door", new_shipment.Door.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
-
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
+    match state.shipments.create(&new_shipment).await? {
+        Some(shipment) => {
+            record_shipment_event(
+                state,
+                &shipment.LoadId,
+                "new_shipment",
+                &from,
+                &shipment.Status,
+                &user.0.username,
+                &*new_shipment,
+            ).await;
+            state.events.publish(Event::NewShipment(shipment.clone()));
+            Ok(Json(shipment))
         },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
+        None => Err(stale_transition(&new_shipment.LoadId, &from, ShipmentStatus::NotStarted)),
     }
 }
 
@@ -215,71 +217,24 @@ pub async fn new_shipment(
 pub async fn shipment_door(
     shipment_door: Json<ShipmentDoor>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MERGE (s:Shipment {LoadId: $LoadId})
-        SET s.Door = $Door
-        RETURN s
-    ")
-    .param("LoadId", shipment_door.LoadId.clone())
-    .param("Door", shipment_door.Door.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
-
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
+    user: AuthenticatedUser,
+    _perm: Can<DoorsWrite>,
+) -> Result<Json<Shipment>, ApiError> {
+    match state.shipments.set_door(&shipment_door.LoadId, &shipment_door.Door).await? {
+        Some(shipment) => {
+            record_shipment_event(
+                state,
+                &shipment.LoadId,
+                "set_shipment_door",
+                &shipment.Status,
+                &shipment.Status,
+                &user.0.username,
+                &*shipment_door,
+            ).await;
+            state.events.publish(Event::SetShipmentDoor(shipment.clone()));
+            Ok(Json(shipment))
         },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
+        None => Err(ApiError::not_found()),
     }
 }
 
@@ -287,311 +242,82 @@ pub async fn shipment_door(
 pub async fn hot_trailer(
     hot_trailer_request: Json<HotTrailerRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Vec<TrailerSchedule>>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-    println!("{:?}", hot_trailer_request);
-
-    let query = query("
-        MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
-        WHERE trailer.id = $TrailerID
-        SET s.IsHot = NOT s.IsHot  
-        RETURN trailer.id as TrailerID, s
-    ").param("TrailerID", hot_trailer_request.TrailerID.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut data: Vec<TrailerSchedule> = Vec::new();
-            while let Ok(Some(record)) = result.next().await {
-
-                let trailer_id: String = record.get("TrailerID").unwrap();
-                let schedule_node: Node = record.get("s").unwrap();
-                let schedule_date: String = schedule_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = schedule_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = schedule_node.get("ArrivalTime").unwrap_or("".to_string());
-                let carrier_code: String = schedule_node.get("CarrierCode").unwrap_or("".to_string());
-                let contact_email: String = schedule_node.get("ContactEmail").unwrap_or("".to_string());
-                let door_number: String = schedule_node.get("DoorNumber").unwrap_or("".to_string());
-                let is_hot: bool = schedule_node.get("IsHot").unwrap_or(false);
-                let last_free_date: String = schedule_node.get("LastFreeDate").unwrap_or("".to_string());;
-                let load_status: String = schedule_node.get("LoadStatus").unwrap_or("".to_string());
-                let request_date: String = schedule_node.get("RequestDate").unwrap_or("".to_string());
-                let carrier_claim: String = schedule_node.get("ClaimComments").unwrap();
-                let has_claim: bool = schedule_node.get("HasClaim").unwrap();
-                let is_stat6: bool = schedule_node.get("IsStat6").unwrap();
-                let seal: String = schedule_node.get("Seal").unwrap();
-                let is_multi: bool = schedule_node.get("IsMulti").unwrap_or(false);
-                let schedule_data = TrailerSchedule {
-                    TrailerID: trailer_id,
-                    Schedule: Schedule {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        CarrierCode: carrier_code,
-                        ContactEmail: contact_email,	
-                        DoorNumber: door_number,
-                        IsHot: is_hot,
-                        LastFreeDate: last_free_date,
-                        LoadStatus: load_status,
-                        RequestDate: request_date,
-                        ClaimComments: carrier_claim,
-                        HasClaim: has_claim,
-                        IsMulti: is_multi,
-                        IsStat6: is_stat6,
-                        Seal: seal,
-                    },
-                };
-                data.push(schedule_data);
-            }
-
-            Ok(Json(data))
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    user: AuthenticatedUser,
+    _perm: Can<ScheduleWrite>,
+) -> Result<Json<Vec<TrailerSchedule>>, ApiError> {
+    let previous = schedule_snapshot(state, &hot_trailer_request.TrailerID).await;
+
+    let data = state.trailers.toggle_hot(&hot_trailer_request.TrailerID).await?;
+    record_schedules(state, &data, &previous, "hot_trailer", Event::HotTrailer, &user).await;
+    Ok(Json(data))
 }
 
 #[post("/api/set_door", format = "json", data = "<set_door_request>")]
 pub async fn set_door(
     set_door_request: Json<SetDoorRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Vec<TrailerSchedule>>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-    println!("{:?}", set_door_request);
-
-    let query = query("
-        MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
-        WHERE trailer.id = $TrailerID
-        SET s.DoorNumber = $Door
-        RETURN trailer.id as TrailerID, s
-    ")
-    .param("TrailerID", set_door_request.TrailerID.clone())
-    .param("Door", set_door_request.Door.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut data: Vec<TrailerSchedule> = Vec::new();
-            while let Ok(Some(record)) = result.next().await {
-
-                let trailer_id: String = record.get("TrailerID").unwrap();
-                let schedule_node: Node = record.get("s").unwrap();
-                let schedule_date: String = schedule_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = schedule_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = schedule_node.get("ArrivalTime").unwrap_or("".to_string());
-                let carrier_code: String = schedule_node.get("CarrierCode").unwrap_or("".to_string());
-                let contact_email: String = schedule_node.get("ContactEmail").unwrap_or("".to_string());
-                let door_number: String = schedule_node.get("DoorNumber").unwrap_or("".to_string());
-                let is_hot: bool = schedule_node.get("IsHot").unwrap_or(false);
-                let last_free_date: String = schedule_node.get("LastFreeDate").unwrap_or("".to_string());
-                let load_status: String = schedule_node.get("LoadStatus").unwrap_or("".to_string());
-                let request_date: String = schedule_node.get("RequestDate").unwrap_or("".to_string());
-                let carrier_claim: String = schedule_node.get("ClaimComments").unwrap();
-                let has_claim: bool = schedule_node.get("HasClaim").unwrap();
-                let is_stat6: bool = schedule_node.get("IsStat6").unwrap();
-                let seal: String = schedule_node.get("Seal").unwrap();
-                let is_multi: bool = schedule_node.get("IsMulti").unwrap_or(false);
-                let schedule_data = TrailerSchedule {
-                    TrailerID: trailer_id,
-                    Schedule: Schedule {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        CarrierCode: carrier_code,
-                        ContactEmail: contact_email,	
-                        DoorNumber: door_number,
-                        IsHot: is_hot,
-                        LastFreeDate: last_free_date,
-                        LoadStatus: load_status,
-                        RequestDate: request_date,
-                        ClaimComments: carrier_claim,
-                        HasClaim: has_claim,
-                        IsMulti: is_multi,
-                        IsStat6: is_stat6,
-                        Seal: seal,
-                    },
-                };
-                data.push(schedule_data);
-            }
-
-            Ok(Json(data))
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    user: AuthenticatedUser,
+    _perm: Can<DoorsWrite>,
+) -> Result<Json<Vec<TrailerSchedule>>, ApiError> {
+    let previous = schedule_snapshot(state, &set_door_request.TrailerID).await;
+
+    let data = state.trailers.set_door(&set_door_request.TrailerID, &set_door_request.Door).await?;
+    record_schedules(state, &data, &previous, "set_door", Event::SetDoor, &user).await;
+    Ok(Json(data))
 }
 
 #[post("/api/set_arrivalTime", format = "json", data = "<set_arrival_time_request>")]
 pub async fn set_arrival_time(
     set_arrival_time_request: Json<SetArrivalTimeRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Vec<TrailerSchedule>>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-    println!("{:?}", set_arrival_time_request);
+    user: AuthenticatedUser,
+    _perm: Can<ScheduleWrite>,
+) -> Result<Json<Vec<TrailerSchedule>>, ApiError> {
+    let previous = schedule_snapshot(state, &set_arrival_time_request.TrailerID).await;
 
     let load_status = if set_arrival_time_request.ArrivalTime.is_empty() {
-        "in-transit".to_string()
+        "in-transit"
     } else {
-        "arrived".to_string()
+        "arrived"
     };
 
-    let query = query("
-        MATCH (trailer:Trailer)-[:HAS_SCHEDULE]->(s:Schedule)
-        WHERE trailer.id = $TrailerID
-        SET s.ArrivalTime = $ArrivalTime
-        SET s.LoadStatue = $load_status
-        RETURN trailer.id as TrailerID, s
-    ")
-    .param("TrailerID", set_arrival_time_request.TrailerID.clone())
-    .param("ArrivalTime", set_arrival_time_request.ArrivalTime.clone())
-    .param("load_status", load_status);
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut data: Vec<TrailerSchedule> = Vec::new();
-            while let Ok(Some(record)) = result.next().await {
-
-                let trailer_id: String = record.get("TrailerID").unwrap();
-                let schedule_node: Node = record.get("s").unwrap();
-                let schedule_date: String = schedule_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = schedule_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = schedule_node.get("ArrivalTime").unwrap_or("".to_string());
-                let carrier_code: String = schedule_node.get("CarrierCode").unwrap_or("".to_string());
-                let contact_email: String = schedule_node.get("ContactEmail").unwrap_or("".to_string());
-                let door_number: String = schedule_node.get("DoorNumber").unwrap_or("".to_string());
-                let is_hot: bool = schedule_node.get("IsHot").unwrap_or(false);
-                let last_free_date: String = schedule_node.get("LastFreeDate").unwrap_or("".to_string());
-                let load_status: String = schedule_node.get("LoadStatus").unwrap_or("".to_string());
-                let request_date: String = schedule_node.get("RequestDate").unwrap_or("".to_string());
-                let carrier_claim: String = schedule_node.get("ClaimComments").unwrap();
-                let has_claim: bool = schedule_node.get("HasClaim").unwrap();
-                let is_stat6: bool = schedule_node.get("IsStat6").unwrap();
-                let seal: String = schedule_node.get("Seal").unwrap();
-                let is_multi: bool = schedule_node.get("IsMulti").unwrap_or(false);
-                let schedule_data = TrailerSchedule {
-                    TrailerID: trailer_id,
-                    Schedule: Schedule {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        CarrierCode: carrier_code,
-                        ContactEmail: contact_email,	
-                        DoorNumber: door_number,
-                        IsHot: is_hot,
-                        LastFreeDate: last_free_date,
-                        LoadStatus: load_status,
-                        RequestDate: request_date,
-                        ClaimComments: carrier_claim,
-                        HasClaim: has_claim,
-                        IsMulti: is_multi,
-                        IsStat6: is_stat6,
-                        Seal: seal,
-                    },
-                };
-
-                data.push(schedule_data);
-            }
-
-            Ok(Json(data))
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    let data = state.trailers.set_arrival_time(
+        &set_arrival_time_request.TrailerID,
+        &set_arrival_time_request.ArrivalTime,
+        load_status,
+    ).await?;
+    record_schedules(state, &data, &previous, "set_arrival_time", Event::TrailerArrived, &user).await;
+    Ok(Json(data))
 }
 
 #[post("/api/set_shipment_trailer", format = "json", data = "<set_shipment_arrival_time>")]
 pub async fn set_shipment_trailer(
     set_shipment_arrival_time: Json<ShipmentArrivalTimeRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.ArrivalTime = $ArrivalTime,
-            s.TrailerNum = $TrailerNum
-        RETURN s
-    ")
-    .param("LoadId", set_shipment_arrival_time.LoadId.clone())
-    .param("TrailerNum", set_shipment_arrival_time.TrailerNum.clone())
-    .param("ArrivalTime", set_shipment_arrival_time.ArrivalTime.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsWrite>,
+) -> Result<Json<Shipment>, ApiError> {
+    let update = ShipmentUpdate {
+        ArrivalTime: Some(set_shipment_arrival_time.ArrivalTime.clone()),
+        TrailerNum: Some(set_shipment_arrival_time.TrailerNum.clone()),
+        ..ShipmentUpdate::default()
+    };
 
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }          
+    match state.shipments.update(&set_shipment_arrival_time.LoadId, None, &update).await? {
+        Some(shipment) => {
+            record_shipment_event(
+                state,
+                &shipment.LoadId,
+                "shipment_trailer_arrival",
+                &shipment.Status,
+                &shipment.Status,
+                &user.0.username,
+                &*set_shipment_arrival_time,
+            ).await;
+            state.events.publish(Event::ShipmentTrailerArrival(shipment.clone()));
+            Ok(Json(shipment))
         },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
+        None => Err(ApiError::not_found()),
     }
 }
 
@@ -599,438 +325,136 @@ pub async fn set_shipment_trailer(
 pub async fn set_shipment_departure_time(
     set_shipment_departure_time: Json<ShipmentDepartTimeRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.DepartTime = $DepartTime,
-            s.Status = 'COMPLETE',
-            s.Seal = $Seal
-        RETURN s
-    ")
-    .param("LoadId", set_shipment_departure_time.LoadId.clone())
-    .param("Seal", set_shipment_departure_time.Seal.clone())
-    .param("DepartTime", set_shipment_departure_time.DepartTime.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsWrite>,
+) -> Result<Json<Shipment>, ApiError> {
+    let update = ShipmentUpdate {
+        DepartTime: Some(set_shipment_departure_time.DepartTime.clone()),
+        Seal: Some(set_shipment_departure_time.Seal.clone()),
+        ..ShipmentUpdate::default()
+    };
 
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    transition(
+        state,
+        &set_shipment_departure_time.LoadId,
+        ShipmentStatus::Complete,
+        update,
+        Event::ShipmentDepart,
+        &user,
+        &*set_shipment_departure_time,
+    ).await
 }
 
 #[post("/api/set_shipment_pick_start", format = "json", data = "<set_shipment_pick_start>")]
 pub async fn set_shipment_pick_start(
     set_shipment_pick_start: Json<PickStartRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.Status = 'PICKING',
-            s.Picker = $Picker,
-            s.PickStartTime = $PickStartTime
-        RETURN s
-    ")
-    .param("LoadId", set_shipment_pick_start.LoadId.clone())
-    .param("PickStartTime", set_shipment_pick_start.StartTime.clone())
-    .param("Picker", set_shipment_pick_start.Picker.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            let mut data: Vec<Shipment> = Vec::new();
-            if let Ok(Some(record)) = result.next().await {
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsPick>,
+) -> Result<Json<Shipment>, ApiError> {
+    let update = ShipmentUpdate {
+        Picker: Some(set_shipment_pick_start.Picker.clone()),
+        PickStartTime: Some(set_shipment_pick_start.StartTime.clone()),
+        ..ShipmentUpdate::default()
+    };
 
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    transition(
+        state,
+        &set_shipment_pick_start.LoadId,
+        ShipmentStatus::Picking,
+        update,
+        Event::StartShipmentPick,
+        &user,
+        &*set_shipment_pick_start,
+    ).await
 }
 
 #[post("/api/shipment_pick_finish", format = "json", data = "<shipment_pick_finish>")]
 pub async fn shipment_pick_finish(
     shipment_pick_finish: Json<ShipmentPickFinishRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.Status = 'VERIFICATION',
-            s.PickFinishTime = $FinishTime
-        RETURN s
-    ")
-    .param("LoadId", shipment_pick_finish.LoadId.clone())
-    .param("FinishTime", shipment_pick_finish.FinishTime.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsPick>,
+) -> Result<Json<Shipment>, ApiError> {
+    let update = ShipmentUpdate {
+        PickFinishTime: Some(shipment_pick_finish.FinishTime.clone()),
+        ..ShipmentUpdate::default()
+    };
 
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    transition(
+        state,
+        &shipment_pick_finish.LoadId,
+        ShipmentStatus::Verification,
+        update,
+        Event::FinishShipmentPick,
+        &user,
+        &*shipment_pick_finish,
+    ).await
 }
 
 #[post("/api/shipment_verification", format = "json", data = "<shipment_verification>")]
 pub async fn shipment_verification(
     shipment_verification: Json<VerifiedByRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.Status = 'READY TO LOAD',
-            s.VerifiedBy = $VerifiedBy
-        RETURN s
-    ")
-    .param("LoadId", shipment_verification.LoadId.clone())
-    .param("VerifiedBy", shipment_verification.VerifiedBy.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsPick>,
+) -> Result<Json<Shipment>, ApiError> {
+    let update = ShipmentUpdate {
+        VerifiedBy: Some(shipment_verification.VerifiedBy.clone()),
+        ..ShipmentUpdate::default()
+    };
 
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    transition(
+        state,
+        &shipment_verification.LoadId,
+        ShipmentStatus::ReadyToLoad,
+        update,
+        Event::VerifiedBy,
+        &user,
+        &*shipment_verification,
+    ).await
 }
 
 #[post("/api/shipment_begin_loading", format = "json", data = "<shipment_begin_loading>")]
 pub async fn shipment_begin_loading(
     shipment_begin_loading: Json<ShipmentBeginLoading>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.Status = 'LOADING'
-        RETURN s
-    ")
-    .param("LoadId", shipment_begin_loading.LoadId.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
-
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
-        },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
-    }
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsWrite>,
+) -> Result<Json<Shipment>, ApiError> {
+    transition(
+        state,
+        &shipment_begin_loading.LoadId,
+        ShipmentStatus::Loading,
+        ShipmentUpdate::default(),
+        Event::ShipmentStartLoading,
+        &user,
+        &*shipment_begin_loading,
+    ).await
 }
 
 #[post("/api/shipment_hold", format = "json", data = "<shipment_hold>")]
 pub async fn shipment_hold(
     shipment_hold: Json<ShipmentBeginLoading>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Shipment>, Json<&'static str>> {
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
-
-    let query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})
-        SET s.IsHold = NOT s.IsHold
-        RETURN s
-    ")
-    .param("LoadId", shipment_hold.LoadId.clone());
-
-    match graph.execute(query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
-
-                let shipment_node: Node = record.get("s").unwrap();
-                let schedule_date: String = shipment_node.get("ScheduleDate").unwrap_or("".to_string());
-                let schedule_time: String = shipment_node.get("ScheduleTime").unwrap_or("".to_string());
-                let arrival_time: String = shipment_node.get("ArrivalTime").unwrap_or("".to_string());
-                let depart_time: String = shipment_node.get("DepartTime").unwrap_or("".to_string());
-                let dock: String = shipment_node.get("Dock").unwrap_or("".to_string());
-                let door: String = shipment_node.get("Door").unwrap_or("".to_string());
-                let load_id: String = shipment_node.get("LoadId").unwrap_or("".to_string());
-                let load_num: String = shipment_node.get("LoadNum").unwrap_or("".to_string());
-                let status: String = shipment_node.get("Status").unwrap_or("".to_string());
-                let picker: String = shipment_node.get("Picker").unwrap_or("".to_string());
-                let pick_start_time: String = shipment_node.get("PickStartTime").unwrap_or("".to_string());
-                let pick_finish_time: String = shipment_node.get("PickFinishTime").unwrap_or("".to_string());
-                let verified_by: String = shipment_node.get("VerifiedBy").unwrap_or("".to_string());
-                let trailer_num: String = shipment_node.get("TrailerNum").unwrap_or("".to_string());
-                let is_hold: bool = shipment_node.get("IsHold").unwrap_or(false);
-                let seal: String = shipment_node.get("Seal").unwrap_or("".to_string());
-                let shipment = Shipment {
-                        ScheduleDate: schedule_date,
-                        ScheduleTime: schedule_time,
-                        ArrivalTime: arrival_time,
-                        DepartTime: depart_time,
-                        Dock: dock,	
-                        Door: door,
-                        LoadId: load_id,
-                        LoadNum: load_num,
-                        Status: status,
-                        Picker: picker,
-                        PickStartTime: pick_start_time,
-                        PickFinishTime: pick_finish_time,
-                        VerifiedBy: verified_by,
-                        TrailerNum: trailer_num,
-                        IsHold: is_hold,
-                        Seal: seal,
-                    };
-                    Ok(Json(shipment))
-            } else {
-                Err(Json("No record found"))
-            }
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsWrite>,
+) -> Result<Json<Shipment>, ApiError> {
+    match state.shipments.toggle_hold(&shipment_hold.LoadId).await? {
+        Some(shipment) => {
+            record_shipment_event(
+                state,
+                &shipment.LoadId,
+                "shipment_hold",
+                &shipment.Status,
+                &shipment.Status,
+                &user.0.username,
+                &*shipment_hold,
+            ).await;
+            state.events.publish(Event::ShipmentHold(shipment.clone()));
+            Ok(Json(shipment))
         },
-        Err(e) => {
-            println!("Failed to run query: {:?}", e);
-            Err(Json("Internal Server Error"))
-        }
+        None => Err(ApiError::not_found()),
     }
 }
 
@@ -1038,79 +462,66 @@ pub async fn shipment_hold(
 pub async fn shipment_lines(
     shipment_lines: Json<ShipmentLinesRequest>,
     state: &State<AppState>,
-    _user: AuthenticatedUser,
-    role: Role,
-) -> Result<Json<Vec<ShipmentLine>>, Json<&'static str>> {
-    
-    if role.0 != "write" && role.0 != "admin" {
-        return Err(Json("Forbidden"));
-    }
-
-    let graph = &state.graph;
+    user: AuthenticatedUser,
+    _perm: Can<ShipmentsWrite>,
+) -> Result<Json<Vec<ShipmentLine>>, ApiError> {
     let mut lines = shipment_lines.Lines.clone();
     // Retain only lines with non-zero quantity.
     lines.retain(|line| line.quantity != 0);
-    println!("{:?}", shipment_lines.clone());
 
-    // Delete existing shipment lines.
-    let delete_query = query("
-        MATCH (s:Shipment {LoadId: $LoadId})-[:HAS_LINE]->(sl:ShipmentLine)
-        WITH sl
-        DETACH DELETE sl
-        RETURN COUNT(sl) as count
-    ")
-    .param("LoadId", shipment_lines.LoadId.clone());
-
-    match graph.execute(delete_query).await {
-        Ok(mut result) => {
-            if let Ok(Some(record)) = result.next().await {
-                let count: u32 = record.get("count").unwrap_or(0);
-                println!("{}",count);
-            }
-        },
-        Err(e) => {
-            println!("{:?}",e);
-        }
+    let created_lines = state.shipments.replace_lines(&shipment_lines.LoadId, &lines).await?;
+
+    if let Ok(Some((current, _))) = state.shipments.state(&shipment_lines.LoadId).await {
+        record_shipment_event(
+            state,
+            &shipment_lines.LoadId,
+            "shipment_lines",
+            &current,
+            &current,
+            &user.0.username,
+            &created_lines,
+        ).await;
+        state.events.publish(Event::ShipmentLines(ShipmentLinesRequest {
+            LoadId: shipment_lines.LoadId.clone(),
+            Lines: created_lines.clone(),
+        }));
     }
-    // Collect created shipment lines.
-    let mut created_lines = Vec::new();
 
-    // For each new line, create it and return the created node.
-    for line in lines {
-        let create_query = query("
-            MATCH (s:Shipment {LoadId: $LoadId})
-            CREATE (s)-[:HAS_LINE]->(sl:ShipmentLine {
-                PartNumber: $item,
-                Quantity: $quantity,
-                Ip: $ip
-            })
-            RETURN sl.PartNumber as item, sl.Quantity as quantity, sl.Ip as ip
-        ")
-        .param("item", line.item.clone())
-        .param("quantity", line.quantity.clone())
-        .param("LoadId", shipment_lines.LoadId.clone())
-        .param("ip", line.ip.clone());
+    Ok(Json(created_lines))
+}
 
-        match graph.execute(create_query).await {
-            Ok(mut result) => {
-                if let Ok(Some(record)) = result.next().await {
-                    let item: String = record.get("item").unwrap_or("".to_string());
-                    let quantity: u32 = record.get("quantity").unwrap();
-                    let ip: String = record.get("ip").unwrap_or("".to_string());
-                    let line = ShipmentLine {
-                        item,
-                        quantity,
-                        ip,
-                    };
-                    created_lines.push(line);
-                }
-            },
-            Err(e) => {
-                println!("{:?}",e);
-            }
-        }
-    }
+#[post("/api/shipment_status_override", format = "json", data = "<status_override>")]
+pub async fn shipment_status_override(
+    status_override: Json<ShipmentStatusOverrideRequest>,
+    state: &State<AppState>,
+    user: AuthenticatedUser,
+    _role: RequireAdmin,
+) -> Result<Json<Shipment>, ApiError> {
+    let from = match state.shipments.state(&status_override.LoadId).await? {
+        Some((current, _)) => current,
+        None => return Err(ApiError::not_found()),
+    };
 
-    // Return the created shipment lines as JSON.
-    Ok(Json(created_lines))
-}
\ No newline at end of file
+    // Admin escape hatch: skips the transition table and the hold check.
+    let update = ShipmentUpdate {
+        Status: Some(status_override.Status.as_str().to_string()),
+        ..ShipmentUpdate::default()
+    };
+
+    match state.shipments.update(&status_override.LoadId, None, &update).await? {
+        Some(shipment) => {
+            record_shipment_event(
+                state,
+                &shipment.LoadId,
+                "shipment_status_override",
+                &from,
+                &shipment.Status,
+                &user.0.username,
+                &*status_override,
+            ).await;
+            state.events.publish(Event::ShipmentStatusOverride(shipment.clone()));
+            Ok(Json(shipment))
+        },
+        None => Err(ApiError::not_found()),
+    }
+}
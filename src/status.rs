@@ -0,0 +1,132 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+/*
+    Shipment lifecycle
+
+    NOT STARTED -> PICKING -> VERIFICATION -> READY TO LOAD -> LOADING -> COMPLETE
+
+    Any move not listed in TRANSITIONS is rejected, and a shipment on hold cannot
+    change status (re-saving a NOT STARTED shipment is still allowed). Admins can
+    bypass both checks through /api/shipment_status_override.
+*/
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShipmentStatus {
+    #[serde(rename = "NOT STARTED")]
+    NotStarted,
+    #[serde(rename = "PICKING")]
+    Picking,
+    #[serde(rename = "VERIFICATION")]
+    Verification,
+    #[serde(rename = "READY TO LOAD")]
+    ReadyToLoad,
+    #[serde(rename = "LOADING")]
+    Loading,
+    #[serde(rename = "COMPLETE")]
+    Complete,
+}
+
+pub const TRANSITIONS: &[(ShipmentStatus, ShipmentStatus)] = &[
+    (ShipmentStatus::NotStarted, ShipmentStatus::NotStarted),
+    (ShipmentStatus::NotStarted, ShipmentStatus::Picking),
+    (ShipmentStatus::Picking, ShipmentStatus::Verification),
+    (ShipmentStatus::Verification, ShipmentStatus::ReadyToLoad),
+    (ShipmentStatus::ReadyToLoad, ShipmentStatus::Loading),
+    (ShipmentStatus::Loading, ShipmentStatus::Complete),
+];
+
+impl ShipmentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShipmentStatus::NotStarted => "NOT STARTED",
+            ShipmentStatus::Picking => "PICKING",
+            ShipmentStatus::Verification => "VERIFICATION",
+            ShipmentStatus::ReadyToLoad => "READY TO LOAD",
+            ShipmentStatus::Loading => "LOADING",
+            ShipmentStatus::Complete => "COMPLETE",
+        }
+    }
+
+    pub fn can_transition_to(&self, next: ShipmentStatus) -> bool {
+        TRANSITIONS.contains(&(*self, next))
+    }
+}
+
+impl fmt::Display for ShipmentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ShipmentStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            // Shipments created before Status was always set read back as ''.
+            "" | "NOT STARTED" => Ok(ShipmentStatus::NotStarted),
+            "PICKING" => Ok(ShipmentStatus::Picking),
+            "VERIFICATION" => Ok(ShipmentStatus::Verification),
+            "READY TO LOAD" => Ok(ShipmentStatus::ReadyToLoad),
+            "LOADING" => Ok(ShipmentStatus::Loading),
+            "COMPLETE" => Ok(ShipmentStatus::Complete),
+            _ => Err(()),
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+pub struct TransitionError {
+    pub code: &'static str,
+    pub message: String,
+    pub LoadId: String,
+    pub from: String,
+    pub to: String,
+    pub IsHold: bool,
+}
+
+/// Checks a move from the stored `Status`/`IsHold` of a shipment to `next`.
+pub fn check_transition(
+    load_id: &str,
+    current: &str,
+    is_hold: bool,
+    next: ShipmentStatus,
+) -> Result<(), TransitionError> {
+    let error = |code: &'static str, message: String| TransitionError {
+        code,
+        message,
+        LoadId: load_id.to_string(),
+        from: current.to_string(),
+        to: next.to_string(),
+        IsHold: is_hold,
+    };
+
+    let from = match current.parse::<ShipmentStatus>() {
+        Ok(from) => from,
+        Err(_) => {
+            return Err(error(
+                "UNKNOWN_STATUS",
+                format!("Shipment {} has unrecognised status '{}'", load_id, current),
+            ))
+        }
+    };
+
+    if is_hold && from != next {
+        return Err(error(
+            "SHIPMENT_ON_HOLD",
+            format!("Shipment {} is on hold and cannot move to {}", load_id, next),
+        ));
+    }
+
+    if !from.can_transition_to(next) {
+        return Err(error(
+            "INVALID_TRANSITION",
+            format!("Shipment {} cannot move from {} to {}", load_id, from, next),
+        ));
+    }
+
+    Ok(())
+}
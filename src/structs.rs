@@ -1,11 +1,67 @@
+// Field names mirror the Neo4j properties and the JSON the front ends send.
+#![allow(non_snake_case)]
+
 use serde::{Deserialize, Serialize};
-use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tokio::sync::{mpsc::Sender, Mutex, Notify};
 use tokio_tungstenite::tungstenite::protocol::Message;
 use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use neo4rs::Graph;
+use crate::repository::{CountRepository, ShipmentRepository, Store, TrailerRepository, UserRepository};
+use crate::status::ShipmentStatus;
+use crate::config::{AppConfig, QueuePolicy};
+use crate::auth::KeyRing;
+use crate::events::EventBus;
+use crate::wsprotocol::Topic;
+use crate::wsserver::ReplayBuffer;
+
+
+/// A signed-in websocket connection, the user it belongs to and what it gets sent.
+pub struct WsClient {
+    pub username: String,
+    pub role: String,
+    pub topics: Vec<Topic>,
+    /// From the role's trailers:read and shipments:read, whatever the topics say.
+    pub reads_trailers: bool,
+    pub reads_shipments: bool,
+    /// The last broadcast before this client joined; it gets everything after live.
+    pub joined_after_seq: u64,
+    pub sender: Sender<Message>,
+    pub queue_policy: QueuePolicy,
+    /// Messages skipped because the queue was full, under QueuePolicy::Drop.
+    pub dropped: u64,
+    /// Tells the connection to close, when its queue is full under QueuePolicy::Disconnect.
+    pub kick: Arc<Notify>,
+    /// Live events kept back until the client resumes or its resume window closes.
+    pub held: Option<Vec<Message>>,
+}
+
+impl WsClient {
+    pub fn queue_depth(&self) -> usize {
+        let held = self.held.as_ref().map_or(0, Vec::len);
+        self.sender.max_capacity() - self.sender.capacity() + held
+    }
+}
 
+#[derive(Serialize, Debug)]
+pub struct WebSocketStats {
+    pub connections: usize,
+    pub queue_capacity: usize,
+    pub queue_policy: QueuePolicy,
+    pub stream: String,
+    pub last_seq: u64,
+    pub clients: Vec<WebSocketClientStats>,
+}
 
-pub type WebSocketList = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Message>>>>;
+#[derive(Serialize, Debug)]
+pub struct WebSocketClientStats {
+    pub peer_addr: SocketAddr,
+    pub username: String,
+    pub role: String,
+    pub topics: Vec<Topic>,
+    pub queue_depth: usize,
+    pub dropped: u64,
+}
+
+pub type WebSocketList = Arc<Mutex<HashMap<SocketAddr, WsClient>>>;
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
@@ -98,7 +154,7 @@ pub struct RefreshRequest {
     pub refresh_token: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Count {
     pub item: String,
     pub location: String,
@@ -110,7 +166,7 @@ pub struct Count {
     pub date: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DeleteShipmentRequest {
     pub LoadId: String,
 }
@@ -126,17 +182,6 @@ pub struct CountSummary {
     pub date: String,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct IncomingMessage {
-    pub r#type: String,
-    pub data: MessageData,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct MessageData {
-    pub message: String,
-}
-
 #[derive(Deserialize, Debug)]
 pub struct SetScheduleRequest {
     pub TrailerID: String,
@@ -157,7 +202,7 @@ pub struct SetArrivalTimeRequest {
     pub ArrivalTime: String,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TrailerSchedule {
     pub TrailerID: String,
     pub Schedule: Schedule,
@@ -226,44 +271,80 @@ pub struct Shipment {
     pub Seal: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ShipmentArrivalTimeRequest {
     pub ArrivalTime: String,
     pub LoadId: String,
     pub TrailerNum: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ShipmentDepartTimeRequest {
     pub DepartTime: String,
     pub LoadId: String,
     pub Seal: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct VerifiedByRequest {
     pub VerifiedBy: String,
     pub LoadId: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ShipmentPickFinishRequest {
     pub LoadId: String,
     pub FinishTime: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ShipmentDoor {
     pub LoadId: String,
     pub Door: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct ShipmentBeginLoading {
     pub LoadId: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
+pub struct ScheduleChangeRequest {
+    pub TrailerID: Option<String>,
+    pub user: Option<String>,
+    pub date1: Option<String>,
+    pub date2: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScheduleChange {
+    pub TrailerID: String,
+    pub event: String,
+    pub field: String,
+    pub old: String,
+    pub new: String,
+    pub user: String,
+    pub at: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShipmentEvent {
+    pub LoadId: String,
+    pub event: String,
+    pub from: String,
+    pub to: String,
+    pub actor: String,
+    pub at: String,
+    pub payload: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShipmentStatusOverrideRequest {
+    pub LoadId: String,
+    pub Status: ShipmentStatus,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct PickStartRequest {
     pub StartTime: String,
     pub LoadId: String,
@@ -277,12 +358,94 @@ pub struct Sid {
 }
 
 pub struct AppState {
-    pub graph: Arc<Graph>,
-    pub jwt_secret: String,
+    pub trailers: Arc<dyn TrailerRepository>,
+    pub shipments: Arc<dyn ShipmentRepository>,
+    pub counts: Arc<dyn CountRepository>,
+    pub users: Arc<dyn UserRepository>,
+    pub jwt_keys: KeyRing,
     pub ws_list: WebSocketList,
+    pub events: EventBus,
+    pub ws_replay: ReplayBuffer,
+    pub config: AppConfig,
+}
+
+impl AppState {
+    pub fn new<S: Store + 'static>(config: AppConfig, store: Arc<S>) -> Self {
+        AppState {
+            trailers: store.clone(),
+            shipments: store.clone(),
+            counts: store.clone(),
+            users: store,
+            ws_list: Arc::new(Mutex::new(HashMap::new())),
+            events: EventBus::new(config.event_bus_capacity),
+            ws_replay: ReplayBuffer::new(config.ws_replay_buffer),
+            jwt_keys: KeyRing::from_config(&config),
+            config,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct SidsRequest {
     pub date: String,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleRequest {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoleResponse {
+    pub name: String,
+    pub builtin: bool,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserDetails {
+    pub username: String,
+    pub role: String,
+    pub disabled: bool,
+    pub must_reset_password: bool,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateRoleRequest {
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub temporary_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub username: String,
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    pub username: String,
+    pub password: String,
+    pub invite_code: Option<String>,
+    pub role: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    pub role: String,
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub role: String,
+    pub created_by: String,
+    pub expires: i64,
+}
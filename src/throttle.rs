@@ -0,0 +1,36 @@
+use std::net::IpAddr;
+use crate::config::AppConfig;
+use crate::repository::ThrottlePolicy;
+
+/*
+    Login throttling
+
+    Failed logins are counted per submitted username and per client IP through the
+    user repository: as (:LoginThrottle {key}) nodes in Neo4j, so they survive a
+    restart, or in memory with MemoryStore. Once a key reaches its limit it is
+    locked for login_lockout_secs, doubling with every further failure up to
+    login_lockout_max_secs. A key that stays quiet for login_lockout_max_secs
+    after its last failure or lockout starts again from zero. An admin's unlock
+    clears the username's key, and the IP's key when given one.
+
+    Usernames that do not exist are counted the same way, so a lockout does not
+    reveal whether an account exists. The IP limit is much higher than the user
+    limit because the floor terminals share a network.
+*/
+
+pub fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+pub fn ip_key(ip: &IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// The lockout settings for `key`: IP keys get the much higher IP limit.
+pub fn policy(key: &str, config: &AppConfig) -> ThrottlePolicy {
+    ThrottlePolicy {
+        max_attempts: if key.starts_with("ip:") { config.login_ip_max_attempts } else { config.login_max_attempts },
+        lockout_secs: config.login_lockout_secs as i64,
+        lockout_max_secs: config.login_lockout_max_secs as i64,
+    }
+}
@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::events::Event;
+use crate::structs::{DeleteShipmentRequest, Shipment, TrailerSchedule};
+
+/*
+    Websocket protocol
+
+    Every frame, both ways, is a JSON object with the protocol version, a type
+    and the data for that type:
+
+        {"v": 1, "type": "set_door", "data": {"TrailerID": "TRL-100", "Schedule": {...}}}
+
+    Clients send WsEvents: `auth`, `subscribe`, `unsubscribe` and `resume`, and
+    the events they relay to the other screens, which carry the same payloads the
+    server publishes under those names. The server sends ServerMessages: a
+    `welcome` once signed in, events, the client's `subscriptions` after each
+    change, the outcome of a `resume`, and an `error` frame in reply to anything
+    it won't accept. Bump PROTOCOL_VERSION for any change an older client would
+    misread.
+
+    Events also carry `seq`, counting up from 1 across all broadcasts of one
+    server run, which `stream` identifies. A client only sees the seqs matching
+    its subscriptions, so gaps are normal.
+*/
+
+pub const PROTOCOL_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WsEvent {
+    Auth(AuthRequest),
+    Subscribe(Topics),
+    Unsubscribe(Topics),
+    Resume(ResumeRequest),
+    HotTrailer(TrailerSchedule),
+    ScheduleTrailer(TrailerSchedule),
+    SetDoor(TrailerSchedule),
+    TrailerArrived(TrailerSchedule),
+    ShipmentTrailerArrival(Shipment),
+    SetShipmentDoor(Shipment),
+    StartShipmentPick(Shipment),
+    FinishShipmentPick(Shipment),
+    NewShipment(Shipment),
+    ShipmentDepart(Shipment),
+    ShipmentStartLoading(Shipment),
+    DeleteShipment(DeleteShipmentRequest),
+    ShipmentHold(Shipment),
+    VerifiedBy(Shipment),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuthRequest {
+    pub token: String,
+}
+
+/// What a client gets events about, e.g. `"trailers"` or `{"door": "12"}`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// Every trailer event.
+    Trailers,
+    /// Every shipment event.
+    Shipments,
+    LoadId(String),
+    /// Trailers and shipments at this door.
+    Door(String),
+    /// Trailers and shipments scheduled on this date.
+    Date(String),
+}
+
+impl Topic {
+    pub fn matches(&self, event: &Event) -> bool {
+        match self {
+            Topic::Trailers => event.is_trailer_event(),
+            Topic::Shipments => !event.is_trailer_event(),
+            Topic::LoadId(load_id) => event.load_id() == Some(load_id.as_str()),
+            Topic::Door(door) => event.door() == Some(door.as_str()),
+            Topic::Date(date) => event.schedule_date() == Some(date.as_str()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Topics {
+    pub topics: Vec<Topic>,
+}
+
+/// The last event a client saw before it lost its connection.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResumeRequest {
+    pub stream: String,
+    pub last_seq: u64,
+}
+
+impl WsEvent {
+    /// Reads one text frame, or the error to send back to the client.
+    pub fn parse(text: &str) -> Result<WsEvent, WsError> {
+        let frame: Value = serde_json::from_str(text)
+            .map_err(|e| WsError::new("BAD_MESSAGE", format!("Message is not JSON: {}", e)))?;
+        if frame.get("v").and_then(Value::as_u64) != Some(PROTOCOL_VERSION) {
+            return Err(WsError::new("UNSUPPORTED_VERSION", format!("Expected \"v\": {}", PROTOCOL_VERSION)));
+        }
+        WsEvent::deserialize(&frame).map_err(|e| {
+            let message = e.to_string();
+            if message.starts_with("unknown variant") {
+                WsError::new("UNKNOWN_TYPE", message)
+            } else {
+                WsError::new("BAD_MESSAGE", message)
+            }
+        })
+    }
+
+    /// The server event a client is relaying, None for auth, subscriptions and resume.
+    pub fn into_event(self) -> Option<Event> {
+        let event = match self {
+            WsEvent::Auth(_) | WsEvent::Subscribe(_) | WsEvent::Unsubscribe(_) | WsEvent::Resume(_) => return None,
+            WsEvent::HotTrailer(schedule) => Event::HotTrailer(schedule),
+            WsEvent::ScheduleTrailer(schedule) => Event::ScheduleTrailer(schedule),
+            WsEvent::SetDoor(schedule) => Event::SetDoor(schedule),
+            WsEvent::TrailerArrived(schedule) => Event::TrailerArrived(schedule),
+            WsEvent::ShipmentTrailerArrival(shipment) => Event::ShipmentTrailerArrival(shipment),
+            WsEvent::SetShipmentDoor(shipment) => Event::SetShipmentDoor(shipment),
+            WsEvent::StartShipmentPick(shipment) => Event::StartShipmentPick(shipment),
+            WsEvent::FinishShipmentPick(shipment) => Event::FinishShipmentPick(shipment),
+            WsEvent::NewShipment(shipment) => Event::NewShipment(shipment),
+            WsEvent::ShipmentDepart(shipment) => Event::ShipmentDepart(shipment),
+            WsEvent::ShipmentStartLoading(shipment) => Event::ShipmentStartLoading(shipment),
+            WsEvent::DeleteShipment(request) => Event::DeleteShipment(request),
+            WsEvent::ShipmentHold(shipment) => Event::ShipmentHold(shipment),
+            WsEvent::VerifiedBy(shipment) => Event::VerifiedBy(shipment),
+        };
+        Some(event)
+    }
+}
+
+/// Sent back to a client in place of a message it got wrong, with a code like
+/// the API's: BAD_MESSAGE, UNSUPPORTED_VERSION, UNKNOWN_TYPE or FORBIDDEN.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WsError {
+    pub code: String,
+    pub message: String,
+}
+
+impl WsError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        WsError { code: code.to_string(), message: message.into() }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome(Welcome),
+    Subscriptions(Topics),
+    /// Sent after the missed events.
+    Resumed(Resumed),
+    /// The missed events are no longer kept, or are from another server run.
+    /// Reload everything over the API, and resume from `seq` next time.
+    ResyncRequired(StreamPosition),
+    Error(WsError),
+    /// Events keep their own type names.
+    #[serde(untagged)]
+    Event(Box<Event>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Welcome {
+    pub username: String,
+    pub role: String,
+    pub stream: String,
+    /// The last event broadcast before this connection joined.
+    pub seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Resumed {
+    pub replayed: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamPosition {
+    pub stream: String,
+    pub seq: u64,
+}
+
+#[derive(Serialize)]
+struct Frame<'a> {
+    v: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relayed_by: Option<&'a str>,
+    #[serde(flatten)]
+    message: &'a ServerMessage,
+}
+
+impl ServerMessage {
+    pub fn to_text(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&Frame { v: PROTOCOL_VERSION, seq: None, relayed_by: None, message: self })
+    }
+
+    /// For broadcasts, which are numbered.
+    pub fn to_text_at(&self, seq: u64) -> serde_json::Result<String> {
+        serde_json::to_string(&Frame { v: PROTOCOL_VERSION, seq: Some(seq), relayed_by: None, message: self })
+    }
+
+    /// For events a client sent, which are passed on unnumbered.
+    pub fn to_text_relayed_by(&self, username: &str) -> serde_json::Result<String> {
+        serde_json::to_string(&Frame { v: PROTOCOL_VERSION, seq: None, relayed_by: Some(username), message: self })
+    }
+}
@@ -1,162 +1,823 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use futures_util::{StreamExt, SinkExt};
 use rocket::{get, State};
-use tokio::net::TcpListener;
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
-use crate::structs::{AppState, IncomingMessage, WebSocketList};
+use rocket::fairing::AdHoc;
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::Status;
+use rocket::http::uri::Origin;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use tokio::sync::{mpsc, Notify};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{interval_at, sleep, timeout, Instant};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{self, CloseFrame, Message};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use uuid::Uuid;
+use crate::auth::{current_claims, decode_token, Claims, KeyRing, TokenType};
+use crate::config::{AppConfig, QueuePolicy};
+use crate::error::ApiError;
+use crate::events::{Event, EventBus};
+use crate::permission::{
+    DoorsWrite, Permission, ScheduleWrite, ShipmentsDelete, ShipmentsPick, ShipmentsRead, ShipmentsWrite, TrailersRead,
+};
+use crate::repository::UserRepository;
+use crate::role::Role;
+use crate::structs::{AppState, WebSocketClientStats, WebSocketList, WebSocketStats, WsClient};
+use crate::wsprotocol::{ResumeRequest, Resumed, ServerMessage, StreamPosition, Topic, Topics, Welcome, WsError, WsEvent};
 
-#[get("/ws")]
-pub async fn ws_handler(state: &State<AppState>) -> Result<(), rocket::http::Status> {
-    let ws_list = state.ws_list.clone();
-    tokio::spawn(async move {
-        if let Err(e) = run_ws_server(ws_list).await {
-            println!("Error in WebSocket server: {}", e);
+/*
+    Replay
+
+    The broadcaster numbers every event and keeps the last `ws_replay_buffer` of
+    them. A client that reconnects sends the stream and last seq it saw, and gets
+    the events it missed in order, before anything newer. It is told to resync
+    instead when those are gone or came from an earlier server run.
+
+    To keep that order, live events for a new client are held back until it has
+    resumed, or `ws_resume_window_ms` after the welcome if it doesn't. A resume
+    after that, or a second one, is refused: the events it would replay could
+    come after newer ones the client already has.
+
+    If the broadcaster falls more than `event_bus_capacity` behind, the bus drops
+    events before they are numbered. Their seqs are skipped all the same, so no
+    resume reaches across them, and every client is told to resync.
+
+    Both sides lock ws_list before the buffer, so a joining or resuming client
+    sees every seq exactly once: either replayed, or live through its channel.
+*/
+
+#[derive(Clone)]
+pub struct ReplayBuffer {
+    stream: String,
+    inner: Arc<std::sync::Mutex<Replayed>>,
+}
+
+struct Replayed {
+    last_seq: u64,
+    capacity: usize,
+    /// (seq, event and frame), oldest first. None for seqs lost to a lagging broadcaster.
+    events: VecDeque<(u64, Option<(Event, String)>)>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        let replayed = Replayed { last_seq: 0, capacity, events: VecDeque::with_capacity(capacity) };
+        ReplayBuffer { stream: Uuid::new_v4().to_string(), inner: Arc::new(std::sync::Mutex::new(replayed)) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Replayed> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn position(&self) -> StreamPosition {
+        StreamPosition { stream: self.stream.clone(), seq: self.lock().last_seq }
+    }
+
+    /// Numbers the event and keeps it, evicting the oldest when full. Returns the frame.
+    fn record(&self, event: &Event) -> serde_json::Result<String> {
+        let mut replayed = self.lock();
+        let seq = replayed.last_seq + 1;
+        let text = ServerMessage::Event(Box::new(event.clone())).to_text_at(seq)?;
+        replayed.push(seq, Some((event.clone(), text.clone())));
+        Ok(text)
+    }
+
+    /// Uses up seqs for events that never reached the broadcaster.
+    fn skip(&self, missed: u64) -> StreamPosition {
+        let mut replayed = self.lock();
+        let first = replayed.last_seq + 1;
+        // Anything kept from before the gap would be evicted anyway.
+        let kept = missed.min(replayed.capacity as u64);
+        for seq in first + missed - kept..first + missed {
+            replayed.push(seq, None);
         }
-    });
+        replayed.last_seq = first + missed - 1;
+        StreamPosition { stream: self.stream.clone(), seq: replayed.last_seq }
+    }
 
-    Ok(())
+    /// Events after `after` up to and including `until`, or None if any of them
+    /// were evicted or `after` is from the future.
+    fn between(&self, after: u64, until: u64) -> Option<Vec<(Event, String)>> {
+        let replayed = self.lock();
+        if after > replayed.last_seq {
+            return None;
+        }
+        if after >= until {
+            return Some(Vec::new());
+        }
+        let oldest = replayed.events.front().map(|(seq, _)| *seq)?;
+        if after + 1 < oldest {
+            return None;
+        }
+        replayed.events.iter()
+            .filter(|(seq, _)| *seq > after && *seq <= until)
+            .map(|(_, kept)| kept.clone())
+            .collect()
+    }
+}
+
+impl Replayed {
+    fn push(&mut self, seq: u64, kept: Option<(Event, String)>) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back((seq, kept));
+        self.last_seq = seq;
+    }
+}
+
+/// Numbers every event published on the bus and sends it to the clients subscribed
+/// to it. Runs for the life of the server, started once at liftoff.
+pub async fn broadcast_events(events: EventBus, ws_list: WebSocketList, replay: ReplayBuffer) {
+    let mut receiver = events.subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                println!("Websocket broadcaster fell behind, {} events were not sent", missed);
+                let mut ws_list = ws_list.lock().await;
+                let resync = ServerMessage::ResyncRequired(replay.skip(missed));
+                let text = resync.to_text().expect("resync serializes");
+                let gone: Vec<_> = ws_list.iter_mut()
+                    .filter_map(|(peer_addr, client)| (!client.deliver_live(Message::Text(text.clone()))).then_some(*peer_addr))
+                    .collect();
+                for peer_addr in gone {
+                    ws_list.remove(&peer_addr);
+                }
+                continue;
+            },
+            Err(RecvError::Closed) => break,
+        };
+
+        let mut ws_list = ws_list.lock().await;
+        let text = match replay.record(&event) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("Failed to serialize {} event: {}", event.name(), e);
+                continue;
+            },
+        };
+        let sent = send_to_subscribers(&mut ws_list, &event, &text);
+        println!("Broadcasting {} to {} of {} clients", event.name(), sent, ws_list.len());
+    }
+}
+
+/// Passes on an event a client sent. It never went through the store, so it is
+/// marked with who sent it and has no seq: it is not replayed, and it doesn't
+/// count against the stream position clients resume from.
+async fn relay(ws_list: &WebSocketList, username: &str, event: &Event) {
+    let text = match ServerMessage::Event(Box::new(event.clone())).to_text_relayed_by(username) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("Failed to serialize {} event: {}", event.name(), e);
+            return;
+        },
+    };
+    let mut ws_list = ws_list.lock().await;
+    let sent = send_to_subscribers(&mut ws_list, event, &text);
+    println!("Relaying {} from {} to {} of {} clients", event.name(), username, sent, ws_list.len());
+}
+
+/// Queues the frame for every client that wants the event, dropping the ones that
+/// have fallen too far behind. Returns how many it was queued for.
+fn send_to_subscribers(ws_list: &mut HashMap<SocketAddr, WsClient>, event: &Event, text: &str) -> usize {
+    let mut sent = 0;
+    let mut gone = Vec::new();
+    for (peer_addr, client) in ws_list.iter_mut().filter(|(_, client)| client.wants(event)) {
+        sent += 1;
+        if !client.deliver_live(Message::Text(text.to_string())) {
+            println!("Disconnecting {} ({}), its queue is full", client.username, peer_addr);
+            gone.push(*peer_addr);
+        }
+    }
+    for peer_addr in gone {
+        ws_list.remove(&peer_addr);
+    }
+    sent
 }
 
-async fn run_ws_server(ws_list: WebSocketList) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind("0.0.0.0:9001").await?;
-    println!("WebSocket server listening on ws://0.0.0.0:9001");
+impl WsClient {
+    fn wants(&self, event: &Event) -> bool {
+        let readable = if event.is_trailer_event() { self.reads_trailers } else { self.reads_shipments };
+        readable && self.topics.iter().any(|topic| topic.matches(event))
+    }
+
+    /// Queues a message without waiting on the client. False means it has to go:
+    /// its queue is full under QueuePolicy::Disconnect, or it is already closing.
+    fn deliver(&mut self, message: Message) -> bool {
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) if self.queue_policy == QueuePolicy::Drop => {
+                self.dropped += 1;
+                true
+            },
+            Err(TrySendError::Full(_)) => {
+                self.kick.notify_one();
+                false
+            },
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
 
-    while let Ok((stream, _)) = listener.accept().await {
-        let peer_addr = stream.peer_addr().expect("connected streams should have a peer address");
-        println!("Accepted connection from {}", peer_addr);
-        let ws_list_inner = ws_list.clone();
-        tokio::spawn(handle_connection(stream, peer_addr, ws_list_inner));
+    /// Like deliver, but held back while the client may still resume. The held
+    /// events count against the same queue capacity.
+    fn deliver_live(&mut self, message: Message) -> bool {
+        let room = self.sender.capacity();
+        match &mut self.held {
+            Some(held) if held.len() < room => {
+                held.push(message);
+                true
+            },
+            Some(_) if self.queue_policy == QueuePolicy::Drop => {
+                self.dropped += 1;
+                true
+            },
+            Some(_) => {
+                self.kick.notify_one();
+                false
+            },
+            None => self.deliver(message),
+        }
     }
 
-    Ok(())
+    /// Ends the resume window and queues what was held back. False as for deliver.
+    fn release(&mut self) -> bool {
+        self.held.take().unwrap_or_default().into_iter().all(|message| self.deliver(message))
+    }
 }
 
-async fn handle_connection(
-    stream: tokio::net::TcpStream,
+/// Connection counts and queue depths for GET /api/admin/websockets.
+pub async fn stats(state: &AppState) -> WebSocketStats {
+    let ws_list = state.ws_list.lock().await;
+    let position = state.ws_replay.position();
+    let clients = ws_list.iter().map(|(peer_addr, client)| WebSocketClientStats {
+        peer_addr: *peer_addr,
+        username: client.username.clone(),
+        role: client.role.clone(),
+        topics: client.topics.clone(),
+        queue_depth: client.queue_depth(),
+        dropped: client.dropped,
+    }).collect();
+
+    WebSocketStats {
+        connections: ws_list.len(),
+        queue_capacity: state.config.ws_queue_capacity,
+        queue_policy: state.config.ws_queue_policy,
+        stream: position.stream,
+        last_seq: position.seq,
+        clients,
+    }
+}
+
+/*
+    Websocket upgrade
+
+    GET /ws with the usual handshake headers is answered with 101 Switching
+    Protocols on the Rocket port, so it goes through the same CORS and request
+    fairings as the API. Rocket hands the upgraded connection to the channel,
+    which joins it to the shared client list.
+*/
+
+pub struct WebSocketUpgrade {
+    key: String,
     peer_addr: SocketAddr,
+    token: Option<String>,
+    subprotocol: bool,
+}
+
+/// Subprotocol a client names ahead of its access token in Sec-WebSocket-Protocol.
+const TOKEN_SUBPROTOCOL: &str = "access_token";
+
+/// The ?token= taken off a /ws request before Rocket logs it.
+struct QueryToken(Option<String>);
+
+/// Moves ?token= out of a /ws request's URI into the request, so the access token
+/// doesn't end up in the log line Rocket writes for every request.
+pub fn token_fairing() -> AdHoc {
+    AdHoc::on_request("Websocket token", |request, _| Box::pin(async move {
+        if request.uri().path() != "/ws" {
+            return;
+        }
+        let Some(query) = request.uri().query() else { return };
+        let mut token = None;
+        let mut rest = Vec::new();
+        for (raw, (name, value)) in query.raw_segments().zip(query.segments()) {
+            if name == "token" {
+                token = Some(value.to_string());
+            } else {
+                rest.push(raw.as_str());
+            }
+        }
+        if token.is_none() {
+            return;
+        }
+        let uri = match rest.is_empty() {
+            true => "/ws".to_string(),
+            false => format!("/ws?{}", rest.join("&")),
+        };
+        if let Ok(uri) = Origin::parse_owned(uri) {
+            request.set_uri(uri);
+        }
+        request.local_cache(|| QueryToken(token));
+    }))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocketUpgrade {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let has_token = |name: &str, token: &str| headers.get(name)
+            .flat_map(|value| value.split(','))
+            .any(|part| part.trim().eq_ignore_ascii_case(token));
+
+        if !has_token("Connection", "upgrade") || !has_token("Upgrade", "websocket") {
+            println!("Rejected /ws request without a websocket upgrade");
+            return Outcome::Error((Status::BadRequest, ()));
+        }
+        if headers.get_one("Sec-WebSocket-Version") != Some("13") {
+            println!("Rejected websocket handshake with an unsupported version");
+            return Outcome::Error((Status::BadRequest, ()));
+        }
+        let (key, peer_addr) = match (headers.get_one("Sec-WebSocket-Key"), request.remote()) {
+            (Some(key), Some(peer_addr)) => (key.to_string(), peer_addr),
+            _ => return Outcome::Error((Status::BadRequest, ())),
+        };
+        // "access_token, <jwt>" in Sec-WebSocket-Protocol, else the ?token= the fairing kept.
+        let mut protocols = headers.get("Sec-WebSocket-Protocol").flat_map(|value| value.split(',')).map(str::trim);
+        let from_header = match (protocols.next(), protocols.next()) {
+            (Some(TOKEN_SUBPROTOCOL), Some(token)) => Some(token.to_string()),
+            _ => None,
+        };
+        let subprotocol = from_header.is_some();
+        let token = from_header.or_else(|| request.local_cache(|| QueryToken(None)).0.clone());
+        Outcome::Success(WebSocketUpgrade { key, peer_addr, token, subprotocol })
+    }
+}
+
+pub struct WebSocketChannel {
+    upgrade: WebSocketUpgrade,
+    session: Option<Session>,
+    authenticator: Authenticator,
     ws_list: WebSocketList,
-) {
-    let ws_stream = accept_async(stream).await.expect("Error during the websocket handshake occurred");
+    replay: ReplayBuffer,
+    settings: ConnectionSettings,
+}
+
+impl<'r> Responder<'r, 'static> for WebSocketChannel {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.raw_header("Sec-WebSocket-Accept", derive_accept_key(self.upgrade.key.as_bytes()));
+        if self.upgrade.subprotocol {
+            response.raw_header("Sec-WebSocket-Protocol", TOKEN_SUBPROTOCOL);
+        }
+        response.upgrade("websocket", self).ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for WebSocketChannel {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let ws_stream = WebSocketStream::from_raw_socket(io, protocol::Role::Server, None).await;
+        handle_connection(ws_stream, *Pin::into_inner(self)).await;
+        Ok(())
+    }
+}
+
+/*
+    Websocket sessions
+
+    Every connection belongs to a signed-in user. Browsers can't set headers on
+    a websocket, so the access token comes as the first message:
+
+        {"v": 1, "type": "auth", "data": {"token": "<access token>"}}
+
+    or on the upgrade, either as the subprotocols "access_token, <token>" (the
+    server answers with "access_token") or as ?token=. A query string ends up in
+    proxy and access logs, so token_fairing takes it off the URI before Rocket
+    logs the request, but anything in front of the server still sees it.
+
+    A bad token on the upgrade is refused with 401 before switching protocols.
+    A connection that doesn't authenticate in time, or sends a bad token, is
+    closed with 1008 (policy violation), as is one whose user is disabled,
+    deleted, made to reset their password or given another role, checked on
+    every ping. Only authenticated connections get events, and what they may
+    send is checked against their role's permissions. What they send is
+    relayed to the other screens as it is, but kept off the EventBus: only the
+    REST handlers publish changes to the stored data.
+*/
+
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Heartbeat and queue limits for each connection, from the config.
+struct ConnectionSettings {
+    ping_interval: Duration,
+    idle_timeout: Duration,
+    queue_capacity: usize,
+    queue_policy: QueuePolicy,
+    resume_window: Duration,
+}
+
+impl ConnectionSettings {
+    fn from_config(config: &AppConfig) -> Self {
+        ConnectionSettings {
+            ping_interval: Duration::from_secs(config.ws_ping_interval_secs),
+            idle_timeout: Duration::from_secs(config.ws_idle_timeout_secs),
+            queue_capacity: config.ws_queue_capacity,
+            queue_policy: config.ws_queue_policy,
+            resume_window: Duration::from_millis(config.ws_resume_window_ms),
+        }
+    }
+}
+
+/// Who is on the other end of a connection and what their role grants.
+struct Session {
+    claims: Claims,
+    permissions: Vec<String>,
+}
+
+/// What relaying an event takes: the same as the REST call that makes that change.
+enum Requirement {
+    Permission(&'static str),
+    Admin,
+}
+
+fn requirement(event: &Event) -> Requirement {
+    match event {
+        Event::HotTrailer(_) | Event::ScheduleTrailer(_) | Event::TrailerArrived(_) => Requirement::Permission(ScheduleWrite::NAME),
+        Event::SetDoor(_) | Event::SetShipmentDoor(_) => Requirement::Permission(DoorsWrite::NAME),
+        Event::NewShipment(_) | Event::ShipmentTrailerArrival(_) | Event::ShipmentStartLoading(_)
+        | Event::ShipmentDepart(_) | Event::ShipmentHold(_) | Event::ShipmentLines(_) => Requirement::Permission(ShipmentsWrite::NAME),
+        Event::StartShipmentPick(_) | Event::FinishShipmentPick(_) | Event::VerifiedBy(_) => Requirement::Permission(ShipmentsPick::NAME),
+        Event::DeleteShipment(_) => Requirement::Permission(ShipmentsDelete::NAME),
+        Event::ShipmentStatusOverride(_) => Requirement::Admin,
+    }
+}
+
+impl Session {
+    fn is_admin(&self) -> bool {
+        self.claims.role.parse::<Role>() == Ok(Role::Admin)
+    }
+
+    fn has(&self, permission: &str) -> bool {
+        self.is_admin() || self.permissions.iter().any(|p| p == permission)
+    }
+
+    fn may_send(&self, event: &Event) -> bool {
+        match requirement(event) {
+            Requirement::Permission(name) => self.has(name),
+            Requirement::Admin => self.is_admin(),
+        }
+    }
+
+    /// `trailers` and `shipments` need the matching read permission. The rest are
+    /// narrower, and the client only gets the kinds of event it can read anyway.
+    fn may_subscribe(&self, topic: &Topic) -> bool {
+        match topic {
+            Topic::Trailers => self.has(TrailersRead::NAME),
+            Topic::Shipments | Topic::LoadId(_) => self.has(ShipmentsRead::NAME),
+            Topic::Door(_) | Topic::Date(_) => true,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        (self.claims.exp as i64) < chrono::Utc::now().timestamp()
+    }
+}
+
+#[derive(Clone)]
+struct Authenticator {
+    keys: KeyRing,
+    users: Arc<dyn UserRepository>,
+}
+
+impl Authenticator {
+    async fn session(&self, token: &str) -> Result<Session, ApiError> {
+        let claims = match decode_token(token, &self.keys) {
+            Ok(claims) if claims.token_type == TokenType::Access => claims,
+            Ok(_) => return Err(ApiError::Unauthorized("Refresh tokens cannot open a websocket".to_string())),
+            Err(e) => {
+                println!("Rejected websocket token: {:?}", e);
+                return Err(ApiError::Unauthorized("Invalid or expired token".to_string()));
+            },
+        };
+
+        let claims = match current_claims(self.users.as_ref(), claims).await? {
+            Some(claims) => claims,
+            None => return Err(ApiError::Unauthorized("Invalid or expired token".to_string())),
+        };
+
+        let mut session = Session { claims, permissions: Vec::new() };
+        if !session.is_admin() {
+            session.permissions = self.users.role_permissions(&session.claims.role).await?;
+        }
+        Ok(session)
+    }
+
+    /// False once the user has been disabled, deleted, made to reset their password
+    /// or given another role, so a connection doesn't keep what the token granted
+    /// for the rest of its life.
+    async fn still_valid(&self, session: &Session) -> bool {
+        match current_claims(self.users.as_ref(), session.claims.clone()).await {
+            Ok(Some(claims)) => claims.role == session.claims.role,
+            Ok(None) => false,
+            Err(e) => {
+                // Don't drop every screen because the database blinked.
+                println!("Failed to recheck {}: {}", session.claims.username, e);
+                true
+            },
+        }
+    }
+
+    /// Waits for the auth message on a connection that didn't bring a token.
+    async fn first_message(&self, ws_stream: &mut WebSocketStream<IoStream>) -> Result<Session, &'static str> {
+        let text = loop {
+            match ws_stream.next().await {
+                Some(Ok(Message::Text(text))) => break text,
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                _ => return Err("Expected an auth message"),
+            }
+        };
+        let auth = match WsEvent::parse(&text) {
+            Ok(WsEvent::Auth(auth)) => auth,
+            _ => return Err("Expected an auth message"),
+        };
+        self.session(&auth.token).await.map_err(|_| "Authentication failed")
+    }
+}
+
+fn error_frame(error: WsError) -> Message {
+    let text = ServerMessage::Error(error).to_text().expect("error frames serialize");
+    Message::Text(text)
+}
+
+fn policy_close(reason: &'static str) -> Message {
+    Message::Close(Some(CloseFrame { code: CloseCode::Policy, reason: reason.into() }))
+}
+
+/// Adds or removes topics for a client and sends it the full list, or the error
+/// if it asked for a topic its role can't read. Nothing changes in that case.
+async fn change_topics(
+    ws_list: &WebSocketList,
+    peer_addr: SocketAddr,
+    session: &Session,
+    request: Topics,
+    subscribe: bool,
+) -> Option<WsError> {
+    if subscribe {
+        if let Some(topic) = request.topics.iter().find(|topic| !session.may_subscribe(topic)) {
+            let topic = serde_json::to_string(topic).unwrap_or_default();
+            let message = format!("Role {} may not subscribe to {}", session.claims.role, topic);
+            return Some(WsError::new("FORBIDDEN", message));
+        }
+    }
+
+    let mut ws_list = ws_list.lock().await;
+    let client = ws_list.get_mut(&peer_addr)?;
+    for topic in request.topics {
+        if subscribe && !client.topics.contains(&topic) {
+            client.topics.push(topic);
+        } else if !subscribe {
+            client.topics.retain(|t| *t != topic);
+        }
+    }
+    println!("{} ({}) is subscribed to {:?}", client.username, peer_addr, client.topics);
+
+    let reply = ServerMessage::Subscriptions(Topics { topics: client.topics.clone() });
+    let text = reply.to_text().expect("subscriptions serialize");
+    client.deliver(Message::Text(text));
+    None
+}
+
+/// Sends a client the events it missed before this connection, as far as its
+/// subscriptions go, then `resumed`, then the live events held back meanwhile.
+/// Or `resync_required` if they are gone, or more than its queue has room for.
+async fn resume(ws_list: &WebSocketList, replay: &ReplayBuffer, peer_addr: SocketAddr, request: ResumeRequest) -> Option<WsError> {
+    let mut ws_list = ws_list.lock().await;
+    let client = ws_list.get_mut(&peer_addr)?;
+    let held = match &client.held {
+        Some(held) => held.len(),
+        None => return Some(WsError::new("BAD_MESSAGE", "Resume only once, right after the welcome")),
+    };
+
+    let position = replay.position();
+    let missed = if request.stream == position.stream {
+        replay.between(request.last_seq, client.joined_after_seq)
+            .map(|missed| missed.into_iter().filter(|(event, _)| client.wants(event)).collect::<Vec<_>>())
+            .filter(|missed| missed.len() + held < client.sender.capacity())
+    } else {
+        None
+    };
+    match missed {
+        Some(missed) => {
+            for (_, text) in &missed {
+                client.deliver(Message::Text(text.clone()));
+            }
+            println!("Replayed {} events to {} ({})", missed.len(), client.username, peer_addr);
+            let reply = ServerMessage::Resumed(Resumed { replayed: missed.len() });
+            client.deliver(Message::Text(reply.to_text().expect("resume replies serialize")));
+            if !client.release() {
+                ws_list.remove(&peer_addr);
+            }
+        },
+        None => {
+            // Still held, the client may try again from another position.
+            println!("{} ({}) can't resume from {}, needs a resync", client.username, peer_addr, request.last_seq);
+            let reply = ServerMessage::ResyncRequired(position);
+            client.deliver(Message::Text(reply.to_text().expect("resume replies serialize")));
+        },
+    }
+    None
+}
+
+#[get("/ws")]
+pub async fn ws_handler(upgrade: WebSocketUpgrade, state: &State<AppState>) -> Result<WebSocketChannel, ApiError> {
+    let authenticator = Authenticator { keys: state.jwt_keys.clone(), users: state.users.clone() };
+    let session = match &upgrade.token {
+        Some(token) => Some(authenticator.session(token).await?),
+        None => None,
+    };
+    Ok(WebSocketChannel {
+        upgrade,
+        session,
+        authenticator,
+        ws_list: state.ws_list.clone(),
+        replay: state.ws_replay.clone(),
+        settings: ConnectionSettings::from_config(&state.config),
+    })
+}
+
+async fn handle_connection(mut ws_stream: WebSocketStream<IoStream>, channel: WebSocketChannel) {
+    let WebSocketChannel { upgrade, session, authenticator, ws_list, replay, settings } = channel;
+    let peer_addr = upgrade.peer_addr;
     println!("WebSocket handshake successful with {}", peer_addr);
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let session = match session {
+        Some(session) => session,
+        None => {
+            let result = timeout(AUTH_TIMEOUT, authenticator.first_message(&mut ws_stream)).await
+                .unwrap_or(Err("Authentication timed out"));
+            match result {
+                Ok(session) => session,
+                Err(reason) => {
+                    println!("Closing unauthenticated websocket {}: {}", peer_addr, reason);
+                    ws_stream.send(policy_close(reason)).await.ok();
+                    return;
+                },
+            }
+        },
+    };
+    let username = session.claims.username.clone();
+    println!("WebSocket {} authenticated as {} ({})", peer_addr, username, session.claims.role);
 
-    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let (tx, mut rx) = mpsc::channel(settings.queue_capacity);
+    let kick = Arc::new(Notify::new());
 
     {
         let mut ws_list = ws_list.lock().await;
-        ws_list.insert(peer_addr, tx);
+        // Everything it can read until it asks for less.
+        let topics = [Topic::Trailers, Topic::Shipments].into_iter()
+            .filter(|topic| session.may_subscribe(topic))
+            .collect();
+        let position = replay.position();
+        let mut client = WsClient {
+            username: username.clone(),
+            role: session.claims.role.clone(),
+            topics,
+            reads_trailers: session.has(TrailersRead::NAME),
+            reads_shipments: session.has(ShipmentsRead::NAME),
+            joined_after_seq: position.seq,
+            sender: tx,
+            queue_policy: settings.queue_policy,
+            dropped: 0,
+            kick: kick.clone(),
+            held: Some(Vec::new()),
+        };
+        let welcome = ServerMessage::Welcome(Welcome {
+            username: username.clone(),
+            role: session.claims.role.clone(),
+            stream: position.stream,
+            seq: position.seq,
+        });
+        client.deliver(Message::Text(welcome.to_text().expect("welcome serializes")));
+        ws_list.insert(peer_addr, client);
         println!("Added {} to WebSocket list. Total clients: {}", peer_addr, ws_list.len());
     }
 
-    // Clone ws_list for the incoming messages task
-    let ws_list_for_incoming = ws_list.clone();
-    // Task to handle incoming messages from the WebSocket connection
-    tokio::spawn(async move {
-        while let Some(message) = ws_receiver.next().await {
-            match message {
-                Ok(msg) => {
-                    if msg.is_text() {
-                        let msg_text = msg.to_text().unwrap();
-                        println!("Received message from {}: {}", peer_addr, msg_text);
-                        match serde_json::from_str::<IncomingMessage>(msg_text) {
-                            Ok(incoming_message) => {
-                                match incoming_message.r#type.as_str() {
-                                    "hot_trailer" => {
-                                        println!("Handling hot_trailer: {:?}", incoming_message.data);
-                                    }
-                                    "schedule_trailer" => {
-                                        println!("Handling schedule_trailer: {:?}", incoming_message.data);
-                                    }
-                                    "set_door" => {
-                                        println!("Handling set_door: {:?}", incoming_message.data);
-                                    }
-                                    "trailer_arrived" => {
-                                        println!("Handling trailer_arrived: {:?}", incoming_message.data);
-                                    }
-                                    "shipment_trailer_arrival" => {
-                                        println!("Handling set_shipment_trailer: {:?}", incoming_message.data);
-                                    }
-                                    "set_shipment_door" => {
-                                        println!("Handling set_shipment_door: {:?}", incoming_message.data);
-                                    }
-                                    "start_shipment_pick" => {
-                                        println!("Handling start_shipment_pick: {:?}", incoming_message.data);
-                                    }
-                                    "finish_shipment_pick" => {
-                                        println!("Handling finish_shipment_pick: {:?}", incoming_message.data);
-                                    }
-                                    "new_shipment" => {
-                                        println!("Handling new_shipment: {:?}", incoming_message.data);
-                                    }
-                                    "shipment_depart" => {
-                                        println!("Handling shipment_depart: {:?}", incoming_message.data);
-                                    }
-                                    "shipment_start_loading" => {
-                                        println!("Handling shipment_start_loading: {:?}", incoming_message.data);
-                                    }
-                                    "delete_shipment" => {
-                                        println!("Handling delete_shipment: {:?}", incoming_message.data);
-                                    }
-                                    "shipment_hold" => {
-                                        println!("Handling shipment_hold: {:?}", incoming_message.data);
-                                    }
-                                    "verified_by" => {
-                                        println!("Handling verified_by: {:?}", incoming_message.data);
-                                    }
-                                    _ => {
-                                        println!("Unknown event type: {:?}", incoming_message.r#type);
-                                    }
-                                }
-
-                                // Broadcast the message to all connected clients
-                                let response = Message::Text(serde_json::to_string(&incoming_message).unwrap());
-                                let ws_list = ws_list_for_incoming.lock().await;
-                                println!("Broadcasting message to {} clients", ws_list.len());
-                                for sender in ws_list.values() {
-                                    if sender.send(response.clone()).is_err() {
-                                        println!("Failed to send message to {}", peer_addr);
-                                    } else {
-                                        println!("Message sent to client {}", peer_addr);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("Failed to parse incoming message: {:?}", e);
-                            }
+    // One loop per connection: messages from the client, messages queued for it,
+    // pings, and being kicked for falling behind. A client that sends nothing,
+    // not even a pong, for the idle timeout is closed, as is one whose socket
+    // takes that long to accept a message.
+    let mut ping = interval_at(Instant::now() + settings.ping_interval, settings.ping_interval);
+    let mut last_heard = Instant::now();
+    let resume_window = sleep(settings.resume_window);
+    tokio::pin!(resume_window);
+    let mut may_resume = true;
+    let close_reason = loop {
+        tokio::select! {
+            incoming = ws_stream.next() => {
+                let msg = match incoming {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        println!("WebSocket error with {}: {}", peer_addr, e);
+                        break None;
+                    },
+                    None => break None,
+                };
+                last_heard = Instant::now();
+                if session.expired() {
+                    println!("Token for {} on {} expired, closing", username, peer_addr);
+                    break Some("Token expired");
+                }
+
+                let rejection = match msg {
+                    Message::Text(text) => {
+                        match WsEvent::parse(&text) {
+                            Ok(WsEvent::Auth(_)) => Some(WsError::new("BAD_MESSAGE", "Already authenticated")),
+                            Ok(WsEvent::Subscribe(request)) => change_topics(&ws_list, peer_addr, &session, request, true).await,
+                            Ok(WsEvent::Unsubscribe(request)) => change_topics(&ws_list, peer_addr, &session, request, false).await,
+                            Ok(WsEvent::Resume(request)) => resume(&ws_list, &replay, peer_addr, request).await,
+                            Ok(message) => match message.into_event() {
+                                Some(event) if session.may_send(&event) => {
+                                    relay(&ws_list, &username, &event).await;
+                                    None
+                                },
+                                Some(event) => Some(WsError::new(
+                                    "FORBIDDEN",
+                                    format!("Role {} may not send {}", session.claims.role, event.name()),
+                                )),
+                                // Auth, subscriptions and resume are handled above.
+                                None => None,
+                            },
+                            Err(error) => Some(error),
                         }
-                    } else if msg.is_binary() {
-                        println!("Received binary message from {}", peer_addr);
-                    } else if msg.is_close() {
+                    },
+                    Message::Binary(_) => Some(WsError::new("BAD_MESSAGE", "Messages must be text")),
+                    Message::Close(_) => {
                         println!("Received close message from {}", peer_addr);
-                        break;
+                        break None;
+                    },
+                    Message::Ping(_) | Message::Pong(_) => None,
+                };
+                if let Some(error) = rejection {
+                    println!("Rejected message from {} ({}): {}", peer_addr, username, error.message);
+                    if !send_within(&mut ws_stream, error_frame(error), settings.idle_timeout).await {
+                        break None;
                     }
                 }
-                Err(e) => {
-                    println!("WebSocket error with {}: {}", peer_addr, e);
-                    break;
+            },
+            outgoing = rx.recv() => match outgoing {
+                Some(message) => {
+                    if !send_within(&mut ws_stream, message, settings.idle_timeout).await {
+                        println!("Failed to send outgoing message to {}", peer_addr);
+                        break Some("Not accepting messages");
+                    }
+                },
+                // Removed from ws_list, e.g. after a full queue.
+                None => break Some("Too far behind"),
+            },
+            _ = ping.tick() => {
+                if last_heard.elapsed() >= settings.idle_timeout {
+                    println!("No word from {} ({}) in {:?}, closing", username, peer_addr, settings.idle_timeout);
+                    break Some("Idle timeout");
                 }
-            }
-        }
-        let mut ws_list = ws_list_for_incoming.lock().await;
-        ws_list.remove(&peer_addr);
-        println!("Client {} removed. Total clients: {}", peer_addr, ws_list.len());
-    });
-
-    // Clone ws_list for the outgoing messages task
-    let ws_list_for_outgoing = ws_list.clone();
-    // Task to handle outgoing messages to the WebSocket connection
-    tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            println!("Sending outgoing message to {}", peer_addr);
-            if ws_sender.send(message).await.is_err() {
-                println!("Failed to send outgoing message to {}", peer_addr);
-                break;
-            }
+                if !authenticator.still_valid(&session).await {
+                    println!("{} on {} was disabled or changed role, closing", username, peer_addr);
+                    break Some("Signed out");
+                }
+                if !send_within(&mut ws_stream, Message::Ping(Vec::new()), settings.idle_timeout).await {
+                    break None;
+                }
+            },
+            _ = &mut resume_window, if may_resume => {
+                may_resume = false;
+                let mut ws_list = ws_list.lock().await;
+                if ws_list.get_mut(&peer_addr).is_some_and(|client| !client.release()) {
+                    ws_list.remove(&peer_addr);
+                }
+            },
+            _ = kick.notified() => break Some("Too far behind"),
         }
+    };
 
-        // Clean up the WebSocket list after the connection is closed
-        let mut ws_list = ws_list_for_outgoing.lock().await;
-        ws_list.remove(&peer_addr);
-        println!("Client {} disconnected. Total clients: {}", peer_addr, ws_list.len());
-    });
-}
\ No newline at end of file
+    let mut ws_list = ws_list.lock().await;
+    ws_list.remove(&peer_addr);
+    println!("Client {} disconnected. Total clients: {}", peer_addr, ws_list.len());
+    drop(ws_list);
+    if let Some(reason) = close_reason {
+        send_within(&mut ws_stream, policy_close(reason), CLOSE_TIMEOUT).await;
+    }
+}
+
+async fn send_within(ws_stream: &mut WebSocketStream<IoStream>, message: Message, limit: Duration) -> bool {
+    matches!(timeout(limit, ws_stream.send(message)).await, Ok(Ok(())))
+}
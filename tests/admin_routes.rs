@@ -0,0 +1,260 @@
+mod common;
+
+use common::*;
+use rocket::http::Status;
+use rocket_http::config::QueuePolicy;
+use rocket_http::structs::AppState;
+use rocket_http::wsprotocol::Topic;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+#[rocket::async_test]
+async fn admin_routes_are_admin_only() {
+    let app = TestApp::spawn().await;
+    let writer = app.token(WRITER).await;
+
+    for path in ["/api/admin/permissions", "/api/admin/roles", "/api/admin/users", "/api/admin/users/rita", "/api/admin/invites", "/api/admin/websockets"] {
+        assert_error(app.get(path, None).await, Status::Unauthorized, "UNAUTHORIZED").await;
+        assert_error(app.get(path, Some(&writer)).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+
+    let posts = [
+        ("/api/admin/roles", json!({ "name": "dock", "permissions": [] })),
+        ("/api/admin/users/rita/role", json!({ "role": "write" })),
+        ("/api/admin/users/rita/disable", json!({})),
+        ("/api/admin/users/rita/enable", json!({})),
+        ("/api/admin/users/rita/reset_password", json!({ "temporary_password": "Temporary-Pass-11" })),
+        ("/api/admin/users/rita/unlock", json!({})),
+        ("/api/admin/invites", json!({ "role": "read" })),
+        ("/api/shipment_status_override", json!({ "LoadId": "L-100", "Status": "COMPLETE" })),
+    ];
+    for (path, request) in posts {
+        assert_error(app.post(path, Some(&writer), request).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+
+    for path in ["/api/admin/roles/read", "/api/admin/users/rita", "/api/admin/invites/abc"] {
+        assert_error(app.delete(path, Some(&writer)).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+}
+
+#[rocket::async_test]
+async fn lists_permissions_and_builtin_roles() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let permissions = body(app.get("/api/admin/permissions", Some(&admin)).await).await;
+    assert!(permissions.as_array().unwrap().contains(&json!("shipments:delete")));
+
+    let roles = body(app.get("/api/admin/roles", Some(&admin)).await).await;
+    let names: Vec<&str> = roles.as_array().unwrap().iter().map(|r| r["name"].as_str().unwrap()).collect();
+    for builtin in ["admin", "read", "write"] {
+        assert!(names.contains(&builtin), "missing role {}", builtin);
+    }
+}
+
+#[rocket::async_test]
+async fn custom_roles_grant_exactly_their_permissions() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let response = app.post("/api/admin/roles", Some(&admin), json!({ "name": "picker", "permissions": ["shipments:read", "shipments:pick"] })).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response).await["builtin"], false);
+
+    let response = app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "picker" })).await;
+    assert_eq!(body(response).await["role"], "picker");
+
+    let picker = app.token(READER).await;
+    let start = json!({ "LoadId": "L-100", "StartTime": "08:15", "Picker": READER });
+    assert_eq!(app.post("/api/set_shipment_pick_start", Some(&picker), start).await.status(), Status::Ok);
+    // No trailers:read in the role.
+    assert_error(app.get("/api/schedule_trailer", Some(&picker)).await, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn set_role_validates_the_request() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let cases = [
+        (json!({ "name": " ", "permissions": [] }), Status::BadRequest),
+        (json!({ "name": "admin", "permissions": [] }), Status::UnprocessableEntity),
+        (json!({ "name": "dock", "permissions": ["doors:fly"] }), Status::UnprocessableEntity),
+    ];
+    for (request, status) in cases {
+        assert_eq!(app.post("/api/admin/roles", Some(&admin), request).await.status(), status);
+    }
+}
+
+#[rocket::async_test]
+async fn delete_role_refuses_builtin_and_assigned_roles() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    assert_error(app.delete("/api/admin/roles/read", Some(&admin)).await, Status::Conflict, "CONFLICT").await;
+    assert_error(app.delete("/api/admin/roles/nope", Some(&admin)).await, Status::NotFound, "NOT_FOUND").await;
+
+    app.post("/api/admin/roles", Some(&admin), json!({ "name": "dock", "permissions": ["doors:write"] })).await;
+    app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "dock" })).await;
+    assert_error(app.delete("/api/admin/roles/dock", Some(&admin)).await, Status::Conflict, "CONFLICT").await;
+
+    app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "read" })).await;
+    assert_eq!(app.delete("/api/admin/roles/dock", Some(&admin)).await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn lists_and_gets_users_without_password_hashes() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let users = body(app.get("/api/admin/users", Some(&admin)).await).await;
+    let users = users.as_array().unwrap();
+    assert_eq!(users.len(), 5);
+    assert!(users.iter().all(|u| u.get("password").is_none()));
+
+    let user = body(app.get(&format!("/api/admin/users/{}", DISABLED), Some(&admin)).await).await;
+    assert_eq!(user, json!({ "username": DISABLED, "role": "read", "disabled": true, "must_reset_password": false }));
+
+    assert_error(app.get("/api/admin/users/nobody", Some(&admin)).await, Status::NotFound, "NOT_FOUND").await;
+}
+
+#[rocket::async_test]
+async fn role_change_applies_at_next_login() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let response = app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "write" })).await;
+    assert_eq!(body(response).await["role"], "write");
+    let login = body(app.login(READER, PASSWORD).await).await;
+    assert_eq!(login["user"]["role"], "write");
+
+    let response = app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "owner" })).await;
+    assert_error(response, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+    let response = app.post(&format!("/api/admin/users/{}/role", ADMIN), Some(&admin), json!({ "role": "read" })).await;
+    assert_error(response, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn issued_access_tokens_follow_the_stored_user() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+    let writer = app.token(WRITER).await;
+    let hold = json!({ "LoadId": "L-100" });
+
+    app.post(&format!("/api/admin/users/{}/role", WRITER), Some(&admin), json!({ "role": "read" })).await;
+    assert_error(app.post("/api/shipment_hold", Some(&writer), hold.clone()).await, Status::Forbidden, "FORBIDDEN").await;
+    app.post(&format!("/api/admin/users/{}/role", WRITER), Some(&admin), json!({ "role": "write" })).await;
+    assert_eq!(app.post("/api/shipment_hold", Some(&writer), hold).await.status(), Status::Ok);
+
+    app.post(&format!("/api/admin/users/{}/disable", WRITER), Some(&admin), json!({})).await;
+    assert_error(app.get("/api/schedule_trailer", Some(&writer)).await, Status::Unauthorized, "UNAUTHORIZED").await;
+    app.delete(&format!("/api/admin/users/{}", WRITER), Some(&admin)).await;
+    assert_error(app.get("/api/schedule_trailer", Some(&writer)).await, Status::Unauthorized, "UNAUTHORIZED").await;
+}
+
+#[rocket::async_test]
+async fn disable_ends_sessions_and_enable_restores_login() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+    let login = body(app.login(READER, PASSWORD).await).await;
+    let refresh = login["refresh_token"].as_str().unwrap();
+
+    let response = app.post(&format!("/api/admin/users/{}/disable", READER), Some(&admin), json!({})).await;
+    assert_eq!(body(response).await["disabled"], true);
+    assert_eq!(app.login(READER, PASSWORD).await.status(), Status::Forbidden);
+    assert_eq!(app.post("/refresh", None, json!({ "refresh_token": refresh })).await.status(), Status::Unauthorized);
+
+    let response = app.post(&format!("/api/admin/users/{}/enable", READER), Some(&admin), json!({})).await;
+    assert_eq!(body(response).await["disabled"], false);
+    assert_eq!(app.login(READER, PASSWORD).await.status(), Status::Ok);
+
+    let response = app.post(&format!("/api/admin/users/{}/disable", ADMIN), Some(&admin), json!({})).await;
+    assert_error(response, Status::Forbidden, "FORBIDDEN").await;
+    let response = app.post("/api/admin/users/nobody/enable", Some(&admin), json!({})).await;
+    assert_error(response, Status::NotFound, "NOT_FOUND").await;
+}
+
+#[rocket::async_test]
+async fn reset_password_forces_a_change() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+    let reader = app.token(READER).await;
+    let temporary = "Temporary-Pass-11";
+
+    let weak = json!({ "temporary_password": "short" });
+    let response = app.post(&format!("/api/admin/users/{}/reset_password", READER), Some(&admin), weak).await;
+    assert_error(response, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+
+    let response = app.post(&format!("/api/admin/users/{}/reset_password", READER), Some(&admin), json!({ "temporary_password": temporary })).await;
+    assert_eq!(body(response).await["must_reset_password"], true);
+    assert_eq!(app.login(READER, temporary).await.status(), Status::Forbidden);
+    // Tokens from before the reset stop working straight away.
+    assert_error(app.get("/api/schedule_trailer", Some(&reader)).await, Status::Unauthorized, "UNAUTHORIZED").await;
+
+    let change = json!({ "username": READER, "current_password": temporary, "new_password": "Brand-New-Forklift-3" });
+    assert_eq!(app.post("/change_password", None, change).await.status(), Status::Ok);
+    assert_eq!(app.login(READER, "Brand-New-Forklift-3").await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn delete_user_removes_the_account() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    assert_eq!(app.delete(&format!("/api/admin/users/{}", READER), Some(&admin)).await.status(), Status::Ok);
+    assert_eq!(app.login(READER, PASSWORD).await.status(), Status::Unauthorized);
+    assert_error(app.delete(&format!("/api/admin/users/{}", READER), Some(&admin)).await, Status::NotFound, "NOT_FOUND").await;
+    assert_error(app.delete(&format!("/api/admin/users/{}", ADMIN), Some(&admin)).await, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn invites_can_be_listed_and_withdrawn() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let response = app.post("/api/admin/invites", Some(&admin), json!({ "role": "owner" })).await;
+    assert_error(response, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+    let response = app.post("/api/admin/invites", Some(&admin), json!({ "role": "read", "expires_in_hours": 0 })).await;
+    assert_error(response, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+
+    let invite = body(app.post("/api/admin/invites", Some(&admin), json!({ "role": "read", "expires_in_hours": 2 })).await).await;
+    assert_eq!(invite["created_by"], ADMIN);
+    let code = invite["code"].as_str().unwrap();
+
+    let invites = body(app.get("/api/admin/invites", Some(&admin)).await).await;
+    assert_eq!(invites.as_array().map(Vec::len), Some(1));
+
+    assert_eq!(app.delete(&format!("/api/admin/invites/{}", code), Some(&admin)).await.status(), Status::Ok);
+    assert_eq!(body(app.get("/api/admin/invites", Some(&admin)).await).await, Value::Array(Vec::new()));
+
+    let register = json!({ "username": "newbie", "password": PASSWORD, "invite_code": code });
+    assert_error(app.post("/register", None, register).await, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn websocket_stats_show_connections_and_queue_depth() {
+    let app = TestApp::spawn_with(|config| config.ws_queue_capacity = 4).await;
+    let admin = app.token(ADMIN).await;
+    let state = app.client.rocket().state::<AppState>().unwrap();
+
+    let stats = body(app.get("/api/admin/websockets", Some(&admin)).await).await;
+    assert_eq!((&stats["connections"], &stats["clients"]), (&json!(0), &json!([])));
+    assert_eq!((&stats["queue_capacity"], &stats["queue_policy"]), (&json!(4), &json!("disconnect")));
+
+    let (client, _rx, _) = fake_ws_client(READER, "read", vec![Topic::Trailers], 4, QueuePolicy::Disconnect);
+    for _ in 0..3 {
+        client.sender.try_send(Message::Text("{}".to_string())).unwrap();
+    }
+    state.ws_list.lock().await.insert("127.0.0.1:50000".parse().unwrap(), client);
+
+    let stats = body(app.get("/api/admin/websockets", Some(&admin)).await).await;
+    assert_eq!(stats["connections"], 1);
+    assert_eq!(stats["clients"], json!([{
+        "peer_addr": "127.0.0.1:50000",
+        "username": READER,
+        "role": "read",
+        "topics": ["trailers"],
+        "queue_depth": 3,
+        "dropped": 0,
+    }]));
+}
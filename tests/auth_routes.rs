@@ -0,0 +1,223 @@
+mod common;
+
+use std::net::SocketAddr;
+use common::*;
+use rocket::http::{ContentType, Header, Status};
+use serde_json::json;
+
+#[rocket::async_test]
+async fn login_returns_tokens_and_role() {
+    let app = TestApp::spawn().await;
+
+    let response = app.login(WRITER, PASSWORD).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = body(response).await;
+    assert_eq!(body["user"], json!({ "username": WRITER, "role": "write" }));
+    assert!(body["token"].is_string());
+    assert!(body["refresh_token"].is_string());
+}
+
+#[rocket::async_test]
+async fn login_rejects_bad_credentials_alike() {
+    let app = TestApp::spawn().await;
+
+    assert_error(app.login(WRITER, "Not-The-Password-1").await, Status::Unauthorized, "UNAUTHORIZED").await;
+    assert_error(app.login("nobody", PASSWORD).await, Status::Unauthorized, "UNAUTHORIZED").await;
+}
+
+#[rocket::async_test]
+async fn login_refuses_disabled_and_reset_accounts() {
+    let app = TestApp::spawn().await;
+
+    assert_error(app.login(DISABLED, PASSWORD).await, Status::Forbidden, "FORBIDDEN").await;
+    assert_error(app.login(MUST_RESET, PASSWORD).await, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn repeated_failures_lock_the_account() {
+    let app = TestApp::spawn_with(|config| config.login_max_attempts = 3).await;
+
+    for _ in 0..3 {
+        assert_eq!(app.login(READER, "Wrong-Password-99").await.status(), Status::Unauthorized);
+    }
+    // Locked now, even with the right password.
+    let response = app.login(READER, PASSWORD).await;
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response.headers().get_one("Retry-After").is_some());
+
+    // Until an admin lifts it.
+    let admin = app.token(ADMIN).await;
+    let response = app.post(&format!("/api/admin/users/{}/unlock", READER), Some(&admin), json!({})).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(app.login(READER, PASSWORD).await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn unlocking_with_an_ip_lifts_the_ip_lockout() {
+    let app = TestApp::spawn_with(|config| config.login_ip_max_attempts = 3).await;
+    let terminal: SocketAddr = "10.0.0.7:50000".parse().unwrap();
+    let login = |username: &'static str, password: &'static str| app.client.post("/login")
+        .header(ContentType::JSON)
+        .remote(terminal)
+        .body(json!({ "username": username, "password": password }).to_string())
+        .dispatch();
+
+    for _ in 0..3 {
+        assert_eq!(login("nobody", "Wrong-Password-99").await.status(), Status::Unauthorized);
+    }
+    assert_eq!(login(READER, PASSWORD).await.status(), Status::TooManyRequests);
+
+    // Clearing the username alone leaves the terminal locked out.
+    let admin = app.token(ADMIN).await;
+    let response = app.post(&format!("/api/admin/users/{}/unlock", READER), Some(&admin), json!({})).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(login(READER, PASSWORD).await.status(), Status::TooManyRequests);
+
+    let response = app.post(&format!("/api/admin/users/{}/unlock?ip=10.0.0.7", READER), Some(&admin), json!({})).await;
+    assert_eq!(body(response).await, json!("Account and IP unlocked"));
+    assert_eq!(login(READER, PASSWORD).await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn a_spoofed_ip_header_does_not_dodge_the_ip_lockout() {
+    let app = TestApp::spawn_with(|config| config.login_ip_max_attempts = 3).await;
+    let terminal: SocketAddr = "10.0.0.7:50000".parse().unwrap();
+    let login = |username: &'static str, password: &'static str, real_ip: String| app.client.post("/login")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Real-IP", real_ip))
+        .remote(terminal)
+        .body(json!({ "username": username, "password": password }).to_string())
+        .dispatch();
+
+    for n in 0..3 {
+        assert_eq!(login("nobody", "Wrong-Password-99", format!("192.0.2.{}", n)).await.status(), Status::Unauthorized);
+    }
+    assert_eq!(login(READER, PASSWORD, "192.0.2.99".to_string()).await.status(), Status::TooManyRequests);
+}
+
+#[rocket::async_test]
+async fn protected_routes_need_a_valid_access_token() {
+    let app = TestApp::spawn().await;
+
+    assert_error(app.get("/api/get_shipments", None).await, Status::Unauthorized, "UNAUTHORIZED").await;
+    assert_error(app.get("/api/get_shipments", Some("not-a-jwt")).await, Status::Unauthorized, "UNAUTHORIZED").await;
+
+    // A refresh token is not an access token.
+    let login = body(app.login(READER, PASSWORD).await).await;
+    let refresh = login["refresh_token"].as_str().unwrap();
+    assert_error(app.get("/api/get_shipments", Some(refresh)).await, Status::Unauthorized, "UNAUTHORIZED").await;
+}
+
+#[rocket::async_test]
+async fn register_needs_an_invite_unless_open() {
+    let app = TestApp::spawn().await;
+    let request = json!({ "username": "newbie", "password": PASSWORD });
+
+    assert_error(app.post("/register", None, request.clone()).await, Status::Forbidden, "FORBIDDEN").await;
+
+    let open = TestApp::spawn_with(|config| config.open_registration = true).await;
+    assert_eq!(open.post("/register", None, request).await.status(), Status::Ok);
+    let login = body(open.login("newbie", PASSWORD).await).await;
+    assert_eq!(login["user"]["role"], "read");
+}
+
+#[rocket::async_test]
+async fn register_with_invite_uses_its_role_once() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    let invite = body(app.post("/api/admin/invites", Some(&admin), json!({ "role": "write" })).await).await;
+    let code = invite["code"].as_str().unwrap();
+
+    let response = app.post("/register", None, json!({ "username": "newbie", "password": PASSWORD, "invite_code": code })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let login = body(app.login("newbie", PASSWORD).await).await;
+    assert_eq!(login["user"]["role"], "write");
+
+    let response = app.post("/register", None, json!({ "username": "second", "password": PASSWORD, "invite_code": code })).await;
+    assert_error(response, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn register_validates_the_request() {
+    let app = TestApp::spawn_with(|config| config.open_registration = true).await;
+    let admin = app.token(ADMIN).await;
+
+    let weak = json!({ "username": "newbie", "password": "short" });
+    assert_error(app.post("/register", None, weak).await, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+
+    let taken = json!({ "username": READER, "password": PASSWORD });
+    assert_error(app.post("/register", None, taken).await, Status::Conflict, "CONFLICT").await;
+
+    // Only admins pick a role, and only one that exists.
+    let with_role = json!({ "username": "newbie", "password": PASSWORD, "role": "admin" });
+    assert_error(app.post("/register", None, with_role.clone()).await, Status::Forbidden, "FORBIDDEN").await;
+    let unknown_role = json!({ "username": "newbie", "password": PASSWORD, "role": "owner" });
+    assert_error(app.post("/register", Some(&admin), unknown_role).await, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+    assert_eq!(app.post("/register", Some(&admin), with_role).await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn refresh_rotates_and_detects_reuse() {
+    let app = TestApp::spawn().await;
+    let login = body(app.login(WRITER, PASSWORD).await).await;
+    let first = login["refresh_token"].as_str().unwrap().to_string();
+
+    let response = app.post("/refresh", None, json!({ "refresh_token": first })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let second = body(response).await["refresh_token"].as_str().unwrap().to_string();
+    assert_ne!(first, second);
+
+    // Replaying the first token revokes the whole family, including the second.
+    assert_error(app.post("/refresh", None, json!({ "refresh_token": first })).await, Status::Unauthorized, "UNAUTHORIZED").await;
+    assert_error(app.post("/refresh", None, json!({ "refresh_token": second })).await, Status::Unauthorized, "UNAUTHORIZED").await;
+}
+
+#[rocket::async_test]
+async fn refresh_rejects_access_tokens() {
+    let app = TestApp::spawn().await;
+    let access = app.token(WRITER).await;
+
+    assert_error(app.post("/refresh", None, json!({ "refresh_token": access })).await, Status::Unauthorized, "UNAUTHORIZED").await;
+}
+
+#[rocket::async_test]
+async fn logout_revokes_the_refresh_token() {
+    let app = TestApp::spawn().await;
+    let login = body(app.login(WRITER, PASSWORD).await).await;
+    let refresh = login["refresh_token"].as_str().unwrap();
+
+    let response = app.post("/logout", None, json!({ "refresh_token": refresh })).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_error(app.post("/refresh", None, json!({ "refresh_token": refresh })).await, Status::Unauthorized, "UNAUTHORIZED").await;
+
+    assert_error(app.post("/logout", None, json!({ "refresh_token": "garbage" })).await, Status::Unauthorized, "UNAUTHORIZED").await;
+}
+
+#[rocket::async_test]
+async fn change_password_clears_a_required_reset() {
+    let app = TestApp::spawn().await;
+    let new_password = "Fresh-Pallet-Jack-77";
+
+    let wrong = json!({ "username": MUST_RESET, "current_password": "Wrong-Password-99", "new_password": new_password });
+    assert_error(app.post("/change_password", None, wrong).await, Status::Unauthorized, "UNAUTHORIZED").await;
+
+    let same = json!({ "username": MUST_RESET, "current_password": PASSWORD, "new_password": PASSWORD });
+    assert_error(app.post("/change_password", None, same).await, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+
+    let request = json!({ "username": MUST_RESET, "current_password": PASSWORD, "new_password": new_password });
+    assert_eq!(app.post("/change_password", None, request).await.status(), Status::Ok);
+
+    assert_eq!(app.login(MUST_RESET, PASSWORD).await.status(), Status::Unauthorized);
+    assert_eq!(app.login(MUST_RESET, new_password).await.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn unknown_routes_and_bad_bodies_get_json_errors() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    assert_error(app.get("/api/nothing_here", Some(&token)).await, Status::NotFound, "NOT_FOUND").await;
+    let response = app.post("/api/shipment_hold", Some(&token), json!({ "Load": "L-100" })).await;
+    assert_error(response, Status::UnprocessableEntity, "UNPROCESSABLE_ENTITY").await;
+}
@@ -0,0 +1,373 @@
+// Each test binary uses a different part of the harness.
+#![allow(dead_code)]
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use rocket::Shutdown;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use rocket_http::build_rocket;
+use rocket_http::auth::{Claims, KeyRing, TokenType};
+use rocket_http::config::{AppConfig, QueuePolicy, Storage};
+use rocket_http::repository::{MemoryData, UserRepository, ShipmentRecord, SidRecord, TrailerRecord, UserRecord};
+use rocket_http::events::EventBus;
+use rocket_http::structs::{AppState, Count, Part, Schedule, Shipment, ShipmentLine, WebSocketList, WsClient};
+use rocket_http::wsprotocol::Topic;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::Message;
+
+/*
+    Test harness
+
+    Every TestApp is the full app from build_rocket on the memory store, started
+    from its own snapshot file seeded with fixtures(). Tests are independent and
+    can run in parallel.
+*/
+
+/// Password of every fixture user.
+pub const PASSWORD: &str = "Dock-Door-Seven-42";
+
+pub const ADMIN: &str = "alice";
+pub const WRITER: &str = "walt";
+pub const READER: &str = "rita";
+pub const DISABLED: &str = "dora";
+pub const MUST_RESET: &str = "rick";
+
+pub const TODAY: &str = "2024-06-03";
+
+pub fn schedule(date: &str, time: &str, door: &str) -> Schedule {
+    Schedule {
+        ScheduleDate: date.to_string(),
+        ScheduleTime: time.to_string(),
+        ArrivalTime: "".to_string(),
+        CarrierCode: "MAEU".to_string(),
+        ContactEmail: "dispatch@example.com".to_string(),
+        DoorNumber: door.to_string(),
+        IsHot: false,
+        LastFreeDate: "2024-06-10".to_string(),
+        LoadStatus: "in-transit".to_string(),
+        RequestDate: "2024-05-20".to_string(),
+        Seal: "".to_string(),
+        IsMulti: false,
+        IsStat6: false,
+        ClaimComments: "".to_string(),
+        HasClaim: false,
+    }
+}
+
+fn part(number: &str, quantity: i32) -> Part {
+    Part { partNumber: number.to_string(), quantity }
+}
+
+fn sid(id: &str, cisco: &str, parts: Vec<Part>) -> SidRecord {
+    SidRecord { id: id.to_string(), CiscoID: cisco.to_string(), Parts: parts }
+}
+
+pub fn shipment(load_id: &str, date: &str, status: &str) -> Shipment {
+    Shipment {
+        ScheduleDate: date.to_string(),
+        ScheduleTime: "09:00".to_string(),
+        Dock: "A".to_string(),
+        Door: "4".to_string(),
+        LoadId: load_id.to_string(),
+        LoadNum: load_id.trim_start_matches("L-").to_string(),
+        Status: status.to_string(),
+        ..Shipment::default()
+    }
+}
+
+fn line(item: &str, quantity: u32) -> ShipmentLine {
+    ShipmentLine { item: item.to_string(), quantity, ip: "IP-1".to_string() }
+}
+
+fn count(item: &str, actual: u32, expected: u32, date: &str) -> Count {
+    Count {
+        item: item.to_string(),
+        location: "R01-A".to_string(),
+        actual,
+        expected,
+        actual_lp_count: 1,
+        expected_lp_count: 1,
+        comment: "".to_string(),
+        date: date.to_string(),
+    }
+}
+
+fn user(username: &str, role: &str) -> UserRecord {
+    UserRecord {
+        username: username.to_string(),
+        // The lowest cost keeps logins fast; verify reads the cost from the hash.
+        password: bcrypt::hash(PASSWORD, 4).expect("hash fixture password"),
+        role: role.to_string(),
+        disabled: false,
+        must_reset_password: false,
+    }
+}
+
+/// Two scheduled trailers on TODAY and the day after, one with no SIDs yet, five
+/// shipments spread over the lifecycle, a few counts and one user per role.
+pub fn fixtures() -> MemoryData {
+    MemoryData {
+        trailers: vec![
+            TrailerRecord {
+                TrailerID: "TRL-100".to_string(),
+                Schedule: schedule(TODAY, "08:00", "12"),
+                CiscoIDs: vec!["C-1".to_string(), "C-2".to_string()],
+                Sids: vec![
+                    sid("S-1", "C-1", vec![part("P-100", 40), part("P-200", 10)]),
+                    sid("S-2", "C-2", vec![part("P-300", 5)]),
+                ],
+            },
+            TrailerRecord {
+                TrailerID: "TRL-200".to_string(),
+                Schedule: schedule("2024-06-04", "13:30", "7"),
+                CiscoIDs: vec!["C-3".to_string()],
+                Sids: vec![sid("S-3", "C-3", vec![part("P-100", 20)])],
+            },
+            TrailerRecord {
+                TrailerID: "TRL-300".to_string(),
+                Schedule: schedule(TODAY, "10:00", ""),
+                CiscoIDs: Vec::new(),
+                Sids: Vec::new(),
+            },
+        ],
+        shipments: vec![
+            ShipmentRecord {
+                Shipment: shipment("L-100", TODAY, "NOT STARTED"),
+                Lines: vec![line("P-100", 10), line("P-200", 4)],
+            },
+            ShipmentRecord {
+                Shipment: Shipment { IsHold: true, ..shipment("L-200", TODAY, "NOT STARTED") },
+                Lines: Vec::new(),
+            },
+            ShipmentRecord {
+                Shipment: shipment("L-300", TODAY, "LOADING"),
+                Lines: vec![line("P-300", 5)],
+            },
+            ShipmentRecord {
+                Shipment: shipment("L-400", "2024-06-02", "COMPLETE"),
+                Lines: Vec::new(),
+            },
+            ShipmentRecord {
+                Shipment: shipment("X-500", "2024-06-05", "NOT STARTED"),
+                Lines: Vec::new(),
+            },
+        ],
+        counts: vec![
+            count("P-100", 38, 40, TODAY),
+            count("P-200", 10, 10, TODAY),
+            count("P-300", 5, 5, "2024-06-10"),
+        ],
+        users: vec![
+            user(ADMIN, "admin"),
+            user(WRITER, "write"),
+            user(READER, "read"),
+            UserRecord { disabled: true, ..user(DISABLED, "read") },
+            UserRecord { must_reset_password: true, ..user(MUST_RESET, "read") },
+        ],
+        ..MemoryData::default()
+    }
+}
+
+/// A test's snapshot file, removed when the test is done with it.
+struct SnapshotFile(PathBuf);
+
+impl Drop for SnapshotFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).ok();
+    }
+}
+
+/// A fixture snapshot file and a memory-store config that loads it.
+fn test_config(configure: impl FnOnce(&mut AppConfig)) -> (AppConfig, SnapshotFile) {
+    let snapshot = std::env::temp_dir().join(format!("rocket_http-test-{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(&snapshot, serde_json::to_vec(&fixtures()).expect("serialize fixtures"))
+        .expect("write fixture snapshot");
+
+    let mut config = AppConfig {
+        storage: Storage::Memory,
+        memory_snapshot: Some(snapshot.to_string_lossy().into_owned()),
+        jwt_secret: "integration-test-secret-of-32-characters".to_string(),
+        // Tests that don't resume shouldn't wait long for live events.
+        ws_resume_window_ms: 200,
+        ..AppConfig::default()
+    };
+    configure(&mut config);
+    config.validate().expect("valid test config");
+    (config, SnapshotFile(snapshot))
+}
+
+pub struct TestApp {
+    pub client: Client,
+    config: AppConfig,
+    snapshot: SnapshotFile,
+}
+
+impl TestApp {
+    pub async fn spawn() -> Self {
+        Self::spawn_with(|_| {}).await
+    }
+
+    /// Starts the app on the fixtures with a test config adjusted by `configure`.
+    pub async fn spawn_with(configure: impl FnOnce(&mut AppConfig)) -> Self {
+        let (config, snapshot) = test_config(configure);
+        let client = Self::client(&config).await;
+        TestApp { client, config, snapshot }
+    }
+
+    /// Stops the app and starts it again from what it saved to its snapshot.
+    pub async fn restart(self) -> Self {
+        let TestApp { client, config, snapshot } = self;
+        drop(client);
+        let client = Self::client(&config).await;
+        TestApp { client, config, snapshot }
+    }
+
+    async fn client(config: &AppConfig) -> Client {
+        let rocket = build_rocket(config.clone()).await.expect("app builds");
+        Client::untracked(rocket).await.expect("valid rocket")
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> LocalResponse<'_> {
+        self.post("/login", None, json!({ "username": username, "password": password })).await
+    }
+
+    /// Access token for a fixture user.
+    pub async fn token(&self, username: &str) -> String {
+        let response = self.login(username, PASSWORD).await;
+        assert_eq!(response.status(), Status::Ok, "login as {}", username);
+        body(response).await["token"].as_str().expect("token in login response").to_string()
+    }
+
+    pub async fn get(&self, path: &str, token: Option<&str>) -> LocalResponse<'_> {
+        let mut request = self.client.get(path.to_string());
+        if let Some(token) = token {
+            request.add_header(bearer(token));
+        }
+        request.dispatch().await
+    }
+
+    pub async fn post(&self, path: &str, token: Option<&str>, json: Value) -> LocalResponse<'_> {
+        let mut request = self.client.post(path.to_string())
+            .header(ContentType::JSON)
+            .body(json.to_string());
+        if let Some(token) = token {
+            request.add_header(bearer(token));
+        }
+        request.dispatch().await
+    }
+
+    pub async fn delete(&self, path: &str, token: Option<&str>) -> LocalResponse<'_> {
+        let mut request = self.client.delete(path.to_string());
+        if let Some(token) = token {
+            request.add_header(bearer(token));
+        }
+        request.dispatch().await
+    }
+}
+
+/// The app listening on a real socket, for what the local client can't do, like
+/// upgrading to a websocket. Shut down when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub events: EventBus,
+    pub ws_list: WebSocketList,
+    pub users: Arc<dyn UserRepository>,
+    keys: KeyRing,
+    shutdown: Shutdown,
+    snapshot: SnapshotFile,
+}
+
+impl TestServer {
+    pub async fn spawn() -> Self {
+        Self::spawn_with(|_| {}).await
+    }
+
+    pub async fn spawn_with(configure: impl FnOnce(&mut AppConfig)) -> Self {
+        let (mut config, snapshot) = test_config(configure);
+        config.address = Ipv4Addr::LOCALHOST.into();
+        config.port = 0;
+
+        let (ready, started) = oneshot::channel();
+        let rocket = build_rocket(config).await.expect("app builds")
+            .attach(AdHoc::on_liftoff("Test server", move |rocket| Box::pin(async move {
+                let state = rocket.state::<AppState>().expect("managed state");
+                let addr = SocketAddr::new(rocket.config().address, rocket.config().port);
+                let handles = (state.events.clone(), state.ws_list.clone(), state.users.clone(), state.jwt_keys.clone());
+                ready.send((addr, handles, rocket.shutdown())).ok();
+            })));
+        tokio::spawn(rocket.launch());
+
+        let (addr, (events, ws_list, users, keys), shutdown) = started.await.expect("server lifts off");
+        TestServer { addr, events, ws_list, users, keys, shutdown, snapshot }
+    }
+
+    /// An access token signed like /login would, without going over HTTP.
+    pub fn token(&self, username: &str, role: &str) -> String {
+        let claims = Claims {
+            username: username.to_string(),
+            role: role.to_string(),
+            exp: (chrono::Utc::now().timestamp() + 3600) as usize,
+            token_type: TokenType::Access,
+            jti: uuid::Uuid::new_v4().to_string(),
+        };
+        self.keys.encode(&claims).expect("token signs")
+    }
+
+    pub async fn connect(&self, path: &str) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+        let (socket, _) = connect_async(format!("ws://{}{}", self.addr, path)).await.expect("websocket connects");
+        socket
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.clone().notify();
+    }
+}
+
+/// A websocket client without a socket behind it: what the server queues for it
+/// lands in the receiver, and the Notify fires if it is kicked for falling behind.
+pub fn fake_ws_client(
+    username: &str,
+    role: &str,
+    topics: Vec<Topic>,
+    capacity: usize,
+    queue_policy: QueuePolicy,
+) -> (WsClient, mpsc::Receiver<Message>, Arc<Notify>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let kick = Arc::new(Notify::new());
+    let client = WsClient {
+        username: username.to_string(),
+        role: role.to_string(),
+        topics,
+        reads_trailers: true,
+        reads_shipments: true,
+        joined_after_seq: 0,
+        sender: tx,
+        queue_policy,
+        dropped: 0,
+        kick: kick.clone(),
+        held: None,
+    };
+    (client, rx, kick)
+}
+
+fn bearer(token: &str) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+pub async fn body(response: LocalResponse<'_>) -> Value {
+    response.into_json::<Value>().await.unwrap_or(Value::Null)
+}
+
+/// Asserts the status and the `code` of an ApiError body.
+pub async fn assert_error(response: LocalResponse<'_>, status: Status, code: &str) {
+    assert_eq!(response.status(), status);
+    assert_eq!(body(response).await["code"], code);
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use std::time::Duration;
+use common::*;
+use rocket::http::Status;
+use rocket_http::events::Event;
+use rocket_http::config::QueuePolicy;
+use rocket_http::structs::AppState;
+use rocket_http::wsprotocol::Topic;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::Receiver;
+use tokio::time::timeout;
+
+async fn next(events: &mut Receiver<Event>) -> Value {
+    let event = timeout(Duration::from_secs(1), events.recv()).await
+        .expect("an event within a second")
+        .expect("bus open");
+    serde_json::to_value(event).unwrap()
+}
+
+fn subscribe(app: &TestApp) -> Receiver<Event> {
+    app.client.rocket().state::<AppState>().expect("managed state").events.subscribe()
+}
+
+#[rocket::async_test]
+async fn trailer_setters_publish_the_updated_schedule() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+    let mut events = subscribe(&app);
+
+    app.post("/api/set_door", Some(&token), json!({ "TrailerID": "TRL-100", "Door": "21" })).await;
+    let event = next(&mut events).await;
+    assert_eq!(event["type"], "set_door");
+    assert_eq!(event["data"]["TrailerID"], "TRL-100");
+    assert_eq!(event["data"]["Schedule"]["DoorNumber"], "21");
+    // The whole schedule, not just the field that changed.
+    assert_eq!(event["data"]["Schedule"]["CarrierCode"], "MAEU");
+
+    app.post("/api/hot_trailer", Some(&token), json!({ "TrailerID": "TRL-100" })).await;
+    assert_eq!(next(&mut events).await["type"], "hot_trailer");
+
+    app.post("/api/set_arrivalTime", Some(&token), json!({ "TrailerID": "TRL-100", "ArrivalTime": "07:45" })).await;
+    let event = next(&mut events).await;
+    assert_eq!(event["type"], "trailer_arrived");
+    assert_eq!(event["data"]["Schedule"]["LoadStatus"], "arrived");
+
+    let schedule = json!({
+        "TrailerID": "TRL-100", "ScheduleDate": TODAY, "RequestDate": TODAY, "CarrierCode": "MSCU",
+        "ScheduleTime": "09:00", "LastFreeDate": TODAY, "ContactEmail": "", "Door": "3", "ClaimComments": "", "Seal": "",
+    });
+    app.post("/api/set_schedule", Some(&token), schedule).await;
+    assert_eq!(next(&mut events).await["type"], "schedule_trailer");
+}
+
+#[rocket::async_test]
+async fn shipment_setters_publish_the_updated_shipment() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+    let admin = app.token(ADMIN).await;
+    let mut events = subscribe(&app);
+
+    let steps = [
+        ("/api/new_shipment", serde_json::to_value(shipment("L-600", TODAY, "NOT STARTED")).unwrap(), "new_shipment"),
+        ("/api/shipment_door", json!({ "LoadId": "L-600", "Door": "9" }), "set_shipment_door"),
+        ("/api/set_shipment_trailer", json!({ "LoadId": "L-600", "ArrivalTime": "07:00", "TrailerNum": "53-1" }), "shipment_trailer_arrival"),
+        ("/api/set_shipment_pick_start", json!({ "LoadId": "L-600", "StartTime": "08:15", "Picker": WRITER }), "start_shipment_pick"),
+        ("/api/shipment_pick_finish", json!({ "LoadId": "L-600", "FinishTime": "09:15" }), "finish_shipment_pick"),
+        ("/api/shipment_verification", json!({ "LoadId": "L-600", "VerifiedBy": READER }), "verified_by"),
+        ("/api/shipment_begin_loading", json!({ "LoadId": "L-600" }), "shipment_start_loading"),
+        ("/api/set_shipment_departureTime", json!({ "LoadId": "L-600", "DepartTime": "17:00", "Seal": "SEAL-1" }), "shipment_depart"),
+        ("/api/shipment_hold", json!({ "LoadId": "L-600" }), "shipment_hold"),
+    ];
+    for (path, request, event_type) in steps {
+        assert_eq!(app.post(path, Some(&token), request).await.status(), Status::Ok, "{}", path);
+        let event = next(&mut events).await;
+        assert_eq!(event["type"], event_type);
+        assert_eq!(event["data"]["LoadId"], "L-600");
+    }
+
+    let lines = json!({ "LoadId": "L-600", "Lines": [{ "item": "P-100", "quantity": 3, "ip": "IP-2" }] });
+    app.post("/api/shipment_lines", Some(&token), lines.clone()).await;
+    assert_eq!(next(&mut events).await, json!({ "type": "shipment_lines", "data": lines }));
+
+    // Carries the shipment as stored after every step before it.
+    app.post("/api/shipment_status_override", Some(&admin), json!({ "LoadId": "L-600", "Status": "LOADING" })).await;
+    let event = next(&mut events).await;
+    assert_eq!(event["type"], "shipment_status_override");
+    assert_eq!(event["data"]["Status"], "LOADING");
+    assert_eq!(event["data"]["Seal"], "SEAL-1");
+    assert_eq!(event["data"]["IsHold"], true);
+
+    app.post("/api/delete_shipment", Some(&admin), json!({ "LoadId": "L-600" })).await;
+    assert_eq!(next(&mut events).await, json!({ "type": "delete_shipment", "data": { "LoadId": "L-600" } }));
+}
+
+#[rocket::async_test]
+async fn failed_writes_publish_nothing() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+    let mut events = subscribe(&app);
+
+    let response = app.post("/api/shipment_begin_loading", Some(&token), json!({ "LoadId": "L-100" })).await;
+    assert_eq!(response.status(), Status::Conflict);
+    app.post("/api/set_door", Some(&token), json!({ "TrailerID": "TRL-999", "Door": "3" })).await;
+
+    assert!(events.try_recv().is_err());
+}
+
+#[rocket::async_test]
+async fn events_reach_connected_websocket_clients() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+    let state = app.client.rocket().state::<AppState>().unwrap();
+
+    let (client, mut rx, _) = fake_ws_client(READER, "read", vec![Topic::Shipments], 8, QueuePolicy::Disconnect);
+    state.ws_list.lock().await.insert("127.0.0.1:50000".parse().unwrap(), client);
+
+    app.post("/api/shipment_hold", Some(&token), json!({ "LoadId": "L-100" })).await;
+
+    let message = timeout(Duration::from_secs(1), rx.recv()).await.expect("a message").expect("open");
+    let event: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+    assert_eq!((&event["v"], &event["seq"], &event["type"]), (&json!(1), &json!(1), &json!("shipment_hold")));
+    assert_eq!(event["data"]["IsHold"], true);
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use common::*;
+use rocket::http::Status;
+use serde_json::json;
+
+async fn register(app: &TestApp, username: &str) {
+    let response = app.post("/register", None, json!({ "username": username, "password": PASSWORD })).await;
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn memory_backend_serves_requests_without_neo4j() {
+    let app = TestApp::spawn_with(|config| config.open_registration = true).await;
+    register(&app, "dockhand").await;
+    let token = app.token("dockhand").await;
+
+    assert_eq!(app.get("/api/get_shipments", Some(&token)).await.status(), Status::Ok);
+
+    // Open registration hands out 'read', which cannot create shipments.
+    let response = app.post("/api/new_shipment", Some(&token), json!({ "LoadId": "L-1" })).await;
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[rocket::async_test]
+async fn memory_snapshot_survives_a_restart() {
+    let app = TestApp::spawn_with(|config| config.open_registration = true).await;
+    register(&app, "dockhand").await;
+
+    let app = app.restart().await;
+    assert_eq!(app.login("dockhand", PASSWORD).await.status(), Status::Ok);
+    assert_eq!(app.login("stranger", PASSWORD).await.status(), Status::Unauthorized);
+}
@@ -0,0 +1,92 @@
+use rocket_http::repository::MIGRATIONS;
+
+#[test]
+fn versions_start_at_one_and_increase_by_one() {
+    let versions: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+    let expected: Vec<i64> = (1..=MIGRATIONS.len() as i64).collect();
+    assert_eq!(versions, expected);
+}
+
+#[test]
+fn every_migration_has_a_name_and_statements() {
+    for migration in MIGRATIONS {
+        assert!(!migration.name.is_empty(), "migration {} has no name", migration.version);
+        assert!(!migration.statements.is_empty(), "migration {} is empty", migration.version);
+    }
+}
+
+#[test]
+fn constraints_are_checked_for_duplicates_first() {
+    let constrained: Vec<i64> = MIGRATIONS.iter().filter(|m| m.creates_constraints()).map(|m| m.version).collect();
+    assert_eq!(constrained, [1]);
+    for migration in MIGRATIONS {
+        for check in migration.checks {
+            assert!(check.query.ends_with("AS value"), "migration {}: {}", migration.version, check.query);
+        }
+    }
+    let checked = MIGRATIONS[0].checks.iter().map(|check| check.query).collect::<Vec<_>>().join("\n");
+    for key in ["(u:User) WITH u.name", "(t:Trailer) WITH t.id", "(s:Shipment) WITH s.LoadId"] {
+        assert!(checked.contains(key), "no duplicate check for {}", key);
+    }
+}
+
+#[test]
+fn schema_statements_can_be_rerun() {
+    for migration in MIGRATIONS {
+        for statement in migration.statements {
+            if statement.starts_with("CREATE CONSTRAINT") || statement.starts_with("CREATE INDEX") {
+                assert!(statement.contains("IF NOT EXISTS"), "migration {}: {}", migration.version, statement);
+            }
+            if statement.starts_with("DROP CONSTRAINT") || statement.starts_with("DROP INDEX") {
+                assert!(statement.ends_with("IF EXISTS"), "migration {}: {}", migration.version, statement);
+            }
+        }
+    }
+}
+
+/// Properties the repository looks nodes up by, so each needs an index or a unique constraint.
+const LOOKUPS: &[(&str, &str)] = &[
+    ("SchemaMigration", "version"),
+    ("User", "name"),
+    ("User", "role"),
+    ("Trailer", "id"),
+    ("Shipment", "LoadId"),
+    ("Shipment", "ScheduleDate"),
+    ("Schedule", "ScheduleDate"),
+    ("Count", "Date"),
+    ("ShipmentEvent", "LoadId"),
+    ("ScheduleChange", "at"),
+    ("Role", "name"),
+    ("Permission", "name"),
+    ("InviteCode", "code"),
+    ("RefreshToken", "jti"),
+    ("RefreshToken", "family"),
+    ("RefreshToken", "username"),
+    ("LoginThrottle", "key"),
+];
+
+/// The (label, property) a CREATE INDEX or CREATE CONSTRAINT statement covers.
+fn covered(statement: &str) -> (&str, &str) {
+    // FOR (c:Count) ON (c.Date), FOR (u:User) REQUIRE u.name IS UNIQUE
+    let start = statement.find("FOR (").unwrap() + "FOR (".len();
+    let end = start + statement[start..].find(')').unwrap();
+    let (variable, label) = statement[start..end].split_once(':').unwrap();
+    let access = format!("{}.", variable);
+    let at = end + statement[end..].find(&access).unwrap() + access.len();
+    let property = &statement[at..];
+    let property = &property[..property.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(property.len())];
+    (label, property)
+}
+
+#[test]
+fn lookups_are_indexed() {
+    let mut indexed: Vec<(&str, &str)> = MIGRATIONS.iter()
+        .flat_map(|migration| migration.statements.iter())
+        .filter(|statement| statement.starts_with("CREATE INDEX") || statement.starts_with("CREATE CONSTRAINT"))
+        .map(|statement| covered(statement))
+        .collect();
+    indexed.sort();
+    let mut expected = LOOKUPS.to_vec();
+    expected.sort();
+    assert_eq!(indexed, expected);
+}
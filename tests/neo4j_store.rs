@@ -0,0 +1,84 @@
+// These run the Cypher itself, which the MemoryStore tests can't check, so they
+// need a Neo4j they may write to:
+//
+//     NEO4J_TEST_URI=bolt://localhost:7687 NEO4J_TEST_PASSWORD=... cargo test --test neo4j_store -- --ignored
+
+use neo4rs::{query, Graph};
+use rocket_http::repository::{Neo4jStore, ShipmentRepository, TrailerRepository};
+use rocket_http::structs::ShipmentEvent;
+use serde_json::json;
+use uuid::Uuid;
+
+async fn connect() -> (Neo4jStore, Graph) {
+    let uri = std::env::var("NEO4J_TEST_URI").unwrap_or_else(|_| "bolt://localhost:7687".to_string());
+    let user = std::env::var("NEO4J_TEST_USER").unwrap_or_else(|_| "neo4j".to_string());
+    let password = std::env::var("NEO4J_TEST_PASSWORD").expect("NEO4J_TEST_PASSWORD");
+    let store = Neo4jStore::connect(&uri, &user, &password).await.expect("Neo4j to connect");
+    let graph = Graph::new(&uri, &user, &password).await.expect("Neo4j to connect");
+    (store, graph)
+}
+
+// set_arrivalTime used to write LoadStatue, so the getters never saw the new status.
+#[rocket::async_test]
+#[ignore = "needs a live Neo4j"]
+async fn arrival_time_writes_load_status_and_drops_load_statue() {
+    let (store, graph) = connect().await;
+    let trailer_id = format!("TEST-{}", Uuid::new_v4());
+    graph.run(query("
+        CREATE (:Trailer {id: $TrailerID})-[:HAS_SCHEDULE]->(:Schedule {
+            ScheduleDate: '2024-06-03', ScheduleTime: '08:00', ArrivalTime: '', CarrierCode: 'ABC',
+            ContactEmail: '', DoorNumber: '12', IsHot: false, LastFreeDate: '', LoadStatus: 'in-transit',
+            RequestDate: '', Seal: '', IsMulti: false, IsStat6: false, ClaimComments: '', HasClaim: false,
+            LoadStatue: 'stale'
+        })
+    ").param("TrailerID", trailer_id.clone())).await.unwrap();
+
+    let result = store.set_arrival_time(&trailer_id, "07:45", "arrived").await;
+    let schedule = store.schedule(&trailer_id).await;
+    let mut leftover = graph.execute(query("
+        MATCH (:Trailer {id: $TrailerID})-[:HAS_SCHEDULE]->(s:Schedule)
+        RETURN s.LoadStatue IS NULL AS removed
+    ").param("TrailerID", trailer_id.clone())).await.unwrap();
+    let removed: bool = leftover.next().await.unwrap().expect("the schedule").get("removed").unwrap();
+    graph.run(query("
+        MATCH (t:Trailer {id: $TrailerID})-[:HAS_SCHEDULE]->(s:Schedule)
+        DETACH DELETE t, s
+    ").param("TrailerID", trailer_id)).await.unwrap();
+
+    assert_eq!(result.unwrap()[0].Schedule.LoadStatus, "arrived");
+    assert_eq!(schedule.unwrap().expect("the schedule").LoadStatus, "arrived");
+    assert!(removed, "LoadStatue is still set");
+}
+
+#[rocket::async_test]
+#[ignore = "needs a live Neo4j"]
+async fn deleting_a_shipment_leaves_the_event_in_its_history() {
+    let (store, graph) = connect().await;
+    let load_id = format!("TEST-{}", Uuid::new_v4());
+    graph.run(query("CREATE (:Shipment {LoadId: $LoadId, Status: 'NOT STARTED'})").param("LoadId", load_id.clone())).await.unwrap();
+    let event = ShipmentEvent {
+        LoadId: load_id.clone(),
+        event: "delete_shipment".to_string(),
+        from: "NOT STARTED".to_string(),
+        to: "DELETED".to_string(),
+        actor: "test".to_string(),
+        at: "2024-06-03T08:00:00+00:00".to_string(),
+        payload: json!({ "LoadId": load_id }),
+    };
+
+    let deleted = store.delete(&load_id, &event).await;
+    let state = store.state(&load_id).await;
+    let history = store.events(&load_id).await;
+    // A shipment that isn't there gets no event.
+    let missing = format!("TEST-{}", Uuid::new_v4());
+    store.delete(&missing, &ShipmentEvent { LoadId: missing.clone(), ..event.clone() }).await.unwrap();
+    let missing_history = store.events(&missing).await;
+    graph.run(query("MATCH (e:ShipmentEvent {LoadId: $LoadId}) DELETE e").param("LoadId", load_id)).await.unwrap();
+
+    deleted.unwrap();
+    assert_eq!(state.unwrap(), None);
+    let history = history.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!((history[0].event.as_str(), history[0].to.as_str()), ("delete_shipment", "DELETED"));
+    assert!(missing_history.unwrap().is_empty());
+}
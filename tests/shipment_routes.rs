@@ -0,0 +1,223 @@
+mod common;
+
+use common::*;
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+fn load_ids(shipments: &Value) -> Vec<&str> {
+    shipments.as_array().unwrap().iter().map(|s| s["LoadId"].as_str().unwrap()).collect()
+}
+
+#[rocket::async_test]
+async fn reads_need_shipments_read() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+    app.post("/api/admin/roles", Some(&admin), json!({ "name": "yard", "permissions": ["trailers:read"] })).await;
+    app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "yard" })).await;
+    let yard = app.token(READER).await;
+
+    assert_error(app.get("/api/get_shipments", Some(&yard)).await, Status::Forbidden, "FORBIDDEN").await;
+    assert_error(app.get("/api/shipments/L-100/history", Some(&yard)).await, Status::Forbidden, "FORBIDDEN").await;
+    let posts = [
+        ("/api/get_todays_shipments", json!({ "date": TODAY })),
+        ("/api/get_shipment_details", json!({ "LoadId": "L-100" })),
+    ];
+    for (path, request) in posts {
+        assert_error(app.post(path, None, request.clone()).await, Status::Unauthorized, "UNAUTHORIZED").await;
+        assert_error(app.post(path, Some(&yard), request).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+}
+
+#[rocket::async_test]
+async fn lists_recent_and_todays_shipments() {
+    let app = TestApp::spawn().await;
+    let token = app.token(READER).await;
+
+    let all = body(app.get("/api/get_shipments", Some(&token)).await).await;
+    assert_eq!(load_ids(&all).len(), 5);
+
+    // Today's work plus anything older still open; L-400 is complete, X-500 is later.
+    let today = body(app.post("/api/get_todays_shipments", Some(&token), json!({ "date": TODAY })).await).await;
+    let mut ids = load_ids(&today);
+    ids.sort();
+    assert_eq!(ids, ["L-100", "L-200", "L-300"]);
+}
+
+#[rocket::async_test]
+async fn shipment_details_are_its_lines() {
+    let app = TestApp::spawn().await;
+    let token = app.token(READER).await;
+
+    let lines = body(app.post("/api/get_shipment_details", Some(&token), json!({ "LoadId": "L-100" })).await).await;
+    assert_eq!(lines.as_array().map(Vec::len), Some(2));
+
+    let none = body(app.post("/api/get_shipment_details", Some(&token), json!({ "LoadId": "L-999" })).await).await;
+    assert_eq!(none, json!([]));
+}
+
+#[rocket::async_test]
+async fn shipment_writes_need_their_permissions() {
+    let app = TestApp::spawn().await;
+    let reader = app.token(READER).await;
+    let writer = app.token(WRITER).await;
+
+    let writes = [
+        ("/api/new_shipment", serde_json::to_value(shipment("L-600", TODAY, "NOT STARTED")).unwrap()),
+        ("/api/shipment_door", json!({ "LoadId": "L-100", "Door": "9" })),
+        ("/api/set_shipment_trailer", json!({ "LoadId": "L-100", "ArrivalTime": "07:00", "TrailerNum": "53-1" })),
+        ("/api/set_shipment_departureTime", json!({ "LoadId": "L-300", "DepartTime": "17:00", "Seal": "SEAL-1" })),
+        ("/api/set_shipment_pick_start", json!({ "LoadId": "L-100", "StartTime": "08:15", "Picker": READER })),
+        ("/api/shipment_pick_finish", json!({ "LoadId": "L-100", "FinishTime": "09:15" })),
+        ("/api/shipment_verification", json!({ "LoadId": "L-100", "VerifiedBy": READER })),
+        ("/api/shipment_begin_loading", json!({ "LoadId": "L-100" })),
+        ("/api/shipment_hold", json!({ "LoadId": "L-100" })),
+        ("/api/shipment_lines", json!({ "LoadId": "L-100", "Lines": [] })),
+        ("/api/delete_shipment", json!({ "LoadId": "L-100" })),
+    ];
+    for (path, request) in writes {
+        assert_error(app.post(path, None, request.clone()).await, Status::Unauthorized, "UNAUTHORIZED").await;
+        assert_error(app.post(path, Some(&reader), request).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+
+    // shipments:delete is not part of write.
+    assert_error(app.post("/api/delete_shipment", Some(&writer), json!({ "LoadId": "L-100" })).await, Status::Forbidden, "FORBIDDEN").await;
+}
+
+#[rocket::async_test]
+async fn full_lifecycle_records_every_step() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let created = app.post("/api/new_shipment", Some(&token), serde_json::to_value(shipment("L-600", TODAY, "NOT STARTED")).unwrap()).await;
+    assert_eq!(created.status(), Status::Ok);
+    assert_eq!(body(created).await["Status"], "NOT STARTED");
+
+    let steps = [
+        ("/api/set_shipment_pick_start", json!({ "LoadId": "L-600", "StartTime": "08:15", "Picker": WRITER }), "PICKING"),
+        ("/api/shipment_pick_finish", json!({ "LoadId": "L-600", "FinishTime": "09:15" }), "VERIFICATION"),
+        ("/api/shipment_verification", json!({ "LoadId": "L-600", "VerifiedBy": READER }), "READY TO LOAD"),
+        ("/api/shipment_begin_loading", json!({ "LoadId": "L-600" }), "LOADING"),
+        ("/api/set_shipment_departureTime", json!({ "LoadId": "L-600", "DepartTime": "17:00", "Seal": "SEAL-1" }), "COMPLETE"),
+    ];
+    for (path, request, status) in steps {
+        let response = app.post(path, Some(&token), request).await;
+        assert_eq!(response.status(), Status::Ok, "{}", path);
+        assert_eq!(body(response).await["Status"], status, "{}", path);
+    }
+
+    let lines = json!({ "LoadId": "L-600", "Lines": [
+        { "item": "P-100", "quantity": 3, "ip": "IP-2" },
+        { "item": "P-200", "quantity": 0, "ip": "IP-2" },
+    ]});
+    let lines = body(app.post("/api/shipment_lines", Some(&token), lines).await).await;
+    assert_eq!(lines, json!([{ "item": "P-100", "quantity": 3, "ip": "IP-2" }]));
+
+    let done = body(app.post("/api/get_shipment_details", Some(&token), json!({ "LoadId": "L-600" })).await).await;
+    assert_eq!(done, lines);
+
+    let history = body(app.get("/api/shipments/L-600/history", Some(&token)).await).await;
+    let history = history.as_array().unwrap();
+    let mut events: Vec<&str> = history.iter().map(|e| e["event"].as_str().unwrap()).collect();
+    events.sort();
+    assert_eq!(events, [
+        "finish_shipment_pick", "new_shipment", "shipment_depart", "shipment_lines",
+        "shipment_start_loading", "start_shipment_pick", "verified_by",
+    ]);
+    let depart = history.iter().find(|e| e["event"] == "shipment_depart").unwrap();
+    assert_eq!((depart["from"].as_str(), depart["to"].as_str()), (Some("LOADING"), Some("COMPLETE")));
+    assert_eq!(depart["actor"], WRITER);
+    assert_eq!(depart["payload"]["Seal"], "SEAL-1");
+}
+
+#[rocket::async_test]
+async fn out_of_order_transitions_conflict() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let response = app.post("/api/shipment_begin_loading", Some(&token), json!({ "LoadId": "L-100" })).await;
+    assert_eq!(response.status(), Status::Conflict);
+    let error = body(response).await;
+    assert_eq!(error["code"], "INVALID_TRANSITION");
+    assert_eq!((error["from"].as_str(), error["to"].as_str()), (Some("NOT STARTED"), Some("LOADING")));
+
+    // Recreating a shipment that already started would reset it.
+    let response = app.post("/api/new_shipment", Some(&token), serde_json::to_value(shipment("L-300", TODAY, "NOT STARTED")).unwrap()).await;
+    assert_eq!(response.status(), Status::Conflict);
+
+    let response = app.post("/api/shipment_pick_finish", Some(&token), json!({ "LoadId": "L-999", "FinishTime": "09:15" })).await;
+    assert_error(response, Status::NotFound, "NOT_FOUND").await;
+}
+
+#[rocket::async_test]
+async fn hold_blocks_transitions_until_released() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+    let start = json!({ "LoadId": "L-200", "StartTime": "08:15", "Picker": WRITER });
+
+    let response = app.post("/api/set_shipment_pick_start", Some(&token), start.clone()).await;
+    assert_eq!(response.status(), Status::Conflict);
+    let error = body(response).await;
+    assert_eq!(error["code"], "SHIPMENT_ON_HOLD");
+    assert_eq!(error["IsHold"], true);
+
+    let released = body(app.post("/api/shipment_hold", Some(&token), json!({ "LoadId": "L-200" })).await).await;
+    assert_eq!(released["IsHold"], false);
+    assert_eq!(app.post("/api/set_shipment_pick_start", Some(&token), start).await.status(), Status::Ok);
+
+    let held = body(app.post("/api/shipment_hold", Some(&token), json!({ "LoadId": "L-200" })).await).await;
+    assert_eq!(held["IsHold"], true);
+
+    assert_error(app.post("/api/shipment_hold", Some(&token), json!({ "LoadId": "L-999" })).await, Status::NotFound, "NOT_FOUND").await;
+}
+
+#[rocket::async_test]
+async fn door_and_trailer_updates_keep_the_status() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let door = body(app.post("/api/shipment_door", Some(&token), json!({ "LoadId": "L-300", "Door": "9" })).await).await;
+    assert_eq!((door["Door"].as_str(), door["Status"].as_str()), (Some("9"), Some("LOADING")));
+
+    let trailer = json!({ "LoadId": "L-300", "ArrivalTime": "07:00", "TrailerNum": "53-1" });
+    let trailer = body(app.post("/api/set_shipment_trailer", Some(&token), trailer).await).await;
+    assert_eq!((trailer["TrailerNum"].as_str(), trailer["ArrivalTime"].as_str()), (Some("53-1"), Some("07:00")));
+    assert_eq!(trailer["Status"], "LOADING");
+
+    let missing = json!({ "LoadId": "L-999", "ArrivalTime": "07:00", "TrailerNum": "53-1" });
+    assert_error(app.post("/api/set_shipment_trailer", Some(&token), missing).await, Status::NotFound, "NOT_FOUND").await;
+}
+
+#[rocket::async_test]
+async fn admins_can_override_the_status() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    // Skips the transition table and the hold.
+    let response = app.post("/api/shipment_status_override", Some(&admin), json!({ "LoadId": "L-200", "Status": "COMPLETE" })).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response).await["Status"], "COMPLETE");
+
+    let history = body(app.get("/api/shipments/L-200/history", Some(&admin)).await).await;
+    assert_eq!(history[0]["event"], "shipment_status_override");
+    assert_eq!(history[0]["from"], "NOT STARTED");
+
+    let response = app.post("/api/shipment_status_override", Some(&admin), json!({ "LoadId": "L-200", "Status": "SHIPPED" })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = app.post("/api/shipment_status_override", Some(&admin), json!({ "LoadId": "L-999", "Status": "COMPLETE" })).await;
+    assert_error(response, Status::NotFound, "NOT_FOUND").await;
+}
+
+#[rocket::async_test]
+async fn deleted_shipments_keep_their_history() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+
+    assert_eq!(app.post("/api/delete_shipment", Some(&admin), json!({ "LoadId": "L-100" })).await.status(), Status::Ok);
+
+    let all = body(app.get("/api/get_shipments", Some(&admin)).await).await;
+    assert!(!load_ids(&all).contains(&"L-100"));
+
+    let history = body(app.get("/api/shipments/L-100/history", Some(&admin)).await).await;
+    assert_eq!(history[0]["event"], "delete_shipment");
+    assert_eq!(history[0]["to"], "DELETED");
+}
@@ -0,0 +1,53 @@
+use rocket_http::status::{check_transition, ShipmentStatus, TRANSITIONS};
+use ShipmentStatus::*;
+
+const ALL: [ShipmentStatus; 6] = [NotStarted, Picking, Verification, ReadyToLoad, Loading, Complete];
+
+#[test]
+fn transitions_only_move_one_step_forward() {
+    let allowed: Vec<_> = ALL.iter()
+        .flat_map(|from| ALL.iter().map(move |to| (*from, *to)))
+        .filter(|(from, to)| from.can_transition_to(*to))
+        .collect();
+    assert_eq!(allowed, TRANSITIONS);
+    assert_eq!(allowed, [
+        (NotStarted, NotStarted),
+        (NotStarted, Picking),
+        (Picking, Verification),
+        (Verification, ReadyToLoad),
+        (ReadyToLoad, Loading),
+        (Loading, Complete),
+    ]);
+}
+
+#[test]
+fn statuses_read_back_as_stored() {
+    for status in ALL {
+        assert_eq!(status.as_str().parse::<ShipmentStatus>(), Ok(status));
+        assert_eq!(serde_json::to_value(status).unwrap(), status.as_str());
+    }
+    // Shipments from before Status was always set.
+    assert_eq!("".parse::<ShipmentStatus>(), Ok(NotStarted));
+    assert_eq!("picking".parse::<ShipmentStatus>(), Err(()));
+}
+
+#[test]
+fn check_transition_reports_why() {
+    assert!(check_transition("L-1", "PICKING", false, Verification).is_ok());
+    assert!(check_transition("L-1", "", false, Picking).is_ok());
+
+    let codes = [
+        (check_transition("L-1", "NOT STARTED", false, Complete), "INVALID_TRANSITION"),
+        (check_transition("L-1", "COMPLETE", false, Complete), "INVALID_TRANSITION"),
+        (check_transition("L-1", "PICKING", true, Verification), "SHIPMENT_ON_HOLD"),
+        (check_transition("L-1", "SHIPPED", false, Complete), "UNKNOWN_STATUS"),
+    ];
+    for (result, code) in codes {
+        assert_eq!(result.unwrap_err().code, code);
+    }
+
+    // Re-saving a held shipment that hasn't started is fine.
+    assert!(check_transition("L-1", "NOT STARTED", true, NotStarted).is_ok());
+    let error = check_transition("L-1", "LOADING", true, Complete).unwrap_err();
+    assert_eq!((error.from.as_str(), error.to.as_str(), error.IsHold), ("LOADING", "COMPLETE", true));
+}
@@ -0,0 +1,219 @@
+mod common;
+
+use common::*;
+use rocket::http::Status;
+use serde_json::{json, Value};
+
+fn trailer_ids(trailers: &Value) -> Vec<&str> {
+    trailers.as_array().unwrap().iter().map(|t| t["TrailerID"].as_str().unwrap()).collect()
+}
+
+#[rocket::async_test]
+async fn reads_need_trailers_read() {
+    let app = TestApp::spawn().await;
+    let admin = app.token(ADMIN).await;
+    app.post("/api/admin/roles", Some(&admin), json!({ "name": "counter", "permissions": ["counts:read"] })).await;
+    app.post(&format!("/api/admin/users/{}/role", READER), Some(&admin), json!({ "role": "counter" })).await;
+    let counter = app.token(READER).await;
+
+    let posts = [
+        ("/api/get_load_info", json!({ "param": "TRL-100" })),
+        ("/api/trailers", json!({ "date": TODAY })),
+        ("/api/todays_trucks", json!({ "date": TODAY })),
+        ("/api/trucks_date_range", json!({ "date1": TODAY, "date2": TODAY })),
+        ("/api/schedule_changes", json!({})),
+    ];
+    for (path, request) in posts {
+        assert_error(app.post(path, None, request.clone()).await, Status::Unauthorized, "UNAUTHORIZED").await;
+        assert_error(app.post(path, Some(&counter), request).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+    assert_error(app.get("/api/schedule_trailer", Some(&counter)).await, Status::Forbidden, "FORBIDDEN").await;
+
+    let counts = app.post("/api/get_raw_counts", Some(&counter), json!({ "date1": TODAY, "date2": TODAY })).await;
+    assert_eq!(counts.status(), Status::Ok);
+}
+
+#[rocket::async_test]
+async fn schedule_trailer_lists_trailers_with_sids() {
+    let app = TestApp::spawn().await;
+    let token = app.token(READER).await;
+
+    let trailers = body(app.get("/api/schedule_trailer", Some(&token)).await).await;
+    let mut ids = trailer_ids(&trailers);
+    ids.sort();
+    // TRL-300 has no SIDs yet.
+    assert_eq!(ids, ["TRL-100", "TRL-200"]);
+}
+
+#[rocket::async_test]
+async fn trucks_by_day_and_range() {
+    let app = TestApp::spawn().await;
+    let token = app.token(READER).await;
+
+    let today = body(app.post("/api/todays_trucks", Some(&token), json!({ "date": TODAY })).await).await;
+    assert_eq!(trailer_ids(&today), ["TRL-100"]);
+    assert_eq!(today[0]["CiscoIDs"], json!(["C-1", "C-2"]));
+
+    let range = body(app.post("/api/trucks_date_range", Some(&token), json!({ "date1": TODAY, "date2": "2024-06-04" })).await).await;
+    assert_eq!(trailer_ids(&range).len(), 2);
+
+    let empty = body(app.post("/api/trucks_date_range", Some(&token), json!({ "date1": "2024-07-01", "date2": "2024-07-31" })).await).await;
+    assert_eq!(empty, json!([]));
+}
+
+#[rocket::async_test]
+async fn load_info_and_sids_list_parts() {
+    let app = TestApp::spawn().await;
+    let token = app.token(READER).await;
+
+    let info = body(app.post("/api/get_load_info", Some(&token), json!({ "param": "TRL-100" })).await).await;
+    let info = info.as_array().unwrap();
+    assert_eq!(info.len(), 2);
+    let s1 = info.iter().find(|s| s["Sid"]["id"] == "S-1").expect("S-1 in load info");
+    assert_eq!(s1["Sid"]["CiscoID"], "C-1");
+    assert_eq!(s1["Parts"], json!([{ "partNumber": "P-100", "quantity": 40 }, { "partNumber": "P-200", "quantity": 10 }]));
+
+    let sids = body(app.post("/api/trailers", Some(&token), json!({ "date": TODAY })).await).await;
+    assert_eq!(trailer_ids(&sids), ["TRL-100"]);
+    assert_eq!(sids[0]["Sids"].as_array().map(Vec::len), Some(3));
+    assert_eq!(sids[0]["Sids"][2], json!({ "Sid": "S-2", "Cisco": "C-2", "Part": "P-300", "Quantity": 5 }));
+}
+
+#[rocket::async_test]
+async fn load_count_counts_by_prefix_without_login() {
+    let app = TestApp::spawn().await;
+
+    let response = app.post("/api/get_load_count", None, json!({ "prefix": "L-" })).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response).await, json!(4));
+}
+
+#[rocket::async_test]
+async fn raw_counts_in_range() {
+    let app = TestApp::spawn().await;
+    let token = app.token(READER).await;
+
+    let counts = body(app.post("/api/get_raw_counts", Some(&token), json!({ "date1": TODAY, "date2": TODAY })).await).await;
+    let items: Vec<&str> = counts.as_array().unwrap().iter().map(|c| c["item"].as_str().unwrap()).collect();
+    assert_eq!(items.len(), 2);
+    assert!(items.contains(&"P-100") && items.contains(&"P-200"));
+}
+
+#[rocket::async_test]
+async fn schedule_writes_need_their_permissions() {
+    let app = TestApp::spawn().await;
+    let reader = app.token(READER).await;
+
+    let writes = [
+        ("/api/set_schedule", json!({
+            "TrailerID": "TRL-100", "ScheduleDate": TODAY, "RequestDate": TODAY, "CarrierCode": "MAEU",
+            "ScheduleTime": "09:00", "LastFreeDate": TODAY, "ContactEmail": "", "Door": "3", "ClaimComments": "", "Seal": "",
+        })),
+        ("/api/set_door", json!({ "TrailerID": "TRL-100", "Door": "3" })),
+        ("/api/hot_trailer", json!({ "TrailerID": "TRL-100" })),
+        ("/api/set_arrivalTime", json!({ "TrailerID": "TRL-100", "ArrivalTime": "07:45" })),
+    ];
+    for (path, request) in writes {
+        assert_error(app.post(path, None, request.clone()).await, Status::Unauthorized, "UNAUTHORIZED").await;
+        assert_error(app.post(path, Some(&reader), request).await, Status::Forbidden, "FORBIDDEN").await;
+    }
+}
+
+#[rocket::async_test]
+async fn set_schedule_updates_and_logs_each_field() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let request = json!({
+        "TrailerID": "TRL-200", "ScheduleDate": TODAY, "RequestDate": "2024-05-20", "CarrierCode": "MSCU",
+        "ScheduleTime": "13:30", "LastFreeDate": "2024-06-10", "ContactEmail": "dispatch@example.com",
+        "Door": "7", "ClaimComments": "", "Seal": "",
+    });
+    let updated = body(app.post("/api/set_schedule", Some(&token), request).await).await;
+    assert_eq!(updated[0]["Schedule"]["ScheduleDate"], TODAY);
+    assert_eq!(updated[0]["Schedule"]["CarrierCode"], "MSCU");
+
+    let today = body(app.post("/api/todays_trucks", Some(&token), json!({ "date": TODAY })).await).await;
+    assert_eq!(trailer_ids(&today).len(), 2);
+
+    let changes = body(app.post("/api/schedule_changes", Some(&token), json!({ "TrailerID": "TRL-200" })).await).await;
+    let mut fields: Vec<&str> = changes.as_array().unwrap().iter().map(|c| c["field"].as_str().unwrap()).collect();
+    fields.sort();
+    assert_eq!(fields, ["CarrierCode", "ScheduleDate"]);
+    assert!(changes.as_array().unwrap().iter().all(|c| c["user"] == WRITER && c["event"] == "set_schedule"));
+}
+
+#[rocket::async_test]
+async fn set_schedule_for_an_unknown_trailer_changes_nothing() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let request = json!({
+        "TrailerID": "TRL-999", "ScheduleDate": TODAY, "RequestDate": TODAY, "CarrierCode": "MAEU",
+        "ScheduleTime": "09:00", "LastFreeDate": TODAY, "ContactEmail": "", "Door": "3", "ClaimComments": "", "Seal": "",
+    });
+    let response = app.post("/api/set_schedule", Some(&token), request).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(body(response).await, json!([]));
+}
+
+#[rocket::async_test]
+async fn door_hot_and_arrival_updates() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let door = body(app.post("/api/set_door", Some(&token), json!({ "TrailerID": "TRL-100", "Door": "21" })).await).await;
+    assert_eq!(door[0]["Schedule"]["DoorNumber"], "21");
+
+    let hot = body(app.post("/api/hot_trailer", Some(&token), json!({ "TrailerID": "TRL-100" })).await).await;
+    assert_eq!(hot[0]["Schedule"]["IsHot"], true);
+    let hot = body(app.post("/api/hot_trailer", Some(&token), json!({ "TrailerID": "TRL-100" })).await).await;
+    assert_eq!(hot[0]["Schedule"]["IsHot"], false);
+
+    let arrived = body(app.post("/api/set_arrivalTime", Some(&token), json!({ "TrailerID": "TRL-100", "ArrivalTime": "07:45" })).await).await;
+    assert_eq!(arrived[0]["Schedule"]["ArrivalTime"], "07:45");
+
+    let changes = body(app.post("/api/schedule_changes", Some(&token), json!({ "TrailerID": "TRL-100", "user": WRITER })).await).await;
+    let events: Vec<&str> = changes.as_array().unwrap().iter().map(|c| c["event"].as_str().unwrap()).collect();
+    for event in ["set_door", "hot_trailer", "set_arrival_time"] {
+        assert!(events.contains(&event), "no {} change in {:?}", event, events);
+    }
+
+    let nobody = body(app.post("/api/schedule_changes", Some(&token), json!({ "user": READER })).await).await;
+    assert_eq!(nobody, json!([]));
+}
+
+// set_arrivalTime used to write LoadStatue, so the getters never saw the new status.
+#[rocket::async_test]
+async fn arrival_time_sets_the_load_status_the_getters_return() {
+    let app = TestApp::spawn().await;
+    let token = app.token(WRITER).await;
+
+    let load_status = |trailers: &Value| -> Option<String> {
+        trailers.as_array()?.iter()
+            .find(|t| t["TrailerID"] == "TRL-100")
+            .and_then(|t| t["Schedule"]["LoadStatus"].as_str().map(str::to_string))
+    };
+
+    let arrived = body(app.post("/api/set_arrivalTime", Some(&token), json!({ "TrailerID": "TRL-100", "ArrivalTime": "07:45" })).await).await;
+    assert_eq!(load_status(&arrived).as_deref(), Some("arrived"));
+    let today = body(app.post("/api/todays_trucks", Some(&token), json!({ "date": TODAY })).await).await;
+    assert_eq!(load_status(&today).as_deref(), Some("arrived"));
+    let all = body(app.get("/api/schedule_trailer", Some(&token)).await).await;
+    assert_eq!(load_status(&all).as_deref(), Some("arrived"));
+
+    // Clearing the arrival time puts it back in transit.
+    app.post("/api/set_arrivalTime", Some(&token), json!({ "TrailerID": "TRL-100", "ArrivalTime": "" })).await;
+    let today = body(app.post("/api/todays_trucks", Some(&token), json!({ "date": TODAY })).await).await;
+    assert_eq!(load_status(&today).as_deref(), Some("in-transit"));
+
+    let changes = body(app.post("/api/schedule_changes", Some(&token), json!({ "TrailerID": "TRL-100" })).await).await;
+    assert!(changes.as_array().unwrap().iter().any(|c| c["field"] == "LoadStatus" && c["new"] == "arrived"));
+}
+
+#[rocket::async_test]
+async fn ws_route_needs_a_websocket_upgrade() {
+    let app = TestApp::spawn().await;
+
+    assert_error(app.get("/ws", None).await, Status::BadRequest, "BAD_REQUEST").await;
+}
@@ -0,0 +1,548 @@
+mod common;
+
+use std::time::Duration;
+use common::*;
+use futures_util::{SinkExt, StreamExt};
+use rocket_http::config::QueuePolicy;
+use rocket_http::events::Event;
+use rocket_http::repository::UserUpdate;
+use rocket_http::structs::{DeleteShipmentRequest, TrailerSchedule};
+use rocket_http::wsprotocol::Topic;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, sleep_until, timeout, timeout_at, Instant};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn wait_for_clients(server: &TestServer, count: usize) {
+    for _ in 0..100 {
+        if server.ws_list.lock().await.len() == count {
+            return;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    panic!("expected {} websocket clients", count);
+}
+
+async fn next_message(socket: &mut Socket) -> Message {
+    timeout(Duration::from_secs(1), socket.next()).await
+        .expect("a message within a second")
+        .expect("socket open")
+        .expect("valid frame")
+}
+
+async fn next_json(socket: &mut Socket) -> Value {
+    serde_json::from_str(next_message(socket).await.to_text().unwrap()).unwrap()
+}
+
+async fn assert_silent(socket: &mut Socket) {
+    let next = timeout(Duration::from_millis(200), socket.next()).await;
+    assert!(next.is_err(), "unexpected message: {:?}", next);
+}
+
+/// Connects with a token on the upgrade and reads the welcome.
+async fn signed_in(server: &TestServer, username: &str, role: &str) -> Socket {
+    let mut socket = server.connect(&format!("/ws?token={}", server.token(username, role))).await;
+    let welcome = next_json(&mut socket).await;
+    assert_eq!(welcome["type"], "welcome", "{}", welcome);
+    socket
+}
+
+fn frame(event_type: &str, data: Value) -> Message {
+    Message::Text(json!({ "v": 1, "type": event_type, "data": data }).to_string())
+}
+
+fn hot_trailer() -> Message {
+    frame("hot_trailer", json!({ "TrailerID": "TRL-100", "Schedule": schedule(TODAY, "08:00", "12") }))
+}
+
+fn delete_shipment() -> Message {
+    frame("delete_shipment", json!({ "LoadId": "L-100" }))
+}
+
+async fn next_error(socket: &mut Socket) -> Value {
+    let reply = next_json(socket).await;
+    assert_eq!((&reply["v"], &reply["type"]), (&json!(1), &json!("error")), "{}", reply);
+    reply["data"].clone()
+}
+
+#[rocket::async_test]
+async fn ws_upgrades_on_the_rocket_port_for_every_client() {
+    let server = TestServer::spawn().await;
+    // The old side listener failed to bind on the second GET /ws.
+    let mut first = signed_in(&server, READER, "read").await;
+    let mut second = signed_in(&server, READER, "read").await;
+    wait_for_clients(&server, 2).await;
+
+    server.events.publish(Event::DeleteShipment(DeleteShipmentRequest { LoadId: "L-100".to_string() }));
+    for socket in [&mut first, &mut second] {
+        let event = next_json(socket).await;
+        assert_eq!(event["v"], 1);
+        assert_eq!(event["type"], "delete_shipment");
+        assert_eq!(event["data"]["LoadId"], "L-100");
+    }
+
+    first.close(None).await.unwrap();
+    wait_for_clients(&server, 1).await;
+}
+
+#[rocket::async_test]
+async fn upgrade_with_a_bad_token_is_refused() {
+    let server = TestServer::spawn().await;
+
+    let url = format!("ws://{}/ws?token=not-a-token", server.addr);
+    match tokio_tungstenite::connect_async(url).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expected a 401, got {:?}", other.map(|(_, response)| response)),
+    }
+}
+
+#[rocket::async_test]
+async fn token_in_the_subprotocol_header_authenticates_the_upgrade() {
+    let server = TestServer::spawn().await;
+    let mut request = format!("ws://{}/ws", server.addr).into_client_request().unwrap();
+    let protocols = format!("access_token, {}", server.token(WRITER, "write"));
+    request.headers_mut().insert("Sec-WebSocket-Protocol", protocols.parse().unwrap());
+
+    let (mut socket, response) = tokio_tungstenite::connect_async(request).await.expect("websocket connects");
+    assert_eq!(response.headers().get("Sec-WebSocket-Protocol").unwrap(), "access_token");
+    let welcome = next_json(&mut socket).await;
+    assert_eq!(welcome["type"], "welcome", "{}", welcome);
+    assert_eq!(welcome["data"]["username"], WRITER);
+
+    let mut request = format!("ws://{}/ws", server.addr).into_client_request().unwrap();
+    request.headers_mut().insert("Sec-WebSocket-Protocol", "access_token, not-a-token".parse().unwrap());
+    match tokio_tungstenite::connect_async(request).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expected a 401, got {:?}", other.map(|(_, response)| response)),
+    }
+}
+
+#[rocket::async_test]
+async fn first_message_authenticates_the_connection() {
+    let server = TestServer::spawn().await;
+    let mut socket = server.connect("/ws").await;
+
+    // Nothing is sent to a connection until it has signed in.
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(server.ws_list.lock().await.len(), 0);
+
+    socket.send(frame("auth", json!({ "token": server.token(WRITER, "write") }))).await.unwrap();
+    let welcome = next_json(&mut socket).await;
+    assert_eq!(welcome["type"], "welcome");
+    assert_eq!((&welcome["data"]["username"], &welcome["data"]["role"]), (&json!(WRITER), &json!("write")));
+    assert_eq!(welcome["data"]["seq"], 0);
+    wait_for_clients(&server, 1).await;
+    let clients = server.ws_list.lock().await;
+    let client = clients.values().next().unwrap();
+    assert_eq!((client.username.as_str(), client.role.as_str()), (WRITER, "write"));
+}
+
+#[rocket::async_test]
+async fn anything_but_a_valid_auth_message_closes_the_connection() {
+    let server = TestServer::spawn().await;
+
+    for first in [hot_trailer(), frame("auth", json!({ "token": "forged" }))] {
+        let mut socket = server.connect("/ws").await;
+        socket.send(first).await.unwrap();
+        match next_message(&mut socket).await {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("expected a policy close, got {:?}", other),
+        }
+    }
+    assert_eq!(server.ws_list.lock().await.len(), 0);
+}
+
+#[rocket::async_test]
+async fn inbound_events_need_the_matching_permission() {
+    let server = TestServer::spawn().await;
+    let mut reader = signed_in(&server, READER, "read").await;
+    let mut writer = signed_in(&server, WRITER, "write").await;
+    let mut admin = signed_in(&server, ADMIN, "admin").await;
+    wait_for_clients(&server, 3).await;
+
+    reader.send(hot_trailer()).await.unwrap();
+    assert_eq!(next_error(&mut reader).await["code"], "FORBIDDEN");
+    writer.send(delete_shipment()).await.unwrap();
+    assert_eq!(next_error(&mut writer).await["code"], "FORBIDDEN");
+    assert_silent(&mut admin).await;
+
+    writer.send(hot_trailer()).await.unwrap();
+    let event = next_json(&mut reader).await;
+    assert_eq!((&event["type"], &event["data"]["Schedule"]["DoorNumber"]), (&json!("hot_trailer"), &json!("12")));
+
+    admin.send(delete_shipment()).await.unwrap();
+    assert_eq!(next_json(&mut reader).await["data"], json!({ "LoadId": "L-100" }));
+}
+
+#[rocket::async_test]
+async fn relayed_events_are_marked_and_not_numbered() {
+    let server = TestServer::spawn().await;
+    let mut reader = signed_in(&server, READER, "read").await;
+    let mut writer = signed_in(&server, WRITER, "write").await;
+    wait_for_clients(&server, 2).await;
+    let mut bus = server.events.subscribe();
+
+    writer.send(hot_trailer()).await.unwrap();
+    let relayed = next_json(&mut reader).await;
+    assert_eq!((&relayed["type"], &relayed["relayed_by"], &relayed["seq"]), (&json!("hot_trailer"), &json!(WRITER), &Value::Null));
+    assert!(bus.try_recv().is_err(), "a client's event reached the EventBus");
+
+    // Nor does it take a seq, or come back on resume.
+    server.events.publish(door_change("TRL-100", "13", TODAY));
+    let event = next_json(&mut reader).await;
+    assert_eq!((&event["seq"], &event["relayed_by"]), (&json!(1), &Value::Null));
+    let mut tablet = server.connect(&format!("/ws?token={}", server.token(READER, "read"))).await;
+    let stream = next_json(&mut tablet).await["data"]["stream"].clone();
+    resume(&mut tablet, &stream, 0).await;
+    assert_eq!(next_json(&mut tablet).await["seq"], 1);
+    assert_eq!(next_json(&mut tablet).await["data"]["replayed"], 1);
+}
+
+#[rocket::async_test]
+async fn bad_messages_get_an_error_frame_back() {
+    let server = TestServer::spawn().await;
+    let mut writer = signed_in(&server, WRITER, "write").await;
+    let mut reader = signed_in(&server, READER, "read").await;
+    wait_for_clients(&server, 2).await;
+
+    let cases = [
+        (Message::Text("not json".to_string()), "BAD_MESSAGE"),
+        (Message::Binary(vec![1, 2, 3]), "BAD_MESSAGE"),
+        (Message::Text(json!({ "type": "delete_shipment", "data": { "LoadId": "L-100" } }).to_string()), "UNSUPPORTED_VERSION"),
+        (Message::Text(json!({ "v": 2, "type": "delete_shipment", "data": { "LoadId": "L-100" } }).to_string()), "UNSUPPORTED_VERSION"),
+        (frame("made_up_event", json!({})), "UNKNOWN_TYPE"),
+        // Server-only events can't be sent by clients.
+        (frame("shipment_status_override", json!({ "LoadId": "L-100" })), "UNKNOWN_TYPE"),
+        (frame("set_door", json!({ "TrailerID": "TRL-100", "Door": "3" })), "BAD_MESSAGE"),
+        (frame("auth", json!({ "token": server.token(WRITER, "write") })), "BAD_MESSAGE"),
+    ];
+    for (message, code) in cases {
+        let sent = format!("{:?}", message);
+        writer.send(message).await.unwrap();
+        let error = next_error(&mut writer).await;
+        assert_eq!(error["code"], code, "{}: {}", sent, error);
+        assert!(!error["message"].as_str().unwrap().is_empty());
+    }
+
+    // Nothing was relayed, and the connection is still usable.
+    assert_silent(&mut reader).await;
+    writer.send(hot_trailer()).await.unwrap();
+    assert_eq!(next_json(&mut reader).await["type"], "hot_trailer");
+}
+
+fn door_change(trailer_id: &str, door: &str, date: &str) -> Event {
+    Event::SetDoor(TrailerSchedule { TrailerID: trailer_id.to_string(), Schedule: schedule(date, "08:00", door) })
+}
+
+async fn change_topics(socket: &mut Socket, request: &str, topics: Value) -> Value {
+    socket.send(frame(request, json!({ "topics": topics }))).await.unwrap();
+    let reply = next_json(socket).await;
+    assert_eq!(reply["type"], "subscriptions", "{}", reply);
+    reply["data"]["topics"].clone()
+}
+
+#[rocket::async_test]
+async fn clients_only_get_the_topics_they_subscribe_to() {
+    let server = TestServer::spawn().await;
+    let mut socket = signed_in(&server, READER, "read").await;
+    wait_for_clients(&server, 1).await;
+
+    let topics = change_topics(&mut socket, "unsubscribe", json!(["shipments"])).await;
+    assert_eq!(topics, json!(["trailers"]));
+    server.events.publish(Event::ShipmentHold(shipment("L-100", TODAY, "NOT STARTED")));
+    server.events.publish(door_change("TRL-100", "12", TODAY));
+    assert_eq!(next_json(&mut socket).await["type"], "set_door");
+
+    // Mid-session: swap everything for one door and one load.
+    change_topics(&mut socket, "unsubscribe", json!(["trailers"])).await;
+    let topics = change_topics(&mut socket, "subscribe", json!([{ "door": "12" }, { "load_id": "L-200" }, { "door": "12" }])).await;
+    assert_eq!(topics, json!([{ "door": "12" }, { "load_id": "L-200" }]));
+
+    server.events.publish(door_change("TRL-200", "14", TODAY));
+    server.events.publish(Event::ShipmentHold(shipment("L-100", TODAY, "NOT STARTED")));
+    server.events.publish(Event::DeleteShipment(DeleteShipmentRequest { LoadId: "L-200".to_string() }));
+    server.events.publish(door_change("TRL-100", "12", TODAY));
+    assert_eq!(next_json(&mut socket).await["data"], json!({ "LoadId": "L-200" }));
+    assert_eq!(next_json(&mut socket).await["data"]["TrailerID"], "TRL-100");
+    assert_silent(&mut socket).await;
+}
+
+#[rocket::async_test]
+async fn date_topics_cover_trailers_and_shipments() {
+    let server = TestServer::spawn().await;
+    let mut socket = signed_in(&server, READER, "read").await;
+    wait_for_clients(&server, 1).await;
+    change_topics(&mut socket, "unsubscribe", json!(["trailers", "shipments"])).await;
+    change_topics(&mut socket, "subscribe", json!([{ "date": TODAY }])).await;
+
+    server.events.publish(door_change("TRL-200", "3", "2024-06-04"));
+    server.events.publish(door_change("TRL-100", "3", TODAY));
+    server.events.publish(Event::NewShipment(shipment("L-600", "2024-06-04", "NOT STARTED")));
+    server.events.publish(Event::NewShipment(shipment("L-700", TODAY, "NOT STARTED")));
+    assert_eq!(next_json(&mut socket).await["data"]["TrailerID"], "TRL-100");
+    assert_eq!(next_json(&mut socket).await["data"]["LoadId"], "L-700");
+    assert_silent(&mut socket).await;
+}
+
+#[rocket::async_test]
+async fn topics_need_the_role_to_read_them() {
+    let server = TestServer::spawn().await;
+    // A role with no grants, like one an admin just created.
+    let update = UserUpdate { role: Some("counter".to_string()), ..UserUpdate::default() };
+    server.users.update_user(READER, &update).await.unwrap();
+    let mut socket = signed_in(&server, READER, "counter").await;
+    wait_for_clients(&server, 1).await;
+    assert!(server.ws_list.lock().await.values().all(|client| client.topics.is_empty()));
+
+    socket.send(frame("subscribe", json!({ "topics": [{ "door": "12" }, "trailers"] }))).await.unwrap();
+    assert_eq!(next_error(&mut socket).await["code"], "FORBIDDEN");
+    assert_eq!(change_topics(&mut socket, "subscribe", json!([{ "door": "12" }])).await, json!([{ "door": "12" }]));
+
+    // Subscribed to the door, but still can't read trailers.
+    server.events.publish(door_change("TRL-100", "12", TODAY));
+    assert_silent(&mut socket).await;
+}
+
+async fn resume(socket: &mut Socket, stream: &Value, last_seq: u64) {
+    socket.send(frame("resume", json!({ "stream": stream, "last_seq": last_seq }))).await.unwrap();
+}
+
+/// Publishes the events and waits until the observer has them, so they're numbered.
+async fn broadcast(server: &TestServer, observer: &mut Socket, events: Vec<Event>) -> Vec<Value> {
+    let count = events.len();
+    for event in events {
+        server.events.publish(event);
+    }
+    let mut seqs = Vec::new();
+    for _ in 0..count {
+        seqs.push(next_json(observer).await["seq"].clone());
+    }
+    seqs
+}
+
+#[rocket::async_test]
+async fn broadcasts_are_numbered_and_replayed_on_resume() {
+    let server = TestServer::spawn().await;
+    let mut observer = signed_in(&server, ADMIN, "admin").await;
+    let mut tablet = server.connect(&format!("/ws?token={}", server.token(READER, "read"))).await;
+    let stream = next_json(&mut tablet).await["data"]["stream"].clone();
+
+    let seqs = broadcast(&server, &mut observer, vec![door_change("TRL-100", "12", TODAY)]).await;
+    assert_eq!(seqs, [json!(1)]);
+    assert_eq!(next_json(&mut tablet).await["seq"], 1);
+    tablet.close(None).await.unwrap();
+
+    // Missed while the tablet was away.
+    let missed = vec![
+        door_change("TRL-100", "13", TODAY),
+        Event::ShipmentHold(shipment("L-100", TODAY, "NOT STARTED")),
+        door_change("TRL-200", "14", TODAY),
+    ];
+    assert_eq!(broadcast(&server, &mut observer, missed).await, [json!(2), json!(3), json!(4)]);
+
+    let mut tablet = server.connect(&format!("/ws?token={}", server.token(READER, "read"))).await;
+    let welcome = next_json(&mut tablet).await;
+    assert_eq!((&welcome["data"]["stream"], &welcome["data"]["seq"]), (&stream, &json!(4)));
+    change_topics(&mut tablet, "unsubscribe", json!(["shipments"])).await;
+
+    resume(&mut tablet, &stream, 1).await;
+    let replayed = [next_json(&mut tablet).await, next_json(&mut tablet).await];
+    assert_eq!((&replayed[0]["seq"], &replayed[0]["data"]["Schedule"]["DoorNumber"]), (&json!(2), &json!("13")));
+    assert_eq!((&replayed[1]["seq"], &replayed[1]["data"]["Schedule"]["DoorNumber"]), (&json!(4), &json!("14")));
+    assert_eq!(next_json(&mut tablet).await, json!({ "v": 1, "type": "resumed", "data": { "replayed": 2 } }));
+
+    // Live again, with nothing sent twice.
+    broadcast(&server, &mut observer, vec![door_change("TRL-100", "15", TODAY)]).await;
+    assert_eq!(next_json(&mut tablet).await["seq"], 5);
+    resume(&mut tablet, &stream, 1).await;
+    assert_eq!(next_error(&mut tablet).await["code"], "BAD_MESSAGE");
+    assert_silent(&mut tablet).await;
+}
+
+#[rocket::async_test]
+async fn live_events_wait_for_the_resume() {
+    let server = TestServer::spawn_with(|config| config.ws_resume_window_ms = 5000).await;
+    let mut observer = server.connect(&format!("/ws?token={}", server.token(ADMIN, "admin"))).await;
+    let stream = next_json(&mut observer).await["data"]["stream"].clone();
+    resume(&mut observer, &stream, 0).await;
+    assert_eq!(next_json(&mut observer).await["data"]["replayed"], 0);
+    broadcast(&server, &mut observer, vec![door_change("TRL-100", "12", TODAY)]).await;
+
+    let mut tablet = server.connect(&format!("/ws?token={}", server.token(READER, "read"))).await;
+    next_json(&mut tablet).await;
+    // Published after the welcome, before the resume arrives.
+    broadcast(&server, &mut observer, vec![door_change("TRL-100", "13", TODAY)]).await;
+    assert_silent(&mut tablet).await;
+
+    resume(&mut tablet, &stream, 0).await;
+    assert_eq!(next_json(&mut tablet).await["seq"], 1);
+    assert_eq!(next_json(&mut tablet).await["data"]["replayed"], 1);
+    assert_eq!(next_json(&mut tablet).await["seq"], 2);
+
+    // Without a resume, they flow once the window closes.
+    let server = TestServer::spawn_with(|config| config.ws_resume_window_ms = 300).await;
+    let mut observer = signed_in(&server, ADMIN, "admin").await;
+    let mut tablet = signed_in(&server, READER, "read").await;
+    broadcast(&server, &mut observer, vec![door_change("TRL-100", "12", TODAY)]).await;
+    assert_eq!(next_json(&mut tablet).await["seq"], 1);
+    resume(&mut tablet, &json!("any"), 0).await;
+    assert_eq!(next_error(&mut tablet).await["code"], "BAD_MESSAGE");
+}
+
+#[rocket::async_test]
+async fn resume_asks_for_a_resync_when_the_gap_is_gone() {
+    let server = TestServer::spawn_with(|config| config.ws_replay_buffer = 2).await;
+    let mut observer = signed_in(&server, ADMIN, "admin").await;
+    let events = (10..14).map(|door| door_change("TRL-100", &door.to_string(), TODAY)).collect();
+    broadcast(&server, &mut observer, events).await;
+
+    let mut tablet = server.connect(&format!("/ws?token={}", server.token(READER, "read"))).await;
+    let stream = next_json(&mut tablet).await["data"]["stream"].clone();
+    let resync = json!({ "v": 1, "type": "resync_required", "data": { "stream": stream, "seq": 4 } });
+
+    // Only 3 and 4 are kept.
+    resume(&mut tablet, &stream, 1).await;
+    assert_eq!(next_json(&mut tablet).await, resync);
+    resume(&mut tablet, &json!("an-earlier-run"), 3).await;
+    assert_eq!(next_json(&mut tablet).await, resync);
+    resume(&mut tablet, &stream, 9).await;
+    assert_eq!(next_json(&mut tablet).await, resync);
+
+    resume(&mut tablet, &stream, 2).await;
+    assert_eq!(next_json(&mut tablet).await["seq"], 3);
+    assert_eq!(next_json(&mut tablet).await["seq"], 4);
+    assert_eq!(next_json(&mut tablet).await["type"], "resumed");
+}
+
+#[rocket::async_test]
+async fn silent_clients_are_pinged_then_closed() {
+    let server = TestServer::spawn_with(|config| {
+        config.ws_ping_interval_secs = 1;
+        config.ws_idle_timeout_secs = 2;
+    }).await;
+    let mut silent = signed_in(&server, READER, "read").await;
+    let mut listening = signed_in(&server, WRITER, "write").await;
+
+    // Reading is enough for the client to answer pings.
+    let deadline = Instant::now() + Duration::from_millis(3500);
+    let listener = tokio::spawn(async move {
+        let mut pings = 0;
+        while let Ok(Some(Ok(message))) = timeout_at(deadline, listening.next()).await {
+            if message.is_ping() {
+                pings += 1;
+            }
+        }
+        (pings, listening)
+    });
+    sleep_until(deadline).await;
+
+    let mut pinged = false;
+    loop {
+        match next_message(&mut silent).await {
+            Message::Ping(_) => pinged = true,
+            Message::Close(Some(frame)) => {
+                assert_eq!((frame.code, frame.reason.as_ref()), (CloseCode::Policy, "Idle timeout"));
+                break;
+            },
+            other => panic!("expected pings then a close, got {:?}", other),
+        }
+    }
+    assert!(pinged);
+
+    let (pings, _listening) = listener.await.unwrap();
+    assert!(pings >= 3, "only {} pings", pings);
+    let clients = server.ws_list.lock().await;
+    assert_eq!(clients.values().map(|client| client.username.as_str()).collect::<Vec<_>>(), [WRITER]);
+}
+
+#[rocket::async_test]
+async fn full_queues_drop_or_disconnect_by_policy() {
+    let server = TestServer::spawn().await;
+    let mut observer = signed_in(&server, ADMIN, "admin").await;
+    let (dropping, _dropping_rx, _) = fake_ws_client(READER, "read", vec![Topic::Trailers], 2, QueuePolicy::Drop);
+    let (strict, _strict_rx, kick) = fake_ws_client(WRITER, "write", vec![Topic::Trailers], 2, QueuePolicy::Disconnect);
+    let dropping_addr = "127.0.0.1:50000".parse().unwrap();
+    {
+        let mut clients = server.ws_list.lock().await;
+        clients.insert(dropping_addr, dropping);
+        clients.insert("127.0.0.1:50001".parse().unwrap(), strict);
+    }
+
+    let events = (10..13).map(|door| door_change("TRL-100", &door.to_string(), TODAY)).collect();
+    broadcast(&server, &mut observer, events).await;
+
+    timeout(Duration::from_secs(1), kick.notified()).await.expect("the strict client is kicked");
+    let clients = server.ws_list.lock().await;
+    assert_eq!(clients.len(), 2);
+    let dropping = &clients[&dropping_addr];
+    assert_eq!((dropping.queue_depth(), dropping.dropped), (2, 1));
+}
+
+#[rocket::async_test]
+async fn a_lagging_broadcaster_skips_seqs_and_asks_for_a_resync() {
+    let server = TestServer::spawn_with(|config| config.event_bus_capacity = 2).await;
+    let mut observer = signed_in(&server, ADMIN, "admin").await;
+    wait_for_clients(&server, 1).await;
+
+    // The broadcaster waits on the client list, so the bus overflows and keeps
+    // only the last two.
+    {
+        let _clients = server.ws_list.lock().await;
+        for door in 11..17 {
+            server.events.publish(door_change("TRL-100", &door.to_string(), TODAY));
+        }
+    }
+
+    let mut message = next_json(&mut observer).await;
+    if message["seq"] == 1 {
+        // Taken off the bus before the broadcaster blocked.
+        message = next_json(&mut observer).await;
+    }
+    assert_eq!((&message["type"], &message["data"]["seq"]), (&json!("resync_required"), &json!(4)));
+    let stream = message["data"]["stream"].clone();
+    assert_eq!(next_json(&mut observer).await["seq"], 5);
+    assert_eq!(next_json(&mut observer).await["seq"], 6);
+
+    let mut tablet = server.connect(&format!("/ws?token={}", server.token(READER, "read"))).await;
+    assert_eq!(next_json(&mut tablet).await["data"]["seq"], 6);
+    resume(&mut tablet, &stream, 1).await;
+    assert_eq!(next_json(&mut tablet).await["type"], "resync_required");
+    resume(&mut tablet, &stream, 4).await;
+    assert_eq!(next_json(&mut tablet).await["data"]["Schedule"]["DoorNumber"], "15");
+    assert_eq!(next_json(&mut tablet).await["data"]["Schedule"]["DoorNumber"], "16");
+    assert_eq!(next_json(&mut tablet).await["data"]["replayed"], 2);
+}
+
+#[rocket::async_test]
+async fn connections_close_when_the_user_is_disabled() {
+    let server = TestServer::spawn_with(|config| {
+        config.ws_ping_interval_secs = 1;
+        config.ws_idle_timeout_secs = 5;
+    }).await;
+    let mut socket = signed_in(&server, WRITER, "write").await;
+    wait_for_clients(&server, 1).await;
+
+    let update = UserUpdate { disabled: Some(true), ..UserUpdate::default() };
+    server.users.update_user(WRITER, &update).await.unwrap();
+    let closed = timeout(Duration::from_secs(3), async {
+        loop {
+            match next_message(&mut socket).await {
+                Message::Close(Some(frame)) => break frame,
+                Message::Ping(_) => continue,
+                other => panic!("expected a close, got {:?}", other),
+            }
+        }
+    }).await.expect("closed within a ping or two");
+    assert_eq!((closed.code, closed.reason.as_ref()), (CloseCode::Policy, "Signed out"));
+
+    let url = format!("ws://{}/ws?token={}", server.addr, server.token(WRITER, "write"));
+    match tokio_tungstenite::connect_async(url).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expected a 401, got {:?}", other.map(|(_, response)| response)),
+    }
+}